@@ -4,22 +4,253 @@
 #include <stdint.h>
 #include <stddef.h>
 
+// A reducer can be used by any number of threads at once, except by `path_reducer_set_digest`,
+// and freed once no thread uses it. A session can be moved between threads, but is used by one thread at a time.
 typedef struct PathReducer PathReducer;
+typedef struct PathReductionSession PathReductionSession;
 typedef int32_t BlockID;
 typedef int32_t FunID;
 
-// Gets a path reducer
-PathReducer* get_path_reducer(const void* top_level, int32_t k);
+// Status code returned by the functions below
+typedef enum PathReductionStatus {
+    PATH_REDUCTION_OK = 0,
+    PATH_REDUCTION_NULL_POINTER = 1,
+    PATH_REDUCTION_INVALID_ARGUMENT = 2,
+    PATH_REDUCTION_INVALID_CFG = 3,
+    PATH_REDUCTION_INVALID_PATH = 4,
+    PATH_REDUCTION_PANIC = 5,
+    PATH_REDUCTION_IO_ERROR = 6,
+    PATH_REDUCTION_INVALID_CACHE = 7, // corrupted cache file or unsupported version
+    PATH_REDUCTION_STALE_CACHE = 8,   // cache built from different CFGs or conversion options
+} PathReductionStatus;
+
+// Returns the message of the last error on the calling thread, or NULL if the last call succeeded.
+// The message is valid until the next call into the library on the same thread.
+const char* path_reduction_last_error(void);
+
+// Algorithms used to reduce paths
+typedef enum PathReductionMode {
+    PATH_REDUCTION_MODE_REGEX = 0,  // parse paths with the regular expressions of the CFGs
+    PATH_REDUCTION_MODE_SIMPLE = 1, // drop repeated loop iterations with a stack of blocks
+    PATH_REDUCTION_MODE_AUTOMATON = 2, // parse paths in a single pass with automata of the regular expressions
+} PathReductionMode;
+
+// Results of reducing paths that are not execution paths of the CFGs
+typedef enum PathReductionErrorPolicy {
+    PATH_REDUCTION_ON_ERROR_FROM_ENV = -1,           // read from PATH_REDUCTION_ON_ERROR when the reducer is built
+    PATH_REDUCTION_ON_ERROR_FAIL = 0,                // fail with PATH_REDUCTION_INVALID_PATH
+    PATH_REDUCTION_ON_ERROR_FULL_PATH = 1,           // the unreduced path
+    PATH_REDUCTION_ON_ERROR_EMPTY_PATH = 2,          // the empty path
+    PATH_REDUCTION_ON_ERROR_LONGEST_VALID_PREFIX = 3, // the reduction of the longest valid prefix of the path
+    PATH_REDUCTION_ON_ERROR_RESYNC = 4,              // as above, then resume at the next block starting a function
+} PathReductionErrorPolicy;
+
+// Orders of the states eliminated when converting CFGs to regular expressions.
+// They give the same reductions for valid paths up to loop unrolling, with regular expressions of different sizes.
+typedef enum PathReductionEliminationOrder {
+    PATH_REDUCTION_ELIMINATION_INDEX = 0,              // by block order
+    PATH_REDUCTION_ELIMINATION_MIN_DEGREE = 1,         // fewest incoming times outgoing edges first
+    PATH_REDUCTION_ELIMINATION_WEIGHT = 2,             // least growth of the regular expression first
+    PATH_REDUCTION_ELIMINATION_REVERSE_POST_ORDER = 3, // by reverse post-order from the entry
+    PATH_REDUCTION_ELIMINATION_DOMINATOR = 4,          // deepest in the dominator tree first
+} PathReductionEliminationOrder;
+
+// Algorithms converting CFGs to regular expressions
+typedef enum PathReductionConversion {
+    PATH_REDUCTION_CONVERSION_STATE_ELIMINATION = 0, // eliminate states in the `elimination` order
+    PATH_REDUCTION_CONVERSION_STRUCTURAL = 1,        // follow the loop nesting, from the inner loops out
+} PathReductionConversion;
+
+// Receives `diagnostics_data` and the message of an invalid path
+typedef void (*PathReductionDiagnostics)(void* data, const char* message);
+
+// Bounds of a function, overriding those of PathReducerOptions,
+// e.g. to keep more of a parser and less of a logging helper
+typedef struct PathReductionFunctionK {
+    FunID fun_id;        // -1 to select the functions named `name`
+    const char* name;    // only read if `fun_id` is -1
+    int32_t loop_k;      // bound on the loop iterations of the function, the bound of the reducer if negative
+    int32_t recursion_k; // bound on the recursion depth of the function, the bound of the reducer if negative; 0 drops its calls
+} PathReductionFunctionK;
+
+// Options of `get_path_reducer_with_options`, start from `path_reducer_default_options()`
+typedef struct PathReducerOptions {
+    int32_t k;        // iterations of each loop (at least 1) and depth of recursive calls kept, in every mode, unless set apart below
+    int32_t mode;     // a PathReductionMode
+    int32_t digest;   // a PathReductionDigest
+    int32_t on_error; // a PathReductionErrorPolicy
+    // called with each invalid path, from any thread using the reducer, may be NULL.
+    // If NULL, invalid paths are printed when PATH_REDUCTION_DEBUG is set when the reducer is built.
+    PathReductionDiagnostics diagnostics;
+    void* diagnostics_data;
+    int32_t elimination; // a PathReductionEliminationOrder
+    int32_t conversion;  // a PathReductionConversion
+    int32_t simplify;    // non-zero to simplify the regular expressions after conversion
+    int32_t threads;     // threads of `reduce_paths_batch` and of the conversion of the CFGs, 0 for one per core
+    int32_t loop_k;      // bound on loop iterations, k if negative
+    int32_t recursion_k; // bound on recursion depth, k if negative
+    // bounds of `function_k_count` functions, a later one overriding an earlier one, may be NULL if there are none.
+    // Only read while the reducer is built.
+    const PathReductionFunctionK* function_k;
+    size_t function_k_count;
+} PathReducerOptions;
+
+// Returns the default options: regex reduction with k = 1 and SHA-256 digests,
+// with the error policy and diagnostics left to the environment
+PathReducerOptions path_reducer_default_options(void);
+
+// `top_level` points to the CFGs of the program, laid out as
+//
+//   struct CFGEntry {
+//       char function_name[256];
+//       BlockID entry;
+//       BlockID exit;
+//   };
+//   struct BlockEntry {
+//       FunID calls;               // callee, -1 if not a call, -2 for external calls, -3 for indirect calls
+//       int32_t successor_size;
+//       const BlockID* successors_arr;
+//       int32_t callee_size;       // only read for indirect calls, possible callees (unknown if 0)
+//       const FunID* callees_arr;  // only read for indirect calls
+//   };
+//   struct ExitEntry { FunID function; BlockID block; }; // another block where the function returns, e.g. an early return
+//   struct TopLevel {
+//       int32_t cfg_size;
+//       const CFGEntry* cfg_arr;
+//       int32_t block_size;
+//       const BlockEntry** block_arr;
+//   };
+//
+// or to CFGs with extra exits, told apart by their negative `version`:
+//
+//   struct TopLevelV2 {
+//       int32_t version;                  // PATH_REDUCTION_TOP_LEVEL_V2
+//       TopLevel top_level;
+//       int32_t extra_exit_size;
+//       const ExitEntry* extra_exit_arr;  // may be NULL if extra_exit_size is 0
+//   };
+// Only the exit and extra exits return: other blocks without successors don't, e.g. calls to abort.
+#define PATH_REDUCTION_TOP_LEVEL_V2 (-2)
+
+// Gets a path reducer built with `options`, stored in `out_reducer`
+PathReductionStatus get_path_reducer_with_options(const void* top_level, const PathReducerOptions* options, PathReducer** out_reducer);
+
+// Gets a path reducer with the default options and `k`, stored in `out_reducer`
+PathReductionStatus get_path_reducer(const void* top_level, int32_t k, PathReducer** out_reducer);
+
+// Gets a path reducer built from the CFG file (JSON or binary) at `file_path`, stored in `out_reducer`
+PathReductionStatus get_path_reducer_from_file(const char* file_path, int32_t k, PathReducer** out_reducer);
+
+// Writes the CFGs in `top_level` to a CFG file at `file_path`, in the binary format if `binary` is non-zero, and in JSON otherwise
+PathReductionStatus dump_cfg_file(const void* top_level, const char* file_path, int32_t binary);
+
+// Kinds of problems found by `validate_cfgs`
+typedef enum PathReductionDiagnosticKind {
+    PATH_REDUCTION_UNKNOWN_BLOCK = 0,      // the entry or exit `block` of `function` is not in the block table
+    PATH_REDUCTION_DANGLING_SUCCESSOR = 1, // successor `other` of `block` is not in the block table
+    PATH_REDUCTION_UNKNOWN_CALLEE = 2,     // `block` calls `other`, which is not a function
+    PATH_REDUCTION_SHARED_ENTRY = 3,       // `function` and `other` both start with `block`
+    PATH_REDUCTION_NO_EXIT = 4,            // `function` can't reach its exit or a block without successors, a warning: it is taken not to return
+    PATH_REDUCTION_UNREACHABLE_BLOCK = 5,  // `block` is not reachable from any entry, a warning
+} PathReductionDiagnosticKind;
+
+typedef struct PathReductionDiagnostic {
+    int32_t kind;     // a PathReductionDiagnosticKind
+    int32_t is_error; // non-zero if a reducer can't be built from the CFGs
+    FunID function;   // -1 if none
+    BlockID block;    // -1 if none
+    int32_t other;    // -1 if none
+} PathReductionDiagnostic;
+
+// Checks the CFGs in `top_level`, and gets the problems found in `out_diagnostics`, the number of which is stored in `out_len`.
+// The diagnostics must be freed with `free_diagnostics`.
+PathReductionStatus validate_cfgs(const void* top_level, PathReductionDiagnostic** out_diagnostics, size_t* out_len);
+
+// Frees diagnostics returned by `validate_cfgs`
+void free_diagnostics(PathReductionDiagnostic* diagnostics, size_t len);
+
+// Saves the regular expressions of `reducer` to the file at `file_path`
+PathReductionStatus save_path_reducer(const PathReducer* reducer, const char* file_path);
+
+// Loads a reducer saved by `save_path_reducer` from the file at `file_path` with `options`, stored in `out_reducer`.
+// Fails with PATH_REDUCTION_STALE_CACHE if the reducer was not built from the CFGs in `top_level`,
+// or with other `conversion`, `elimination` or `simplify` options.
+PathReductionStatus load_path_reducer(const char* file_path, const void* top_level, const PathReducerOptions* options, PathReducer** out_reducer);
 
 // Frees a path reducer
 void free_path_reducer(PathReducer* ptr);
 
 void free_boxed_array(int* ptr, size_t len);
 
-// Reduces a path, and gets the hash
-const char* reduce_path(const PathReducer* reducer, const BlockID* path, int32_t path_size, FunID entry_fun_id);
+// Reduces a path, and gets the hash in `out_hash`.
+// The hash is a NUL terminated string of 64 hex digits, and must be freed with `free_hash_string`.
+PathReductionStatus reduce_path(const PathReducer* reducer, const BlockID* path, int32_t path_size, FunID entry_fun_id, char** out_hash);
+
+// Frees a hash returned by `reduce_path`
+void free_hash_string(char* hash);
+
+// Digests of reduced paths, selected with `path_reducer_set_digest`
+typedef enum PathReductionDigest {
+    PATH_REDUCTION_DIGEST_SHA256 = 0, // 32 bytes
+    PATH_REDUCTION_DIGEST_XXH3 = 1,   // 8 bytes, non-cryptographic
+} PathReductionDigest;
+
+// Selects the digest written by `reduce_path_digest` and `reduce_path_hash64`, SHA-256 by default
+PathReductionStatus path_reducer_set_digest(PathReducer* reducer, int32_t kind);
+
+// Gets the size in bytes of the digests written by `reduce_path_digest`
+PathReductionStatus path_reducer_digest_size(const PathReducer* reducer, size_t* out_size);
+
+// Gets the size of the regular expression of the function `fun_id`, to compare elimination orders
+PathReductionStatus path_reducer_regex_size(const PathReducer* reducer, FunID fun_id, size_t* out_size);
+
+// Reduces a path, and writes the raw digest of the reduced path to `out_digest`, a buffer of `out_len` bytes.
+// The size of the digest is stored in `out_written`.
+PathReductionStatus reduce_path_digest(const PathReducer* reducer, const BlockID* path, int32_t path_size, FunID entry_fun_id, uint8_t* out_digest, size_t out_len, size_t* out_written);
+
+// Reduces a path, and gets a 64-bit digest of the reduced path in `out_hash`.
+// With SHA-256 selected, this is the first 8 bytes of the digest.
+PathReductionStatus reduce_path_hash64(const PathReducer* reducer, const BlockID* path, int32_t path_size, FunID entry_fun_id, uint64_t* out_hash);
+
+// What the reduction of a path did, see `reduce_path_stats`
+typedef struct PathReductionStats {
+    size_t path_len;
+    size_t reduced_len;
+    size_t dropped_iterations; // loop iterations dropped because the loop already ran k times
+    size_t dropped_calls;      // calls dropped because the recursion was already k calls deep
+    int recovery;              // PathReductionErrorPolicy applied if the path is invalid, -1 if it is valid
+} PathReductionStats;
+
+// Reduces a path as `reduce_path_hash64`, and gets what the reduction did in `out_stats`,
+// and the 64-bit digest of the reduced path in `out_hash` unless it is NULL.
+PathReductionStatus reduce_path_stats(const PathReducer* reducer, const BlockID* path, int32_t path_size, FunID entry_fun_id, uint64_t* out_hash, PathReductionStats* out_stats);
+
+// Reduces a path, and gets the reduced path in `out_path`, the length of which is stored in `out_len`.
+// The reduced path must be freed with `free_boxed_array`.
+PathReductionStatus reduce_path1(const PathReducer* reducer, const BlockID* path, int32_t path_size, FunID entry_fun_id, BlockID** out_path, int* out_len);
+
+// Reduces `count` paths in parallel, `paths[i]` being `path_sizes[i]` blocks long,
+// and gets the 64-bit digest of each reduced path in `out_hashes`, as `reduce_path_hash64`,
+// and its status in `out_statuses` unless it is NULL.
+// Returns the status of the first path that can't be reduced, whose hash is then 0.
+PathReductionStatus reduce_paths_batch(const PathReducer* reducer, const BlockID* const* paths, const int32_t* path_sizes, size_t count, uint64_t* out_hashes, PathReductionStatus* out_statuses);
+
+// Starts reducing a path given in parts with `path_reducer_feed`, and gets the session in `out_session`.
+// The result is the same as the functions above,
+// keeping only the calls in progress and the reduced path, except with PATH_REDUCTION_ON_ERROR_FULL_PATH.
+// Fails with PATH_REDUCTION_INVALID_ARGUMENT unless the mode is PATH_REDUCTION_MODE_AUTOMATON.
+// The reducer must outlive the session, which must be ended with `path_reducer_finish` or `free_path_reduction_session`.
+PathReductionStatus path_reducer_begin(const PathReducer* reducer, PathReductionSession** out_session);
+
+// Reads the next `size` blocks of the path
+PathReductionStatus path_reducer_feed(PathReductionSession* session, const BlockID* blocks, int32_t size);
+
+// Ends the session, and gets the reduced path in `out_path`, the length of which is stored in `out_len`,
+// and its hash in `out_hash` unless it is NULL. The session is freed, even if the path is invalid.
+// The reduced path must be freed with `free_boxed_array`, and the hash with `free_hash_string`.
+PathReductionStatus path_reducer_finish(PathReductionSession* session, BlockID** out_path, int* out_len, char** out_hash);
 
-// Returns the reduced path, the length of which is stored in `out_len`
-const BlockID* reduce_path1(const PathReducer* reducer, const BlockID* path, int32_t path_size, FunID entry_fun_id, int* out_len);
+// Frees a session without reducing its path
+void free_path_reduction_session(PathReductionSession* session);
 
 #endif
@@ -0,0 +1,740 @@
+//! Reduction of paths with position automata
+//!
+//! The regular expression of each function is compiled to its Glushkov automaton, whose states are
+//! the occurrences of literals and calls in the expression. Each transition records the `Star`
+//! it starts a new iteration of, if any, so that the iterations of each loop can be counted
+//! while reading the path once, with a stack of the calls in progress.
+//! The automata aren't deterministic, e.g. for `a b | a c`, so each call follows all the positions
+//! the path can be at, without backtracking.
+//!
+//! The reduction keeps the same blocks as `RegExp::parse_bounded` when the path can only be parsed in one way:
+//! the first iterations of each loop and the one the path ends in, and calls nested in themselves,
+//! up to the bounds of their function.
+
+use std::{collections::BTreeMap, fmt::Debug};
+
+use crate::{
+    error::Error,
+    re::{Dropped, FunctionBounds, ParseErr, ParseResult, RegExp, Val},
+};
+
+#[derive(Debug, Clone)]
+enum Symbol<Alphabet, Name> {
+    Literal(Alphabet),
+    Var(Name),
+}
+
+/// An occurrence of a literal or a call in a regular expression
+#[derive(Debug, Clone)]
+struct Position<Alphabet, Name> {
+    symbol: Symbol<Alphabet, Name>,
+    /// The stars enclosing the position, outermost first
+    stars: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Edge {
+    to: usize,
+    /// The star whose next iteration starts with the transition
+    iterates: Option<usize>,
+}
+
+/// Glushkov automaton of the regular expression of a function
+#[derive(Debug, Clone)]
+pub struct Automaton<Alphabet, Name> {
+    positions: Vec<Position<Alphabet, Name>>,
+    /// Transitions from the initial state
+    first: Vec<Edge>,
+    follow: Vec<Vec<Edge>>,
+    /// Whether the expression can end at each position
+    last: Vec<bool>,
+    /// The stars whose iterations can end at each position
+    ends: Vec<Vec<usize>>,
+    nullable: bool,
+}
+
+impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> Automaton<Alphabet, Name> {
+    pub fn from_regex(re: &RegExp<Alphabet, Name>) -> Self {
+        let mut builder =
+            Builder { positions: Vec::new(), follow: Vec::new(), ends: Vec::new(), stars: Vec::new(), star_count: 0 };
+        let (first, last, nullable) = builder.build(re);
+        let mut is_last = vec![false; builder.positions.len()];
+        for p in last {
+            is_last[p] = true;
+        }
+        Self {
+            first: first.into_iter().map(|to| Edge { to, iterates: None }).collect(),
+            positions: builder.positions,
+            follow: builder.follow,
+            last: is_last,
+            ends: builder.ends,
+            nullable,
+        }
+    }
+}
+
+struct Builder<Alphabet, Name> {
+    positions: Vec<Position<Alphabet, Name>>,
+    follow: Vec<Vec<Edge>>,
+    ends: Vec<Vec<usize>>,
+    /// The stars enclosing the expression being built
+    stars: Vec<usize>,
+    star_count: usize,
+}
+
+impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> Builder<Alphabet, Name> {
+    /// Adds the positions of `re`, and returns its first and last positions, and whether it is nullable
+    fn build(&mut self, re: &RegExp<Alphabet, Name>) -> (Vec<usize>, Vec<usize>, bool) {
+        match re {
+            RegExp::Epsilon => (Vec::new(), Vec::new(), true),
+            RegExp::Literal(c) => {
+                let p = self.position(Symbol::Literal(c.clone()));
+                (vec![p], vec![p], false)
+            }
+            RegExp::Var(x) => {
+                let p = self.position(Symbol::Var(x.clone()));
+                (vec![p], vec![p], false)
+            }
+            RegExp::Literals(cs) => {
+                let ps: Vec<_> = cs.iter().map(|c| self.position(Symbol::Literal(c.clone()))).collect();
+                for pair in ps.windows(2) {
+                    self.add_edge(pair[0], Edge { to: pair[1], iterates: None });
+                }
+                match (ps.first(), ps.last()) {
+                    (Some(&first), Some(&last)) => (vec![first], vec![last], false),
+                    _ => (Vec::new(), Vec::new(), true),
+                }
+            }
+            RegExp::Concat(r1, r2) => self.concat([r1.as_ref(), r2.as_ref()]),
+            RegExp::Seq(rs) => self.concat(rs.iter().map(|r| r.as_ref())),
+            RegExp::Alter(r1, r2) => {
+                let (mut first, mut last, nullable1) = self.build(r1);
+                let (first2, last2, nullable2) = self.build(r2);
+                first.extend(first2);
+                last.extend(last2);
+                (first, last, nullable1 || nullable2)
+            }
+            RegExp::Star(r) => {
+                let star = self.star_count;
+                self.star_count += 1;
+                self.stars.push(star);
+                let (first, last, _) = self.build(r);
+                self.stars.pop();
+                for &p in &last {
+                    self.ends[p].push(star);
+                    for &q in &first {
+                        self.add_edge(p, Edge { to: q, iterates: Some(star) });
+                    }
+                }
+                (first, last, true)
+            }
+        }
+    }
+
+    fn concat<'r>(&mut self, rs: impl IntoIterator<Item = &'r RegExp<Alphabet, Name>>) -> (Vec<usize>, Vec<usize>, bool)
+    where
+        Alphabet: 'r,
+        Name: 'r,
+    {
+        let (mut first, mut last, mut nullable) = (Vec::new(), Vec::new(), true);
+        for r in rs {
+            let (first2, last2, nullable2) = self.build(r);
+            for &p in &last {
+                for &q in &first2 {
+                    self.add_edge(p, Edge { to: q, iterates: None });
+                }
+            }
+            if nullable {
+                first.extend(&first2);
+            }
+            if nullable2 {
+                last.extend(last2);
+            } else {
+                last = last2;
+            }
+            nullable &= nullable2;
+        }
+        (first, last, nullable)
+    }
+
+    fn position(&mut self, symbol: Symbol<Alphabet, Name>) -> usize {
+        self.positions.push(Position { symbol, stars: self.stars.clone() });
+        self.follow.push(Vec::new());
+        self.ends.push(Vec::new());
+        self.positions.len() - 1
+    }
+
+    /// Adds a transition, unless there is already one between the same positions,
+    /// e.g. from an inner loop, which is then preferred
+    fn add_edge(&mut self, from: usize, edge: Edge) {
+        if !self.follow[from].iter().any(|e| e.to == edge.to) {
+            self.follow[from].push(edge);
+        }
+    }
+}
+
+/// The automata of all the functions of a program
+#[derive(Debug, Clone)]
+pub struct Automata<Alphabet, Name> {
+    automata: BTreeMap<Name, Automaton<Alphabet, Name>>,
+}
+
+/// The last node of a list of `KeptBlocks`, `None` for the empty list
+type Kept = Option<usize>;
+
+/// The blocks kept by the configurations of a parser, as lists sharing their prefixes,
+/// so that following a transition doesn't copy the blocks kept before it
+#[derive(Debug)]
+struct KeptBlocks<Alphabet> {
+    nodes: Vec<KeptNode<Alphabet>>,
+}
+
+#[derive(Debug)]
+struct KeptNode<Alphabet> {
+    /// The blocks kept before
+    prev: Kept,
+    item: KeptItem<Alphabet>,
+}
+
+#[derive(Debug)]
+enum KeptItem<Alphabet> {
+    Block(Alphabet),
+    /// The blocks kept by a call that returned
+    Call(Kept),
+}
+
+impl<Alphabet: Clone> KeptBlocks<Alphabet> {
+    /// Returns the list of `prev` followed by `item`
+    fn push(&mut self, prev: Kept, item: KeptItem<Alphabet>) -> Kept {
+        self.nodes.push(KeptNode { prev, item });
+        Some(self.nodes.len() - 1)
+    }
+
+    /// Returns the blocks of the list ending at `kept`, in order
+    fn to_vec(&self, mut kept: Kept) -> Vec<Alphabet> {
+        let mut blocks = Vec::new();
+        // the lists to go on with after the blocks of a call, as the nodes are read from the last one
+        let mut callers = Vec::new();
+        loop {
+            match kept.map(|i| &self.nodes[i]) {
+                Some(KeptNode { prev, item: KeptItem::Block(block) }) => {
+                    blocks.push(block.clone());
+                    kept = *prev;
+                }
+                Some(KeptNode { prev, item: KeptItem::Call(callee) }) => {
+                    callers.push(*prev);
+                    kept = *callee;
+                }
+                None => match callers.pop() {
+                    Some(caller) => kept = caller,
+                    None => break,
+                },
+            }
+        }
+        blocks.reverse();
+        blocks
+    }
+}
+
+/// A position the path can be at in a call, with the blocks kept since the call
+#[derive(Debug, Clone)]
+struct Config {
+    /// The current position, `None` before the first block of the call
+    state: Option<usize>,
+    /// The iteration of each star enclosing the current position, from 1
+    iterations: Vec<usize>,
+    kept: Kept,
+    /// The iterations and calls dropped since the call
+    dropped: Dropped,
+    /// The blocks kept before the implicit calls since the last transition, and what was dropped before them,
+    /// as they are only kept if the next position is
+    implicit: Option<(Kept, Dropped)>,
+    /// The blocks kept, and what was dropped, if the path ends at the current position:
+    /// the current iteration of each loop is then kept as its last one
+    tail: (Kept, Dropped),
+    /// `tail` when the current iteration of each star enclosing the current position started
+    starts: Vec<(Kept, Dropped)>,
+    /// The implicit calls since the last transition, added to `tail` after the iterations it ends
+    implicit_calls: Vec<(Kept, Dropped)>,
+}
+
+impl Config {
+    fn is_kept(&self, k: usize) -> bool {
+        self.iterations.iter().all(|&i| i <= k)
+    }
+
+    /// Drops the blocks of the implicit calls since the last transition unless `keep`
+    fn settle(&mut self, keep: bool) {
+        if let Some((start, dropped)) = self.implicit.take() {
+            if !keep {
+                self.kept = start;
+                self.dropped = dropped;
+            }
+        }
+    }
+
+    /// Ends the iterations of the stars from `level` on, dropping the blocks of the outermost one
+    /// after the first `k` iterations from `tail`
+    fn end_iterations(&mut self, level: usize, k: usize, counted: bool) {
+        if let Some(i) = (level..self.iterations.len()).find(|&i| self.iterations[i] > k) {
+            self.tail = self.starts[i];
+            if counted {
+                self.tail.1.iterations += 1;
+            }
+        }
+    }
+
+    /// Adds the implicit calls since the last transition to `tail` if `keep`
+    fn settle_tail<Alphabet: Clone>(&mut self, keep: bool, blocks: &mut KeptBlocks<Alphabet>) {
+        for (callee, dropped) in self.implicit_calls.drain(..) {
+            if keep {
+                if callee.is_some() {
+                    self.tail.0 = blocks.push(self.tail.0, KeptItem::Call(callee));
+                }
+                self.tail.1 += dropped;
+            }
+        }
+    }
+
+    fn into_val<Alphabet: Clone>(self, blocks: &KeptBlocks<Alphabet>) -> Val<Alphabet> {
+        let kept = Val::Literals(blocks.to_vec(self.kept));
+        if self.dropped == Dropped::default() {
+            kept
+        } else {
+            Val::Seq(vec![kept, Val::Dropped(self.dropped)])
+        }
+    }
+}
+
+/// A call in progress
+struct Frame<'a, Alphabet, Name> {
+    fun: Name,
+    automaton: &'a Automaton<Alphabet, Name>,
+    /// The positions the path can be at, the first one preferred, as the first alternatives by `RegExp::parse_k`
+    configs: Vec<Config>,
+    /// Whether the call counts in the nesting of `fun`, otherwise its blocks are dropped
+    counted: bool,
+    /// Iterations of each loop of `fun` kept
+    loops: usize,
+    /// Whether the call is implicit
+    implicit: bool,
+}
+
+impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> Frame<'_, Alphabet, Name> {
+    fn follows(&self, config: &Config) -> &[Edge] {
+        match config.state {
+            Some(p) => &self.automaton.follow[p],
+            None => &self.automaton.first,
+        }
+    }
+
+    fn can_end(&self, config: &Config) -> bool {
+        match config.state {
+            Some(p) => self.automaton.last[p],
+            None => self.automaton.nullable,
+        }
+    }
+
+    fn is_kept(&self, config: &Config) -> bool {
+        self.counted && config.is_kept(self.loops)
+    }
+
+    /// Returns the configurations after the transitions to a position matching `symbol`
+    fn advance(&self, matches: impl Fn(&Symbol<Alphabet, Name>) -> bool) -> Vec<Config> {
+        let mut configs: Vec<Config> = Vec::new();
+        for config in &self.configs {
+            for &edge in self.follows(config) {
+                if matches(&self.automaton.positions[edge.to].symbol)
+                    && !configs.iter().any(|c| c.state == Some(edge.to))
+                {
+                    configs.push(self.take(config, edge));
+                }
+            }
+        }
+        configs
+    }
+
+    fn take(&self, config: &Config, edge: Edge) -> Config {
+        let stars_before: &[usize] = match config.state {
+            Some(p) => &self.automaton.positions[p].stars,
+            None => &[],
+        };
+        let stars_after = &self.automaton.positions[edge.to].stars;
+        let mut iterations = Vec::with_capacity(stars_after.len());
+        // the stars enclosing both positions go on, unless inside the star that iterates
+        let mut going_on = true;
+        // the outermost star starting an iteration, the others being nested in it
+        let mut started = None;
+        // the number of stars going on
+        let mut level = 0;
+        for (i, star) in stars_after.iter().enumerate() {
+            if going_on && stars_before.get(i) == Some(star) {
+                if edge.iterates == Some(*star) {
+                    iterations.push(config.iterations[i] + 1);
+                    started = Some(i);
+                    going_on = false;
+                } else {
+                    iterations.push(config.iterations[i]);
+                    level += 1;
+                }
+            } else {
+                going_on = false;
+                started = started.or(Some(i));
+                iterations.push(1);
+            }
+        }
+        let mut dropped = config.dropped;
+        // an iteration after the first `k` ones of a loop, in a kept iteration of the enclosing loops
+        if let Some(i) = started {
+            let k = self.loops;
+            if self.counted && iterations[i] > k && iterations[..i].iter().all(|&j| j <= k) {
+                dropped.iterations += 1;
+            }
+        }
+        let mut ended = config.clone();
+        ended.end_iterations(level, self.loops, self.counted);
+        let mut starts = ended.starts;
+        starts.truncate(level);
+        starts.resize(iterations.len(), ended.tail);
+        Config {
+            state: Some(edge.to),
+            iterations,
+            kept: config.kept,
+            dropped,
+            implicit: config.implicit,
+            tail: ended.tail,
+            starts,
+            implicit_calls: ended.implicit_calls,
+        }
+    }
+
+    /// Adds the blocks kept by a call that returned, and what it dropped
+    fn returned(&mut self, callee: &Config, implicit: bool, blocks: &mut KeptBlocks<Alphabet>) {
+        for i in 0..self.configs.len() {
+            if implicit || self.is_kept(&self.configs[i]) {
+                let config = &mut self.configs[i];
+                if callee.kept.is_some() {
+                    config.kept = blocks.push(config.kept, KeptItem::Call(callee.kept));
+                }
+                config.dropped += callee.dropped;
+            }
+            if self.counted && implicit {
+                self.configs[i].implicit_calls.push((callee.kept, callee.dropped));
+            } else if self.counted {
+                let tail = &mut self.configs[i].tail;
+                if callee.kept.is_some() {
+                    tail.0 = blocks.push(tail.0, KeptItem::Call(callee.kept));
+                }
+                tail.1 += callee.dropped;
+            }
+        }
+    }
+
+    /// Returns the configuration the path ends in, keeping the iterations it ends in
+    fn truncate(&self, mut config: Config, blocks: &mut KeptBlocks<Alphabet>) -> Config {
+        // the iterations ending at the position are done, as the next ones haven't started
+        let ends = config.state.map_or(&[][..], |p| &self.automaton.ends[p][..]);
+        let stars = config.state.map_or(&[][..], |p| &self.automaton.positions[p].stars[..]);
+        let level = stars.iter().position(|star| ends.contains(star)).unwrap_or(stars.len());
+        config.end_iterations(level, self.loops, self.counted);
+        config.settle_tail(self.counted, blocks);
+        config.kept = config.tail.0;
+        config.dropped = config.tail.1;
+        config
+    }
+
+    /// Returns the configuration the call ends in, if it can end
+    fn end(&mut self) -> Option<Config> {
+        let i = self.configs.iter().position(|config| self.can_end(config))?;
+        let keep = self.is_kept(&self.configs[i]);
+        let mut config = self.configs.swap_remove(i);
+        config.settle(keep);
+        Some(config)
+    }
+}
+
+impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> Automata<Alphabet, Name> {
+    /// Compiles the regular expressions of all functions
+    pub fn new(res: &BTreeMap<Name, RegExp<Alphabet, Name>>) -> Self {
+        Self { automata: res.iter().map(|(name, re)| (name.clone(), Automaton::from_regex(re))).collect() }
+    }
+
+    /// Parses a prefix of `s` as a call to `fun`, with the bounds of each function in `bounds`,
+    /// as `RegExp::parse_bounded`.
+    ///
+    /// `firsts` maps the first block of each function to the function, for implicit calls.
+    pub fn parse_k<'a>(
+        &self,
+        fun: &Name,
+        s: &'a [Alphabet],
+        firsts: &BTreeMap<Alphabet, Name>,
+        bounds: &FunctionBounds<Name>,
+    ) -> ParseResult<'a, Alphabet, Val<Alphabet>> {
+        let mut parser = Parser::new(self, fun, bounds)?;
+        for (i, block) in s.iter().enumerate() {
+            let fed = parser.feed(block, firsts).map_err(|err| match err {
+                ParseErr::Invalid(msg, _) => ParseErr::Invalid(msg, s.len() - i),
+                err => err,
+            });
+            if let Some(val) = fed? {
+                return Ok((val, &s[i..]));
+            }
+        }
+        let (val, complete) = parser.finish();
+        if complete {
+            Ok((val, &[]))
+        } else {
+            Err(ParseErr::Abort(val))
+        }
+    }
+}
+
+/// Parser of a call read one block at a time
+pub struct Parser<'a, Alphabet, Name> {
+    automata: &'a Automata<Alphabet, Name>,
+    /// The calls in progress
+    stack: Vec<Frame<'a, Alphabet, Name>>,
+    /// The number of counted calls in progress of each function
+    nesting: BTreeMap<Name, usize>,
+    bounds: &'a FunctionBounds<Name>,
+    /// The blocks kept by the configurations of all the calls
+    blocks: KeptBlocks<Alphabet>,
+}
+
+impl<'a, Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> Parser<'a, Alphabet, Name> {
+    /// Starts parsing a call to `fun`
+    pub fn new(
+        automata: &'a Automata<Alphabet, Name>,
+        fun: &Name,
+        bounds: &'a FunctionBounds<Name>,
+    ) -> Result<Self, ParseErr<Alphabet>> {
+        let blocks = KeptBlocks { nodes: Vec::new() };
+        let mut parser = Self { automata, stack: Vec::new(), nesting: BTreeMap::new(), bounds, blocks };
+        let frame = parser.call(fun, false)?;
+        parser.stack.push(frame);
+        Ok(parser)
+    }
+
+    /// Reads `block`, or returns the result of the call if it returns before `block`.
+    /// If `block` can't be read, the rest of the path in the error is `block` alone.
+    pub fn feed(
+        &mut self,
+        block: &Alphabet,
+        firsts: &BTreeMap<Alphabet, Name>,
+    ) -> Result<Option<Val<Alphabet>>, ParseErr<Alphabet>> {
+        loop {
+            let frame = self.stack.last_mut().unwrap();
+            let literals = frame.advance(|symbol| matches!(symbol, Symbol::Literal(c) if c == block));
+            if !literals.is_empty() {
+                frame.configs = literals;
+                for i in 0..frame.configs.len() {
+                    let keep = frame.is_kept(&frame.configs[i]);
+                    let config = &mut frame.configs[i];
+                    config.settle(keep);
+                    config.settle_tail(frame.counted, &mut self.blocks);
+                    // the tail shares the blocks kept since it last differed from them
+                    let shared = config.tail.0 == config.kept;
+                    if keep {
+                        config.kept = self.blocks.push(config.kept, KeptItem::Block(block.clone()));
+                    }
+                    if keep && shared {
+                        config.tail.0 = config.kept;
+                    } else if frame.counted {
+                        config.tail.0 = self.blocks.push(config.tail.0, KeptItem::Block(block.clone()));
+                    }
+                }
+                return Ok(None);
+            }
+            let callee = firsts.get(block);
+            let calls = frame.advance(|symbol| matches!(symbol, Symbol::Var(x) if Some(x) == callee));
+            if !calls.is_empty() {
+                let callee = self.call(callee.unwrap(), false)?;
+                let frame = self.stack.last_mut().unwrap();
+                frame.configs = calls;
+                // the implicit calls before the call are parsed as part of it
+                for i in 0..frame.configs.len() {
+                    let kept = frame.is_kept(&frame.configs[i]);
+                    let config = &mut frame.configs[i];
+                    config.settle(callee.counted && kept);
+                    config.settle_tail(frame.counted && callee.counted, &mut self.blocks);
+                    if kept && !callee.counted {
+                        config.dropped.calls += 1;
+                    }
+                    if frame.counted && !callee.counted {
+                        config.tail.1.calls += 1;
+                    }
+                }
+                self.stack.push(callee);
+                continue;
+            }
+            // a function that is done returns before an implicit call
+            let done = frame.configs.iter().all(|config| frame.follows(config).is_empty());
+            if let (Some(callee), false) = (callee, done) {
+                // an implicit call, e.g. of a callback or a signal handler, belongs to the next position
+                let callee = self.call(callee, true)?;
+                let frame = self.stack.last_mut().unwrap();
+                for config in &mut frame.configs {
+                    config.implicit.get_or_insert((config.kept, config.dropped));
+                    if frame.counted && !callee.counted {
+                        config.dropped.calls += 1;
+                        config.tail.1.calls += 1;
+                    }
+                }
+                self.stack.push(callee);
+                continue;
+            }
+            if let Some(end) = frame.end() {
+                let frame = self.stack.pop().unwrap();
+                if frame.counted {
+                    *self.nesting.get_mut(&frame.fun).unwrap() -= 1;
+                }
+                match self.stack.last_mut() {
+                    Some(caller) => caller.returned(&end, frame.implicit, &mut self.blocks),
+                    None => return Ok(Some(end.into_val(&self.blocks))),
+                }
+                continue;
+            }
+            let expected: Vec<_> = frame
+                .configs
+                .iter()
+                .flat_map(|config| frame.follows(config))
+                .map(|edge| &frame.automaton.positions[edge.to].symbol)
+                .collect();
+            return Err(ParseErr::Invalid(format!("expected one of {:?} found {:?}", expected, block), 1));
+        }
+    }
+
+    /// Returns the result of the calls in progress, and whether they can all return
+    pub fn finish(mut self) -> (Val<Alphabet>, bool) {
+        let mut complete = true;
+        let mut callee: Option<(Config, bool)> = None;
+        while let Some(mut frame) = self.stack.pop() {
+            if let Some((callee, implicit)) = callee {
+                frame.returned(&callee, implicit, &mut self.blocks);
+            }
+            complete &= frame.configs.iter().any(|config| frame.can_end(config));
+            let implicit = frame.implicit;
+            // the blocks kept by the calls in progress are those of their last iterations
+            let i = frame.configs.iter().position(|config| frame.can_end(config)).unwrap_or(0);
+            let end = frame.configs.swap_remove(i);
+            callee = Some((frame.truncate(end, &mut self.blocks), implicit));
+        }
+        (callee.unwrap().0.into_val(&self.blocks), complete)
+    }
+
+    /// Starts a call to `fun`, whose blocks are dropped if it is nested in itself as many times as its bound
+    fn call(&mut self, fun: &Name, implicit: bool) -> Result<Frame<'a, Alphabet, Name>, ParseErr<Alphabet>> {
+        let automaton = self
+            .automata
+            .automata
+            .get(fun)
+            .ok_or_else(|| ParseErr::Fatal(Error::UnknownFunction(format!("{:?}", fun))))?;
+        let bounds = self.bounds.of(fun);
+        let level = self.nesting.entry(fun.clone()).or_default();
+        let counted = *level < bounds.recursion;
+        if counted {
+            *level += 1;
+        }
+        let config = Config {
+            state: None,
+            iterations: Vec::new(),
+            kept: None,
+            dropped: Dropped::default(),
+            implicit: None,
+            tail: (None, Dropped::default()),
+            starts: Vec::new(),
+            implicit_calls: Vec::new(),
+        };
+        Ok(Frame { fun: fun.clone(), automaton, configs: vec![config], counted, loops: bounds.loops, implicit })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::re::Bounds;
+
+    fn lit(c: i32) -> Arc<RegExp<i32, i32>> {
+        Arc::new(RegExp::Literal(c))
+    }
+
+    /// Returns the blocks kept by `RegExp::parse_k` and by the automata
+    fn both(
+        env: &BTreeMap<i32, RegExp<i32, i32>>,
+        firsts: &BTreeMap<i32, i32>,
+        fun: i32,
+        path: &[i32],
+        bounds: &FunctionBounds<i32>,
+    ) -> (Vec<i32>, Vec<i32>) {
+        let into_vec = |res: ParseResult<i32, Val<i32>>| match res {
+            Ok((val, _)) | Err(ParseErr::Abort(val)) => val.into_vec(),
+            Err(err) => panic!("{:?}", err),
+        };
+        let regex = into_vec(RegExp::Var(fun).parse_bounded(path, env, firsts, bounds));
+        let automaton = into_vec(Automata::new(env).parse_k(&fun, path, firsts, bounds));
+        (regex, automaton)
+    }
+
+    #[test]
+    fn same_reductions_as_parse_k() {
+        // f = 1 (2 | f) 3, g = 6 ((7 8)* 10)* 9, h = 11 12, i = (20 21 | 20 22)* 23
+        let f = RegExp::Seq(vec![lit(1), RegExp::alter(lit(2), Arc::new(RegExp::Var(0))), lit(3)]);
+        let inner = Arc::new(RegExp::Star(Arc::new(RegExp::Literals(vec![7, 8]))));
+        let outer = Arc::new(RegExp::Star(RegExp::concat(inner, lit(10))));
+        let g = RegExp::Seq(vec![lit(6), outer, lit(9)]);
+        let h = RegExp::Literals(vec![11, 12]);
+        let alternatives = RegExp::alter(Arc::new(RegExp::Literals(vec![20, 21])), Arc::new(RegExp::Literals(vec![20, 22])));
+        let i = RegExp::Concat(Arc::new(RegExp::Star(alternatives)), lit(23));
+        let env = BTreeMap::from([(0, f), (1, g), (2, h), (3, i)]);
+        let firsts = BTreeMap::from([(1, 0), (6, 1), (11, 2), (20, 3)]);
+        let paths: [(i32, &[i32]); 7] = [
+            (0, &[1, 1, 1, 2, 3, 3, 3]),
+            (1, &[6, 7, 8, 7, 8, 7, 8, 10, 10, 7, 8, 10, 7, 8, 7, 8, 10, 9]),
+            (1, &[6, 10, 7, 8, 10, 9]),
+            // implicit calls of `h`
+            (1, &[6, 7, 11, 12, 8, 7, 8, 11, 12, 10, 9]),
+            (0, &[1, 11, 12, 1, 2, 3, 3]),
+            (3, &[20, 22, 20, 21, 20, 22, 20, 22, 23]),
+            // ends in the middle of `g`
+            (1, &[6, 7, 8, 7]),
+        ];
+        // and with the bounds of `f`, `g` and `h` apart from those of the other functions
+        let mut overridden = FunctionBounds::new(1);
+        overridden.functions.insert(0, Bounds { loops: 1, recursion: 2 });
+        overridden.functions.insert(1, Bounds { loops: 2, recursion: 1 });
+        overridden.functions.insert(2, Bounds { loops: 1, recursion: 0 });
+        for bounds in [FunctionBounds::new(1), FunctionBounds::new(2), FunctionBounds::new(3), overridden] {
+            for (fun, path) in paths {
+                let (regex, automaton) = both(&env, &firsts, fun, path, &bounds);
+                assert_eq!(regex, automaton, "{:?} {:?}", path, bounds);
+            }
+        }
+        let automata = Automata::new(&env);
+        assert_eq!(automata.automata[&1].positions.len(), 5);
+        let bounds = FunctionBounds::new(1);
+        assert!(matches!(automata.parse_k(&1, &[6, 7, 9], &firsts, &bounds), Err(ParseErr::Invalid(..))));
+        assert!(matches!(automata.parse_k(&4, &[6], &firsts, &bounds), Err(ParseErr::Fatal(_))));
+    }
+
+    #[test]
+    fn kept_blocks_are_shared() {
+        // f = 1 (2 3)* 4, with every iteration kept
+        let f = RegExp::Seq(vec![lit(1), Arc::new(RegExp::Star(Arc::new(RegExp::Literals(vec![2, 3])))), lit(4)]);
+        let automata = Automata::new(&BTreeMap::from([(0, f)]));
+        let mut path = vec![1];
+        path.extend([2, 3].repeat(10_000));
+        path.push(4);
+        let bounds = FunctionBounds::new(20_000);
+        let mut parser = Parser::new(&automata, &0, &bounds).unwrap();
+        for block in &path {
+            assert!(parser.feed(block, &BTreeMap::new()).unwrap().is_none());
+        }
+        // each block is stored once, however many transitions read it
+        assert_eq!(parser.blocks.nodes.len(), path.len());
+        let (val, complete) = parser.finish();
+        assert!(complete);
+        assert_eq!(val.into_vec(), path);
+    }
+}
@@ -0,0 +1,256 @@
+//! Reduces recorded paths from the command line
+
+use std::{env, fs, process::ExitCode};
+
+use path_reduction::{
+    convert::{Conversion, EliminationOrder},
+    error::{Error, Result},
+    hash::hash_path,
+    path_reduction::{ErrorPolicy, FunctionK, FunctionRef, PathReducer, ReducerOptions, ReductionMode},
+};
+
+const USAGE: &str = "\
+usage: path-reduce [options] <cfg-file> <trace-file>...
+
+Reduces each trace in the trace files, and prints its hash and the reduced path.
+With --sizes, the trace files are optional.
+A text trace file holds one trace per line, as block ids separated by spaces or commas.
+
+options:
+    --k <k>                         bound of loop iterations and recursion depth (default 1)
+    --loop-k <k>                    bound of loop iterations (default k)
+    --recursion-k <k>               bound of recursion depth (default k)
+    --function-k <fun>=[<loop-k>],[<recursion-k>]
+                                    bounds of the function with id or name <fun>, e.g. log=,0 drops the calls of log
+    --mode simple|regex|automaton   reduction algorithm (default regex)
+    --on-error full|empty|prefix|resync|fail
+                                    result for invalid paths (default fail)
+    --conversion elimination|structural
+                                    algorithm converting the CFGs (default elimination)
+    --elimination index|min-degree|weight|rpo|dominator
+                                    order of the states eliminated when converting the CFGs (default index)
+    --simplify                      simplifies the regular expressions after conversion
+    --sizes                         prints the size of the regular expression of each function first
+    --entry <fun-id>                id of the function the traces start in (default 0)
+    --threads <n>                   threads converting the CFGs and reducing the traces (default one per core)
+    --binary                        trace files are streams of native endian 32-bit block ids
+    -h, --help                      prints this message";
+
+struct Args {
+    options: ReducerOptions,
+    entry: i32,
+    binary: bool,
+    sizes: bool,
+    cfg_file: String,
+    trace_files: Vec<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> std::result::Result<Args, String> {
+    let mut options = ReducerOptions {
+        on_error: Some(ErrorPolicy::Fail),
+        ..ReducerOptions::default()
+    };
+    let mut entry = 0;
+    let mut binary = false;
+    let mut sizes = false;
+    let mut files = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("missing value of {}", name));
+        match arg.as_str() {
+            "--k" => options.k = value("--k")?.parse().map_err(|err| format!("invalid k: {}", err))?,
+            "--loop-k" => {
+                options.loop_k = Some(value("--loop-k")?.parse().map_err(|err| format!("invalid loop k: {}", err))?)
+            }
+            "--recursion-k" => {
+                options.recursion_k =
+                    Some(value("--recursion-k")?.parse().map_err(|err| format!("invalid recursion k: {}", err))?)
+            }
+            "--function-k" => options.function_k.push(parse_function_k(&value("--function-k")?)?),
+            "--mode" => {
+                options.mode = match value("--mode")?.as_str() {
+                    "simple" => ReductionMode::Simple,
+                    "regex" => ReductionMode::Regex,
+                    "automaton" => ReductionMode::Automaton,
+                    mode => return Err(format!("invalid mode {}", mode)),
+                }
+            }
+            "--on-error" => {
+                options.on_error = match value("--on-error")?.as_str() {
+                    "full" => Some(ErrorPolicy::FullPath),
+                    "empty" => Some(ErrorPolicy::EmptyPath),
+                    "prefix" => Some(ErrorPolicy::LongestValidPrefix),
+                    "resync" => Some(ErrorPolicy::Resync),
+                    "fail" => Some(ErrorPolicy::Fail),
+                    policy => return Err(format!("invalid error policy {}", policy)),
+                }
+            }
+            "--conversion" => {
+                options.conversion = match value("--conversion")?.as_str() {
+                    "elimination" => Conversion::StateElimination,
+                    "structural" => Conversion::Structural,
+                    conversion => return Err(format!("invalid conversion {}", conversion)),
+                }
+            }
+            "--elimination" => {
+                options.elimination = match value("--elimination")?.as_str() {
+                    "index" => EliminationOrder::Index,
+                    "min-degree" => EliminationOrder::MinDegree,
+                    "weight" => EliminationOrder::Weight,
+                    "rpo" => EliminationOrder::ReversePostOrder,
+                    "dominator" => EliminationOrder::Dominator,
+                    order => return Err(format!("invalid elimination order {}", order)),
+                }
+            }
+            "--simplify" => options.simplify = true,
+            "--sizes" => sizes = true,
+            "--entry" => {
+                entry = value("--entry")?.parse().map_err(|err| format!("invalid entry: {}", err))?
+            }
+            "--threads" => {
+                options.threads = value("--threads")?.parse().map_err(|err| format!("invalid threads: {}", err))?
+            }
+            "--binary" => binary = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() || (files.len() < 2 && !sizes) {
+        return Err("expected a CFG file and at least one trace file".to_string());
+    }
+    let cfg_file = files.remove(0);
+    Ok(Args {
+        options,
+        entry,
+        binary,
+        sizes,
+        cfg_file,
+        trace_files: files,
+    })
+}
+
+/// Parses `<fun>=[<loop-k>],[<recursion-k>]`
+fn parse_function_k(arg: &str) -> std::result::Result<FunctionK, String> {
+    let invalid = || format!("invalid function bounds {}", arg);
+    let (fun, bounds) = arg.rsplit_once('=').ok_or_else(invalid)?;
+    let (loop_k, recursion_k) = bounds.split_once(',').ok_or_else(invalid)?;
+    let bound = |k: &str| match k {
+        "" => Ok(None),
+        k => k.parse().map(Some).map_err(|_| invalid()),
+    };
+    let function = match fun.parse() {
+        Ok(fun_id) => FunctionRef::Id(fun_id),
+        Err(_) => FunctionRef::Name(fun.to_string()),
+    };
+    Ok(FunctionK { function, loop_k: bound(loop_k)?, recursion_k: bound(recursion_k)? })
+}
+
+/// Reads the traces in a trace file, with the line number of each trace for text files
+fn read_traces(file: &str, binary: bool) -> Result<Vec<(usize, Vec<i32>)>> {
+    let bytes = fs::read(file).map_err(|err| Error::Io(format!("{}: {}", file, err)))?;
+    if binary {
+        if bytes.len() % 4 != 0 {
+            return Err(Error::InvalidArgument(format!("{}: size is not a multiple of 4", file)));
+        }
+        let trace = bytes
+            .chunks_exact(4)
+            .map(|chunk| i32::from_ne_bytes(chunk.try_into().unwrap()))
+            .collect();
+        return Ok(vec![(1, trace)]);
+    }
+    let text = String::from_utf8_lossy(&bytes);
+    let mut traces = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let trace = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|id| !id.is_empty())
+            .map(|id| {
+                id.parse()
+                    .map_err(|_| Error::InvalidArgument(format!("{}:{}: invalid block id {}", file, i + 1, id)))
+            })
+            .collect::<Result<_>>()?;
+        traces.push((i + 1, trace));
+    }
+    Ok(traces)
+}
+
+fn run(args: Args) -> std::result::Result<(), String> {
+    let reducer = PathReducer::from_cfg_file_with_options(&args.cfg_file, args.options)
+        .map_err(|err| format!("{}: {}", args.cfg_file, err))?;
+    if args.sizes {
+        for (fun_id, size) in reducer.regex_sizes() {
+            println!("function {}\tsize {}", fun_id, size);
+        }
+    }
+    for file in &args.trace_files {
+        let traces = read_traces(file, args.binary).map_err(|err| err.to_string())?;
+        let paths: Vec<_> = traces.iter().map(|(_, trace)| &trace[..]).collect();
+        for ((line, _), reduced) in traces.iter().zip(reducer.reduce_batch(&paths, args.entry)) {
+            let reduced = reduced.map_err(|err| format!("{}:{}: {}", file, line, err))?;
+            let reduced_ids: Vec<_> = reduced.iter().map(|id| id.to_string()).collect();
+            println!("{}:{}\t{}\t{}", file, line, hash_path(&reduced), reduced_ids.join(" "));
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => {
+            if !msg.is_empty() {
+                eprintln!("path-reduce: {}", msg);
+            }
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("path-reduce: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> std::result::Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn options() {
+        let parsed = args(&["--k", "3", "--on-error", "empty", "cfg.json", "a.txt", "b.txt"]).unwrap();
+        assert_eq!(parsed.options.k, 3);
+        assert_eq!(parsed.options.on_error, Some(ErrorPolicy::EmptyPath));
+        assert_eq!(parsed.cfg_file, "cfg.json");
+        assert_eq!(parsed.trace_files, ["a.txt", "b.txt"]);
+        let simple = args(&["--mode", "simple", "--k", "2", "cfg.json", "a.txt"]).unwrap();
+        assert_eq!(simple.options.mode, ReductionMode::Simple);
+        assert_eq!(simple.options.k, 2);
+        assert!(args(&["--mode", "fast", "cfg.json", "a.txt"]).is_err());
+        let automaton = args(&["--mode", "automaton", "cfg.json", "a.txt"]).unwrap();
+        assert_eq!(automaton.options.mode, ReductionMode::Automaton);
+        let sizes = args(&["--elimination", "weight", "--simplify", "--sizes", "cfg.json"]).unwrap();
+        assert!(sizes.options.simplify);
+        assert_eq!(sizes.options.elimination, EliminationOrder::Weight);
+        assert!(sizes.sizes && sizes.trace_files.is_empty());
+        let structural = args(&["--conversion", "structural", "--threads", "4", "cfg.json", "a.txt"]).unwrap();
+        assert_eq!(structural.options.threads, 4);
+        assert_eq!(structural.options.conversion, Conversion::Structural);
+        assert!(args(&["cfg.json"]).is_err());
+        let bounds = args(&["--loop-k", "2", "--function-k", "log=,0", "--function-k", "3=4,", "cfg.json", "a.txt"]).unwrap();
+        assert_eq!((bounds.options.loop_k, bounds.options.recursion_k), (Some(2), None));
+        let log = FunctionK { function: FunctionRef::Name("log".to_string()), loop_k: None, recursion_k: Some(0) };
+        let fun_3 = FunctionK { function: FunctionRef::Id(3), loop_k: Some(4), recursion_k: None };
+        assert_eq!(bounds.options.function_k, [log, fun_3]);
+        assert!(args(&["--function-k", "log=0", "cfg.json", "a.txt"]).is_err());
+    }
+}
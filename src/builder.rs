@@ -0,0 +1,197 @@
+//! Safe construction of the CFGs of a program
+//!
+//! ```
+//! use path_reduction::{builder::CfgBuilder, PathReducer};
+//!
+//! let mut builder = CfgBuilder::new();
+//! let main = builder.add_function("main", 0, 2);
+//! let f = builder.add_function("f", 3, 3);
+//! builder.add_block(0).add_block(1).add_block(2).add_block(3);
+//! builder.set_call(1, f).add_edge(0, 1).add_edge(1, 2);
+//! let reducer = PathReducer::from_cfgs(builder.build()?, 1)?;
+//! assert_eq!(reducer.reduce(&[0, 3, 2], main)?, vec![0, 3, 2]);
+//! # Ok::<(), path_reduction::Error>(())
+//! ```
+
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use crate::{
+    error::{Error, Result},
+    extern_cfg::{BlockID, FunID},
+    intern_cfg::CFG,
+    program::{Block, Function, Program, EXTERN_CALL, INDIRECT_CALL, NOT_A_CALL},
+};
+
+/// Builds the CFGs of a program block by block.
+///
+/// Misuses, such as adding a block twice, are reported by `build`,
+/// as well as the errors found by `Program::validate`.
+#[derive(Debug, Clone, Default)]
+pub struct CfgBuilder {
+    program: Program,
+    /// The first misuse of the builder
+    error: Option<Error>,
+}
+
+impl CfgBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a function starting with block `entry` and ending with block `exit`,
+    /// and returns its id
+    pub fn add_function(&mut self, name: impl Into<String>, entry: BlockID, exit: BlockID) -> FunID {
+        self.program.functions.push(Function {
+            name: name.into(),
+            entry,
+            exit,
+            extra_exits: Vec::new(),
+        });
+        (self.program.functions.len() - 1) as FunID
+    }
+
+    /// Adds `block_id` to the blocks where the function `fun_id` returns, besides its exit block
+    pub fn add_exit(&mut self, fun_id: FunID, block_id: BlockID) -> &mut Self {
+        match usize::try_from(fun_id).ok().and_then(|i| self.program.functions.get_mut(i)) {
+            Some(function) => function.extra_exits.push(block_id),
+            None => self.fail(Error::UnknownFunction(fun_id.to_string())),
+        }
+        self
+    }
+
+    /// Adds a block that is not a call block
+    pub fn add_block(&mut self, block_id: BlockID) -> &mut Self {
+        match self.program.blocks.entry(block_id) {
+            Entry::Vacant(entry) => {
+                entry.insert(Block::default());
+            }
+            Entry::Occupied(_) => {
+                self.fail(Error::InvalidArgument(format!("block {} is added twice", block_id)))
+            }
+        }
+        self
+    }
+
+    /// Marks `block_id` as a call to the function `fun_id`
+    pub fn set_call(&mut self, block_id: BlockID, fun_id: FunID) -> &mut Self {
+        if fun_id < 0 {
+            self.fail(Error::UnknownFunction(fun_id.to_string()));
+        } else {
+            self.set_calls(block_id, fun_id);
+        }
+        self
+    }
+
+    /// Marks `block_id` as a call to an external function
+    pub fn set_extern_call(&mut self, block_id: BlockID) -> &mut Self {
+        self.set_calls(block_id, EXTERN_CALL);
+        self
+    }
+
+    /// Marks `block_id` as an indirect call to one of `callees`,
+    /// or to unknown functions if `callees` is empty
+    pub fn set_indirect_call(&mut self, block_id: BlockID, callees: impl IntoIterator<Item = FunID>) -> &mut Self {
+        let callees: Vec<_> = callees.into_iter().collect();
+        if let Some(&callee) = callees.iter().find(|&&callee| callee < 0) {
+            self.fail(Error::UnknownFunction(callee.to_string()));
+            return self;
+        }
+        self.set_calls(block_id, INDIRECT_CALL);
+        if let Some(block) = self.program.blocks.get_mut(&block_id) {
+            block.callees = callees;
+        }
+        self
+    }
+
+    /// Adds an edge from `from` to `to`
+    pub fn add_edge(&mut self, from: BlockID, to: BlockID) -> &mut Self {
+        if let Some(block) = self.get_block_mut(from) {
+            block.successors.push(to);
+        }
+        self
+    }
+
+    /// Returns the program built so far, after checking it
+    pub fn program(&self) -> Result<&Program> {
+        if let Some(err) = &self.error {
+            return Err(err.clone());
+        }
+        check(&self.program)?;
+        Ok(&self.program)
+    }
+
+    /// Returns the control flow graphs of all functions, indexed by function id
+    pub fn build(&self) -> Result<BTreeMap<FunID, CFG<BlockID, FunID>>> {
+        self.program()?.to_cfgs()
+    }
+
+    fn set_calls(&mut self, block_id: BlockID, calls: FunID) {
+        if let Some(block) = self.get_block_mut(block_id) {
+            if block.calls != NOT_A_CALL && block.calls != calls {
+                let msg = format!("block {} already calls {}", block_id, block.calls);
+                self.fail(Error::InvalidArgument(msg));
+            } else {
+                block.calls = calls;
+            }
+        }
+    }
+
+    /// Returns the block `block_id`, recording an error if it is not added
+    fn get_block_mut(&mut self, block_id: BlockID) -> Option<&mut Block> {
+        if !self.program.blocks.contains_key(&block_id) {
+            self.fail(Error::UnknownBlock(block_id.to_string()));
+        }
+        self.program.blocks.get_mut(&block_id)
+    }
+
+    fn fail(&mut self, err: Error) {
+        self.error.get_or_insert(err);
+    }
+}
+
+/// Checks `program`, failing with its first diagnostic that is an error
+fn check(program: &Program) -> Result<()> {
+    match program.validate().iter().find(|diagnostic| diagnostic.is_error()) {
+        Some(diagnostic) => Err(diagnostic.into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misuses_are_reported() {
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 1);
+        builder.add_block(0).add_block(1).add_edge(0, 1);
+        assert!(builder.build().is_ok());
+        builder.add_edge(2, 0);
+        assert_eq!(builder.build().err(), Some(Error::UnknownBlock("2".to_string())));
+
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 0);
+        builder.add_block(0).add_block(0);
+        assert!(matches!(builder.build(), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn dangling_references() {
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 1);
+        builder.add_block(0).add_block(1).add_edge(0, 1).set_call(1, 1);
+        assert_eq!(builder.build().err(), Some(Error::UnknownFunction("1".to_string())));
+
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 1);
+        builder.add_block(0).add_block(1).add_edge(0, 2);
+        assert_eq!(builder.build().err(), Some(Error::UnknownBlock("2".to_string())));
+
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 0);
+        builder.add_function("f", 0, 0);
+        builder.add_block(0);
+        assert!(matches!(builder.build(), Err(Error::DuplicateEntry { .. })));
+    }
+}
@@ -0,0 +1,54 @@
+//! Little endian encoding shared by the binary file formats
+
+use crate::error::{Error, Result};
+
+pub fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+pub fn put_i32(buf: &mut Vec<u8>, n: i32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Reads values from a buffer
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    /// Builds the error reported for malformed input
+    invalid: fn(String) -> Error,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8], invalid: fn(String) -> Error) -> Self {
+        Self { buf, invalid }
+    }
+
+    /// Returns the error reported for malformed input
+    pub fn invalid(&self, msg: impl Into<String>) -> Error {
+        (self.invalid)(msg.into())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(self.invalid("unexpected end of file"));
+        }
+        let (bytes, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
@@ -1,22 +1,522 @@
 use core::slice;
-use std::ffi::{c_char, c_int, CString};
+use std::{
+   cell::{Cell, RefCell},
+   ffi::{c_char, c_int, c_void, CStr, CString},
+   fs::File,
+   io::{BufReader, BufWriter, Write},
+   panic::{self, AssertUnwindSafe},
+   ptr,
+};
 
 use crate::{
-   extern_cfg::{process_top_level, BlockID, FunID, TopLevel},
-   hash::hash_path,
-   path_reduction::PathReducer,
+   error::{Error, Result},
+   convert::{Conversion, EliminationOrder},
+   extern_cfg::{process_top_level, read_top_level, BlockID, FunID, TopLevel},
+   hash::{hash64, hash_path, write_digest, DigestKind},
+   path_reduction::{
+      DiagnosticSink, ErrorPolicy, FunctionK, FunctionRef, PathReducer, ReducerOptions, ReductionMode, ReductionSession,
+      ReductionStats,
+   },
+   validate::Diagnostic,
 };
 
+/// Status code returned by every exported function
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathReductionStatus {
+   Ok = 0,
+   NullPointer = 1,
+   InvalidArgument = 2,
+   InvalidCfg = 3,
+   InvalidPath = 4,
+   Panic = 5,
+   Io = 6,
+   InvalidCache = 7,
+   StaleCache = 8,
+}
+
+impl From<&Error> for PathReductionStatus {
+   fn from(err: &Error) -> Self {
+      match err {
+         Error::NullPointer(_) => PathReductionStatus::NullPointer,
+         Error::InvalidArgument(_) => PathReductionStatus::InvalidArgument,
+         Error::UnknownBlock(_)
+         | Error::UnknownFunction(_)
+         | Error::DuplicateEntry { .. }
+         | Error::NoFirstBlock(_)
+         | Error::NoExit(_)
+         | Error::InvalidCfgFile(_) => PathReductionStatus::InvalidCfg,
+         Error::NoEntryFunction(_) | Error::InvalidPath(_) => PathReductionStatus::InvalidPath,
+         Error::Io(_) => PathReductionStatus::Io,
+         Error::InvalidCache(_) => PathReductionStatus::InvalidCache,
+         Error::StaleCache => PathReductionStatus::StaleCache,
+         Error::Panic(_) => PathReductionStatus::Panic,
+      }
+   }
+}
+
+thread_local! {
+   /// Message of the last error on this thread
+   static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+thread_local! {
+   /// Reduced paths of the calls on this thread that only return their hashes, reused across calls
+   static REDUCED_PATH: Cell<Vec<BlockID>> = const { Cell::new(Vec::new()) };
+}
+
+/// Reduces `path` into the buffer of this thread, and returns `f` of the reduced path and the statistics
+fn with_reduced_path<R>(
+   reducer: &PathReducer<BlockID, FunID>,
+   path: &[BlockID],
+   entry_fun_id: FunID,
+   f: impl FnOnce(&[BlockID], ReductionStats) -> R,
+) -> Result<R> {
+   // taken rather than borrowed, in case the diagnostics callback reduces paths too
+   let mut reduced_path = REDUCED_PATH.take();
+   let res = reducer
+      .reduce_into_with_stats(path, entry_fun_id, &mut reduced_path)
+      .map(|stats| f(&reduced_path, stats));
+   REDUCED_PATH.set(reduced_path);
+   res
+}
+
+fn set_last_error(err: &Error) {
+   // a message with an interior NUL is reported as an empty message
+   let msg = CString::new(err.to_string()).unwrap_or_default();
+   LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+/// Runs `f`, turning errors and panics into status codes.
+/// The message of the error is kept for `path_reduction_last_error`.
+fn ffi_call(f: impl FnOnce() -> Result<()>) -> PathReductionStatus {
+   let res = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+      let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+         msg.to_string()
+      } else if let Some(msg) = payload.downcast_ref::<String>() {
+         msg.clone()
+      } else {
+         "unknown panic".to_string()
+      };
+      Err(Error::Panic(msg))
+   });
+   match res {
+      Ok(()) => {
+         LAST_ERROR.with(|last| *last.borrow_mut() = None);
+         PathReductionStatus::Ok
+      }
+      Err(err) => {
+         set_last_error(&err);
+         PathReductionStatus::from(&err)
+      }
+   }
+}
+
+/// `PathReducerOptions::on_error` reading the policy from `PATH_REDUCTION_ON_ERROR`
+const ON_ERROR_FROM_ENV: c_int = -1;
+
+/// Receives the message of an invalid path, and `PathReducerOptions::diagnostics_data`
+pub type DiagnosticsCallback = unsafe extern "C" fn(data: *mut c_void, message: *const c_char);
+
+/// Options of `get_path_reducer_with_options`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PathReducerOptions {
+   /// Bound on loop iterations and recursion depth, in every mode, unless set apart below
+   pub k: c_int,
+   /// A `ReductionMode`
+   pub mode: c_int,
+   /// A `DigestKind`
+   pub digest: c_int,
+   /// An `ErrorPolicy`, or `ON_ERROR_FROM_ENV`
+   pub on_error: c_int,
+   /// Called with each invalid path, may be NULL
+   pub diagnostics: Option<DiagnosticsCallback>,
+   pub diagnostics_data: *mut c_void,
+   /// An `EliminationOrder`
+   pub elimination: c_int,
+   /// A `Conversion`
+   pub conversion: c_int,
+   /// Non-zero to simplify the regular expressions of the CFGs
+   pub simplify: c_int,
+   /// Number of threads of `reduce_paths_batch` and of the conversion of the CFGs, 0 for one per core
+   pub threads: c_int,
+   /// Bound on loop iterations, `k` if negative
+   pub loop_k: c_int,
+   /// Bound on recursion depth, `k` if negative
+   pub recursion_k: c_int,
+   /// Bounds of `function_k_count` functions overriding the bounds above, may be NULL if there are none
+   pub function_k: *const PathReductionFunctionK,
+   pub function_k_count: usize,
+}
+
+/// Bounds of a function in `PathReducerOptions`, see `FunctionK`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PathReductionFunctionK {
+   /// Id of the function, or -1 to select the functions named `name`
+   pub fun_id: FunID,
+   /// NUL terminated name, only read if `fun_id` is -1
+   pub name: *const c_char,
+   /// Bound on the loop iterations of the function, the bound of the reducer if negative
+   pub loop_k: c_int,
+   /// Bound on the recursion depth of the function, the bound of the reducer if negative
+   pub recursion_k: c_int,
+}
+
+/// `diagnostics_data` shared with the threads using the reducer
+struct DiagnosticsData(*mut c_void);
+
+// Safety: the caller of `get_path_reducer_with_options` guarantees that
+// the callback can be called from any thread with `diagnostics_data`
+unsafe impl Send for DiagnosticsData {}
+unsafe impl Sync for DiagnosticsData {}
+
+impl DiagnosticsData {
+   fn get(&self) -> *mut c_void {
+      self.0
+   }
+}
+
+impl TryFrom<&PathReducerOptions> for ReducerOptions {
+   type Error = Error;
+
+   fn try_from(options: &PathReducerOptions) -> Result<Self> {
+      let on_error = match options.on_error {
+         ON_ERROR_FROM_ENV => None,
+         policy => Some(ErrorPolicy::try_from(policy)?),
+      };
+      let diagnostics = options.diagnostics.map(|callback| {
+         let data = DiagnosticsData(options.diagnostics_data);
+         DiagnosticSink::new(move |err| {
+            let msg = CString::new(err.to_string()).unwrap_or_default();
+            unsafe { callback(data.get(), msg.as_ptr()) }
+         })
+      });
+      Ok(ReducerOptions {
+         k: k_arg(options.k)?,
+         mode: ReductionMode::try_from(options.mode)?,
+         digest: DigestKind::try_from(options.digest)?,
+         on_error,
+         diagnostics,
+         conversion: Conversion::try_from(options.conversion)?,
+         elimination: EliminationOrder::try_from(options.elimination)?,
+         simplify: options.simplify != 0,
+         threads: usize::try_from(options.threads)
+            .map_err(|_| Error::InvalidArgument(format!("negative number of threads {}", options.threads)))?,
+         loop_k: usize::try_from(options.loop_k).ok(),
+         recursion_k: usize::try_from(options.recursion_k).ok(),
+         function_k: Vec::new(),
+      })
+   }
+}
+
+/// Returns the bounds of the functions in `options`
+///
+/// Requires: `options.function_k` is NULL or points to `options.function_k_count` bounds with valid names
+unsafe fn function_k_arg(options: &PathReducerOptions) -> Result<Vec<FunctionK>> {
+   if options.function_k_count == 0 {
+      return Ok(Vec::new());
+   }
+   if options.function_k.is_null() {
+      return Err(Error::NullPointer("function_k"));
+   }
+   slice::from_raw_parts(options.function_k, options.function_k_count)
+      .iter()
+      .map(|function_k| {
+         let function = match function_k.fun_id {
+            -1 => FunctionRef::Name(str_arg(function_k.name, "name")?.to_string()),
+            fun_id => FunctionRef::Id(fun_id),
+         };
+         Ok(FunctionK {
+            function,
+            loop_k: usize::try_from(function_k.loop_k).ok(),
+            recursion_k: usize::try_from(function_k.recursion_k).ok(),
+         })
+      })
+      .collect()
+}
+
+fn k_arg(k: c_int) -> Result<usize> {
+   usize::try_from(k).map_err(|_| Error::InvalidArgument(format!("negative k {}", k)))
+}
+
+/// Returns the path of `path_size` blocks starting at `path`
+unsafe fn path_slice<'a>(path: *const BlockID, path_size: c_int) -> Result<&'a [BlockID]> {
+   if path_size < 0 {
+      return Err(Error::InvalidArgument(format!("negative path size {}", path_size)));
+   }
+   if path_size == 0 {
+      return Ok(&[]);
+   }
+   if path.is_null() {
+      return Err(Error::NullPointer("path"));
+   }
+   Ok(slice::from_raw_parts(path, path_size as usize))
+}
+
+/// Returns the message of the last error on the calling thread,
+/// or NULL if the last call succeeded.
+///
+/// The message is valid until the next call into the library on the same thread.
+#[no_mangle]
+pub extern "C" fn path_reduction_last_error() -> *const c_char {
+   LAST_ERROR.with(|last| {
+      last.borrow()
+         .as_ref()
+         .map_or(ptr::null(), |msg| msg.as_ptr())
+   })
+}
+
+/// Returns the default options: regex reduction with `k = 1` and SHA-256 digests,
+/// with the error policy and diagnostics left to the environment
+#[no_mangle]
+pub extern "C" fn path_reducer_default_options() -> PathReducerOptions {
+   let options = ReducerOptions::default();
+   PathReducerOptions {
+      k: options.k as c_int,
+      mode: options.mode as c_int,
+      digest: options.digest as c_int,
+      on_error: ON_ERROR_FROM_ENV,
+      diagnostics: None,
+      diagnostics_data: ptr::null_mut(),
+      elimination: options.elimination as c_int,
+      conversion: options.conversion as c_int,
+      simplify: options.simplify as c_int,
+      threads: options.threads as c_int,
+      loop_k: -1,
+      recursion_k: -1,
+      function_k: ptr::null(),
+      function_k_count: 0,
+   }
+}
+
+/// Builds a path reducer from the CFGs in `top_level` with `options`, and stores it in `out_reducer`.
+///
+/// # Safety
+///
+/// `top_level` must point to a valid `TopLevel` or `TopLevelV2`, `options` to valid options,
+/// and `out_reducer` must be valid for writes.
+/// The diagnostics callback may be called from any thread using the reducer, while the reducer is live.
+#[no_mangle]
+pub unsafe extern "C" fn get_path_reducer_with_options(
+   top_level: *const TopLevel,
+   options: *const PathReducerOptions,
+   out_reducer: *mut *const PathReducer<BlockID, FunID>,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let out_reducer = out_reducer.as_mut().ok_or(Error::NullPointer("out_reducer"))?;
+      let options = options.as_ref().ok_or(Error::NullPointer("options"))?;
+      let function_k = function_k_arg(options)?;
+      let options = ReducerOptions { function_k, ..ReducerOptions::try_from(options)? };
+      // with the names of the functions, for `function_k`
+      let reducer = PathReducer::from_program_with_options(&read_top_level(top_level)?, options)?;
+      *out_reducer = Box::into_raw(Box::new(reducer)).cast_const();
+      Ok(())
+   })
+}
+
+/// Builds a path reducer from the CFGs in `top_level` with the default options and `k`,
+/// and stores it in `out_reducer`.
+///
+/// # Safety
+///
+/// `top_level` must point to a valid `TopLevel` or `TopLevelV2`, and `out_reducer` must be valid for writes.
 #[no_mangle]
 pub unsafe extern "C" fn get_path_reducer(
    top_level: *const TopLevel,
    k: c_int,
-) -> *const PathReducer<BlockID, BlockID> {
-   let cfgs = process_top_level(top_level);
-   let reducer = PathReducer::from_cfgs(cfgs, k as usize);
-   Box::into_raw(Box::new(reducer)).cast_const()
+   out_reducer: *mut *const PathReducer<BlockID, FunID>,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let out_reducer = out_reducer.as_mut().ok_or(Error::NullPointer("out_reducer"))?;
+      let options = ReducerOptions::new(k_arg(k)?);
+      let cfgs = process_top_level(top_level)?;
+      let reducer = PathReducer::from_cfgs_with_options(cfgs, options)?;
+      *out_reducer = Box::into_raw(Box::new(reducer)).cast_const();
+      Ok(())
+   })
+}
+
+/// Builds a path reducer from the CFG file at `file_path` with the default options and `k`,
+/// and stores it in `out_reducer`.
+///
+/// # Safety
+///
+/// `file_path` must be a NUL terminated string, and `out_reducer` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn get_path_reducer_from_file(
+   file_path: *const c_char,
+   k: c_int,
+   out_reducer: *mut *const PathReducer<BlockID, FunID>,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let out_reducer = out_reducer.as_mut().ok_or(Error::NullPointer("out_reducer"))?;
+      let file_path = str_arg(file_path, "file_path")?;
+      let reducer = PathReducer::from_cfg_file_with_options(file_path, ReducerOptions::new(k_arg(k)?))?;
+      *out_reducer = Box::into_raw(Box::new(reducer)).cast_const();
+      Ok(())
+   })
+}
+
+/// Writes the CFGs in `top_level` to a CFG file at `file_path`,
+/// in the binary format if `binary` is non-zero, and in JSON otherwise.
+///
+/// # Safety
+///
+/// `top_level` must point to a valid `TopLevel` or `TopLevelV2`, and `file_path` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn dump_cfg_file(
+   top_level: *const TopLevel,
+   file_path: *const c_char,
+   binary: c_int,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let file_path = str_arg(file_path, "file_path")?;
+      read_top_level(top_level)?.save(file_path, binary != 0)
+   })
+}
+
+/// A problem found by `validate_cfgs`, see `Diagnostic`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathReductionDiagnostic {
+   /// Index of the `Diagnostic` variant
+   pub kind: c_int,
+   /// Non-zero if a reducer can't be built from the CFGs
+   pub is_error: c_int,
+   /// Function concerned, -1 if none
+   pub function: FunID,
+   /// Block concerned, -1 if none
+   pub block: BlockID,
+   /// Successor, callee, or the function first starting with `block`, -1 if none
+   pub other: c_int,
+}
+
+impl From<&Diagnostic> for PathReductionDiagnostic {
+   fn from(diagnostic: &Diagnostic) -> Self {
+      let (kind, function, block, other) = match *diagnostic {
+         Diagnostic::UnknownBlock { function, block } => (0, function, block, -1),
+         Diagnostic::DanglingSuccessor { block, successor } => (1, -1, block, successor),
+         Diagnostic::UnknownCallee { block, callee } => (2, -1, block, callee),
+         Diagnostic::SharedEntry { block, first, second } => (3, second, block, first),
+         Diagnostic::NoExit { function } => (4, function, -1, -1),
+         Diagnostic::UnreachableBlock { block } => (5, -1, block, -1),
+      };
+      PathReductionDiagnostic {
+         kind,
+         is_error: diagnostic.is_error() as c_int,
+         function,
+         block,
+         other,
+      }
+   }
+}
+
+/// Checks the CFGs in `top_level`, and stores the problems found in `out_diagnostics`,
+/// and their number in `out_len`.
+/// The diagnostics must be freed with `free_diagnostics`.
+///
+/// # Safety
+///
+/// `top_level` must point to a valid `TopLevel` or `TopLevelV2`, and `out_diagnostics` and `out_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn validate_cfgs(
+   top_level: *const TopLevel,
+   out_diagnostics: *mut *mut PathReductionDiagnostic,
+   out_len: *mut usize,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let out_diagnostics = out_diagnostics.as_mut().ok_or(Error::NullPointer("out_diagnostics"))?;
+      let out_len = out_len.as_mut().ok_or(Error::NullPointer("out_len"))?;
+      let diagnostics: Box<[_]> = read_top_level(top_level)?
+         .validate()
+         .iter()
+         .map(PathReductionDiagnostic::from)
+         .collect();
+      *out_len = diagnostics.len();
+      *out_diagnostics = Box::into_raw(diagnostics) as *mut PathReductionDiagnostic;
+      Ok(())
+   })
+}
+
+/// Frees diagnostics returned by `validate_cfgs`
+///
+/// # Safety
+///
+/// `ptr` and `len` must come from the same `validate_cfgs` call.
+#[no_mangle]
+pub unsafe extern "C" fn free_diagnostics(ptr: *mut PathReductionDiagnostic, len: usize) {
+   if !ptr.is_null() {
+      drop(Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len)));
+   }
+}
+
+/// Returns the UTF-8 string at `ptr`
+unsafe fn str_arg<'a>(ptr: *const c_char, what: &'static str) -> Result<&'a str> {
+   if ptr.is_null() {
+      return Err(Error::NullPointer(what));
+   }
+   CStr::from_ptr(ptr)
+      .to_str()
+      .map_err(|_| Error::InvalidArgument(format!("{} is not UTF-8", what)))
+}
+
+/// Saves the regular expressions of `reducer` to the file at `file_path`
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer, and `file_path` a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn save_path_reducer(
+   reducer: *const PathReducer<BlockID, FunID>,
+   file_path: *const c_char,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_ref().ok_or(Error::NullPointer("reducer"))?;
+      let file_path = str_arg(file_path, "file_path")?;
+      let file = File::create(file_path).map_err(|err| Error::Io(err.to_string()))?;
+      let mut writer = BufWriter::new(file);
+      reducer.save(&mut writer)?;
+      writer.flush().map_err(|err| Error::Io(err.to_string()))
+   })
+}
+
+/// Loads a reducer saved by `save_path_reducer` from the file at `file_path` with `options`,
+/// as `get_path_reducer_with_options`, and stores it in `out_reducer`.
+/// Fails with `StaleCache` if the reducer was not built from the CFGs in `top_level`,
+/// or with other `conversion`, `elimination` or `simplify` options.
+///
+/// # Safety
+///
+/// `file_path` must be a NUL terminated string, `top_level` must point to a valid `TopLevel` or `TopLevelV2`,
+/// `options` to valid options, and `out_reducer` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn load_path_reducer(
+   file_path: *const c_char,
+   top_level: *const TopLevel,
+   options: *const PathReducerOptions,
+   out_reducer: *mut *const PathReducer<BlockID, FunID>,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let out_reducer = out_reducer.as_mut().ok_or(Error::NullPointer("out_reducer"))?;
+      let file_path = str_arg(file_path, "file_path")?;
+      let options = options.as_ref().ok_or(Error::NullPointer("options"))?;
+      let function_k = function_k_arg(options)?;
+      let options = ReducerOptions { function_k, ..ReducerOptions::try_from(options)? };
+      let program = read_top_level(top_level)?;
+      let file = File::open(file_path).map_err(|err| Error::Io(err.to_string()))?;
+      let reducer = PathReducer::load_for_program(&mut BufReader::new(file), &program, options)?;
+      *out_reducer = Box::into_raw(Box::new(reducer)).cast_const();
+      Ok(())
+   })
 }
 
+/// Frees a path reducer returned by `get_path_reducer`
+///
+/// # Safety
+///
+/// `ptr` must be NULL or a reducer returned by `get_path_reducer` that is not freed yet.
 #[no_mangle]
 pub unsafe extern "C" fn free_path_reducer(ptr: *mut PathReducer<BlockID, FunID>) {
     if !ptr.is_null() {
@@ -24,41 +524,679 @@ pub unsafe extern "C" fn free_path_reducer(ptr: *mut PathReducer<BlockID, FunID>
     }
 }
 
+/// Frees an array returned by `reduce_path1`
+///
+/// # Safety
+///
+/// `ptr` and `len` must come from the same `reduce_path1` call.
 #[no_mangle]
-pub extern "C" fn free_boxed_array(ptr: *mut i32, len: usize) {
-    unsafe {
-        // Reconstruct the Box from the raw pointer
-        let _boxed_slice = Box::from_raw(std::slice::from_raw_parts_mut(ptr, len));
-        // Memory is freed when _boxed_slice goes out of scope
+pub unsafe extern "C" fn free_boxed_array(ptr: *mut i32, len: usize) {
+    if ptr.is_null() {
+        return;
     }
+    // Reconstruct the Box from the raw pointer
+    let _boxed_slice = Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len));
+    // Memory is freed when _boxed_slice goes out of scope
 }
 
+/// Reduces a path, and stores its hash in `out_hash`.
+///
+/// The hash is a NUL terminated string of 64 hex digits owned by the caller,
+/// and must be freed with `free_hash_string`.
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer, `path` must point to `path_size` blocks,
+/// and `out_hash` must be valid for writes.
 #[no_mangle]
 pub unsafe extern "C" fn reduce_path(
-   reducer: *const PathReducer<BlockID, BlockID>,
+   reducer: *const PathReducer<BlockID, FunID>,
+   path: *const BlockID,
+   path_size: c_int,
+   entry_fun_id: FunID,
+   out_hash: *mut *mut c_char,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_ref().ok_or(Error::NullPointer("reducer"))?;
+      let out_hash = out_hash.as_mut().ok_or(Error::NullPointer("out_hash"))?;
+      let path = path_slice(path, path_size)?;
+      // hex strings never contain NUL
+      let hash = with_reduced_path(reducer, path, entry_fun_id, |reduced_path, _| {
+         CString::new(hash_path(reduced_path)).unwrap()
+      })?;
+      *out_hash = hash.into_raw();
+      Ok(())
+   })
+}
+
+/// Frees a hash returned by `reduce_path`
+///
+/// # Safety
+///
+/// `hash` must be NULL or a hash returned by `reduce_path` that is not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn free_hash_string(hash: *mut c_char) {
+   if !hash.is_null() {
+      let _ = CString::from_raw(hash);
+   }
+}
+
+/// Selects the digest written by `reduce_path_digest` and `reduce_path_hash64`,
+/// `0` for SHA-256 and `1` for XXH3.
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer not used by other threads during the call.
+#[no_mangle]
+pub unsafe extern "C" fn path_reducer_set_digest(
+   reducer: *mut PathReducer<BlockID, FunID>,
+   kind: c_int,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_mut().ok_or(Error::NullPointer("reducer"))?;
+      reducer.set_digest_kind(DigestKind::try_from(kind)?);
+      Ok(())
+   })
+}
+
+/// Stores the size in bytes of the digests written by `reduce_path_digest` in `out_size`
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer, and `out_size` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn path_reducer_digest_size(
+   reducer: *const PathReducer<BlockID, FunID>,
+   out_size: *mut usize,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_ref().ok_or(Error::NullPointer("reducer"))?;
+      let out_size = out_size.as_mut().ok_or(Error::NullPointer("out_size"))?;
+      *out_size = reducer.digest_kind().size();
+      Ok(())
+   })
+}
+
+/// Stores the size of the regular expression of the function `fun_id` in `out_size`,
+/// to compare elimination orders
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer, and `out_size` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn path_reducer_regex_size(
+   reducer: *const PathReducer<BlockID, FunID>,
+   fun_id: FunID,
+   out_size: *mut usize,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_ref().ok_or(Error::NullPointer("reducer"))?;
+      let out_size = out_size.as_mut().ok_or(Error::NullPointer("out_size"))?;
+      let re = reducer
+         .regex(&fun_id)
+         .ok_or_else(|| Error::InvalidArgument(format!("unknown function {}", fun_id)))?;
+      *out_size = re.size();
+      Ok(())
+   })
+}
+
+/// Reduces a path, and writes the raw digest of the reduced path to `out_digest`,
+/// a buffer of `out_len` bytes. The size of the digest is stored in `out_written`.
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer, `path` must point to `path_size` blocks,
+/// `out_digest` must be valid for `out_len` bytes of writes, and `out_written` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn reduce_path_digest(
+   reducer: *const PathReducer<BlockID, FunID>,
+   path: *const BlockID,
+   path_size: c_int,
+   entry_fun_id: FunID,
+   out_digest: *mut u8,
+   out_len: usize,
+   out_written: *mut usize,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_ref().ok_or(Error::NullPointer("reducer"))?;
+      let out_written = out_written.as_mut().ok_or(Error::NullPointer("out_written"))?;
+      if out_digest.is_null() {
+         return Err(Error::NullPointer("out_digest"));
+      }
+      let out_digest = slice::from_raw_parts_mut(out_digest, out_len);
+      let path = path_slice(path, path_size)?;
+      *out_written = with_reduced_path(reducer, path, entry_fun_id, |reduced_path, _| {
+         write_digest(reduced_path, reducer.digest_kind(), out_digest)
+      })??;
+      Ok(())
+   })
+}
+
+/// Reduces a path, and stores a 64-bit digest of the reduced path in `out_hash`.
+/// With SHA-256 selected, this is the first 8 bytes of the digest.
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer, `path` must point to `path_size` blocks,
+/// and `out_hash` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn reduce_path_hash64(
+   reducer: *const PathReducer<BlockID, FunID>,
+   path: *const BlockID,
+   path_size: c_int,
+   entry_fun_id: FunID,
+   out_hash: *mut u64,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_ref().ok_or(Error::NullPointer("reducer"))?;
+      let out_hash = out_hash.as_mut().ok_or(Error::NullPointer("out_hash"))?;
+      let path = path_slice(path, path_size)?;
+      *out_hash = with_reduced_path(reducer, path, entry_fun_id, |reduced_path, _| {
+         hash64(reduced_path, reducer.digest_kind())
+      })?;
+      Ok(())
+   })
+}
+
+/// What the reduction of a path did, see `reduce_path_stats`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathReductionStats {
+   pub path_len: usize,
+   pub reduced_len: usize,
+   /// Loop iterations dropped because a loop already ran `k` times
+   pub dropped_iterations: usize,
+   /// Calls dropped because the recursion was already `k` calls deep
+   pub dropped_calls: usize,
+   /// `ErrorPolicy` applied if the path is invalid, -1 if it is valid
+   pub recovery: c_int,
+}
+
+impl From<ReductionStats> for PathReductionStats {
+   fn from(stats: ReductionStats) -> Self {
+      Self {
+         path_len: stats.path_len,
+         reduced_len: stats.reduced_len,
+         dropped_iterations: stats.dropped_iterations,
+         dropped_calls: stats.dropped_calls,
+         recovery: stats.recovery.map_or(-1, |policy| policy as c_int),
+      }
+   }
+}
+
+/// Reduces a path as `reduce_path_hash64`, and stores what the reduction did in `out_stats`.
+/// The 64-bit digest of the reduced path is stored in `out_hash` unless it is NULL.
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer, `path` must point to `path_size` blocks,
+/// `out_stats` must be valid for writes, and `out_hash` if not NULL must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn reduce_path_stats(
+   reducer: *const PathReducer<BlockID, FunID>,
    path: *const BlockID,
    path_size: c_int,
    entry_fun_id: FunID,
-) -> *const c_char {
-   let reducer = reducer.as_ref().expect("bad pointer");
-   let path = slice::from_raw_parts(path, path_size as usize);
-   let reduced_path = reducer.reduce(path, entry_fun_id);
-   let hash = hash_path(&reduced_path);
-   let c_string = CString::new(hash).unwrap();
-   c_string.as_ptr()
+   out_hash: *mut u64,
+   out_stats: *mut PathReductionStats,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_ref().ok_or(Error::NullPointer("reducer"))?;
+      let out_stats = out_stats.as_mut().ok_or(Error::NullPointer("out_stats"))?;
+      let path = path_slice(path, path_size)?;
+      let (hash, stats) = with_reduced_path(reducer, path, entry_fun_id, |reduced_path, stats| {
+         (hash64(reduced_path, reducer.digest_kind()), stats)
+      })?;
+      if let Some(out_hash) = out_hash.as_mut() {
+         *out_hash = hash;
+      }
+      *out_stats = stats.into();
+      Ok(())
+   })
 }
 
+/// Reduces a path, and stores the reduced path in `out_path`, and its length in `out_len`.
+/// The reduced path must be freed with `free_boxed_array`.
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer, `path` must point to `path_size` blocks,
+/// and `out_path` and `out_len` must be valid for writes.
 #[no_mangle]
 pub unsafe extern "C" fn reduce_path1(
-   reducer: *const PathReducer<BlockID, BlockID>,
+   reducer: *const PathReducer<BlockID, FunID>,
    path: *const BlockID,
    path_size: c_int,
    entry_fun_id: FunID,
+   out_path: *mut *mut BlockID,
+   out_len: *mut c_int,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_ref().ok_or(Error::NullPointer("reducer"))?;
+      let out_path = out_path.as_mut().ok_or(Error::NullPointer("out_path"))?;
+      let out_len = out_len.as_mut().ok_or(Error::NullPointer("out_len"))?;
+      let path = path_slice(path, path_size)?;
+      let reduced_path = reducer.reduce(path, entry_fun_id)?;
+      *out_len = reduced_path.len() as c_int;
+      *out_path = Box::into_raw(reduced_path.into_boxed_slice()) as *mut BlockID;
+      Ok(())
+   })
+}
+
+/// Reduces `count` paths in parallel on the threads of the reducer, see `PathReducerOptions::threads`.
+/// The 64-bit digest of each reduced path is stored in `out_hashes`, as `reduce_path_hash64`,
+/// and its status in `out_statuses` unless it is NULL.
+/// Returns the status of the first path that can't be reduced, whose hash is then 0.
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer, `paths` and `path_sizes` must point to `count` paths and sizes,
+/// each path must point to its size of blocks, and `out_hashes` and `out_statuses` if not NULL
+/// must be valid for `count` writes.
+#[no_mangle]
+pub unsafe extern "C" fn reduce_paths_batch(
+   reducer: *const PathReducer<BlockID, FunID>,
+   paths: *const *const BlockID,
+   path_sizes: *const c_int,
+   count: usize,
+   out_hashes: *mut u64,
+   out_statuses: *mut PathReductionStatus,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_ref().ok_or(Error::NullPointer("reducer"))?;
+      if count == 0 {
+         return Ok(());
+      }
+      if paths.is_null() {
+         return Err(Error::NullPointer("paths"));
+      }
+      if path_sizes.is_null() {
+         return Err(Error::NullPointer("path_sizes"));
+      }
+      if out_hashes.is_null() {
+         return Err(Error::NullPointer("out_hashes"));
+      }
+      let paths = slice::from_raw_parts(paths, count);
+      let path_sizes = slice::from_raw_parts(path_sizes, count);
+      let paths = paths
+         .iter()
+         .zip(path_sizes)
+         .map(|(&path, &path_size)| path_slice(path, path_size))
+         .collect::<Result<Vec<_>>>()?;
+      let out_hashes = slice::from_raw_parts_mut(out_hashes, count);
+      let mut out_statuses = (!out_statuses.is_null()).then(|| slice::from_raw_parts_mut(out_statuses, count));
+      let hashes = reducer.map_batch(&paths, 0, |reduced_path| {
+         reduced_path.map(|reduced_path| hash64(reduced_path, reducer.digest_kind()))
+      });
+      let mut first_err = None;
+      for (i, hash) in hashes.into_iter().enumerate() {
+         let status = match hash {
+            Ok(hash) => {
+               out_hashes[i] = hash;
+               PathReductionStatus::Ok
+            }
+            Err(err) => {
+               out_hashes[i] = 0;
+               let status = PathReductionStatus::from(&err);
+               first_err.get_or_insert(err);
+               status
+            }
+         };
+         if let Some(out_statuses) = &mut out_statuses {
+            out_statuses[i] = status;
+         }
+      }
+      first_err.map_or(Ok(()), Err)
+   })
+}
+
+/// Session reducing a path given in parts, see `path_reducer_begin`
+pub type PathReductionSession = ReductionSession<'static, BlockID, FunID>;
+
+/// Starts reducing a path given in parts with `path_reducer_feed`, and stores the session in `out_session`.
+/// The session must be ended with `path_reducer_finish` or `free_path_reduction_session`.
+/// Fails with `InvalidArgument` unless the reducer is in `ReductionMode::Automaton`.
+///
+/// # Safety
+///
+/// `reducer` must be a live reducer that outlives the session, and `out_session` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn path_reducer_begin(
+   reducer: *const PathReducer<BlockID, FunID>,
+   out_session: *mut *mut PathReductionSession,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let reducer = reducer.as_ref().ok_or(Error::NullPointer("reducer"))?;
+      let out_session = out_session.as_mut().ok_or(Error::NullPointer("out_session"))?;
+      *out_session = Box::into_raw(Box::new(reducer.begin()?));
+      Ok(())
+   })
+}
+
+/// Reads the next `size` blocks of the path of `session`
+///
+/// # Safety
+///
+/// `session` must be a live session not used by other threads during the call,
+/// and `blocks` must point to `size` blocks.
+#[no_mangle]
+pub unsafe extern "C" fn path_reducer_feed(
+   session: *mut PathReductionSession,
+   blocks: *const BlockID,
+   size: c_int,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      let session = session.as_mut().ok_or(Error::NullPointer("session"))?;
+      session.feed(path_slice(blocks, size)?)
+   })
+}
+
+/// Ends `session`, and stores the reduced path in `out_path`, its length in `out_len`,
+/// and its hash in `out_hash` unless it is NULL, as `reduce_path1` and `reduce_path`.
+/// The session is freed, even if the path is invalid.
+///
+/// # Safety
+///
+/// `session` must be a live session, and `out_path`, `out_len` and `out_hash` if not NULL must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn path_reducer_finish(
+   session: *mut PathReductionSession,
+   out_path: *mut *mut BlockID,
    out_len: *mut c_int,
-) -> *mut BlockID {
-   let reducer = reducer.as_ref().expect("bad pointer");
-   let path = slice::from_raw_parts(path, path_size as usize);
-   let reduced_path = reducer.reduce(path, entry_fun_id);
-   *out_len = reduced_path.len() as c_int;
-   Box::into_raw(reduced_path.into_boxed_slice()) as *mut i32
+   out_hash: *mut *mut c_char,
+) -> PathReductionStatus {
+   ffi_call(|| {
+      if session.is_null() {
+         return Err(Error::NullPointer("session"));
+      }
+      let session = Box::from_raw(session);
+      let out_path = out_path.as_mut().ok_or(Error::NullPointer("out_path"))?;
+      let out_len = out_len.as_mut().ok_or(Error::NullPointer("out_len"))?;
+      let reduced_path = session.finish()?;
+      if let Some(out_hash) = out_hash.as_mut() {
+         // hex strings never contain NUL
+         *out_hash = CString::new(hash_path(&reduced_path)).unwrap().into_raw();
+      }
+      *out_len = reduced_path.len() as c_int;
+      *out_path = Box::into_raw(reduced_path.into_boxed_slice()) as *mut BlockID;
+      Ok(())
+   })
+}
+
+/// Frees a session without reducing its path
+///
+/// # Safety
+///
+/// `session` must be NULL or a session that is not ended yet.
+#[no_mangle]
+pub unsafe extern "C" fn free_path_reduction_session(session: *mut PathReductionSession) {
+   if !session.is_null() {
+      let _ = Box::from_raw(session);
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use std::ffi::CStr;
+
+   use super::*;
+   use crate::builder::CfgBuilder;
+
+   /// Reducer of a single function `1 (2 3)* 4`
+   fn loop_reducer(options: ReducerOptions) -> *const PathReducer<BlockID, FunID> {
+      let mut builder = CfgBuilder::new();
+      builder.add_function("main", 1, 4);
+      builder.add_block(1).add_block(2).add_block(3).add_block(4);
+      builder.add_edge(1, 2).add_edge(1, 4).add_edge(2, 3).add_edge(3, 2).add_edge(3, 4);
+      let reducer = PathReducer::from_cfgs_with_options(builder.build().unwrap(), options).unwrap();
+      Box::into_raw(Box::new(reducer))
+   }
+
+   #[test]
+   fn null_top_level_is_reported() {
+      let mut reducer = ptr::null();
+      let status = unsafe { get_path_reducer(ptr::null(), 1, &mut reducer) };
+      assert_eq!(status, PathReductionStatus::NullPointer);
+      assert!(reducer.is_null());
+      let msg = unsafe { CStr::from_ptr(path_reduction_last_error()) };
+      assert_eq!(msg.to_str().unwrap(), "top_level is NULL");
+   }
+
+   #[test]
+   fn hash_outlives_the_call() {
+      let reducer = loop_reducer(ReducerOptions::new(1));
+      let long_path = [1, 2, 3, 2, 3, 2, 3, 4];
+      let short_path = [1, 2, 3, 4];
+      let mut long_hash = ptr::null_mut();
+      let mut short_hash = ptr::null_mut();
+      let long_reduced = unsafe { &*reducer }.reduce(&long_path, 0).unwrap();
+      unsafe {
+         let status = reduce_path(reducer, long_path.as_ptr(), 8, 0, &mut long_hash);
+         assert_eq!(status, PathReductionStatus::Ok);
+         let status = reduce_path(reducer, short_path.as_ptr(), 4, 0, &mut short_hash);
+         assert_eq!(status, PathReductionStatus::Ok);
+         free_path_reducer(reducer.cast_mut());
+         let long = CStr::from_ptr(long_hash).to_str().unwrap().to_string();
+         let short = CStr::from_ptr(short_hash).to_str().unwrap().to_string();
+         assert_eq!(long.len(), 64);
+         assert_eq!(long, hash_path(&long_reduced));
+         assert_eq!(short, hash_path(&short_path));
+         free_hash_string(long_hash);
+         free_hash_string(short_hash);
+      }
+   }
+
+   #[test]
+   fn raw_digests() {
+      let reducer = loop_reducer(ReducerOptions::new(1)).cast_mut();
+      let path = [1, 2, 3, 4];
+      let mut digest = [0u8; 32];
+      let mut written = 0;
+      let mut hash = 0;
+      let mut hex_hash = ptr::null_mut();
+      unsafe {
+         let status = reduce_path(reducer, path.as_ptr(), 4, 0, &mut hex_hash);
+         assert_eq!(status, PathReductionStatus::Ok);
+         let status = reduce_path_digest(reducer, path.as_ptr(), 4, 0, digest.as_mut_ptr(), 32, &mut written);
+         assert_eq!(status, PathReductionStatus::Ok);
+         assert_eq!(written, 32);
+         assert_eq!(hex::encode(digest), CStr::from_ptr(hex_hash).to_str().unwrap());
+         free_hash_string(hex_hash);
+
+         assert_eq!(path_reducer_set_digest(reducer, 1), PathReductionStatus::Ok);
+         let status = reduce_path_digest(reducer, path.as_ptr(), 4, 0, digest.as_mut_ptr(), 32, &mut written);
+         assert_eq!(status, PathReductionStatus::Ok);
+         assert_eq!(written, 8);
+         let status = reduce_path_hash64(reducer, path.as_ptr(), 4, 0, &mut hash);
+         assert_eq!(status, PathReductionStatus::Ok);
+         assert_eq!(digest[..8], hash.to_ne_bytes());
+         // the digest of the bytes of all the blocks at once
+         let bytes: Vec<u8> = path.iter().flat_map(|block: &BlockID| block.to_ne_bytes()).collect();
+         assert_eq!(hash, xxhash_rust::xxh3::xxh3_64(&bytes));
+
+         let status = reduce_path_digest(reducer, path.as_ptr(), 4, 0, digest.as_mut_ptr(), 4, &mut written);
+         assert_eq!(status, PathReductionStatus::InvalidArgument);
+         assert_eq!(path_reducer_set_digest(reducer, 7), PathReductionStatus::InvalidArgument);
+         let mut size = 0;
+         assert_eq!(path_reducer_regex_size(reducer, 0, &mut size), PathReductionStatus::Ok);
+         assert!(size > 0);
+         assert_eq!(path_reducer_regex_size(reducer, 3, &mut size), PathReductionStatus::InvalidArgument);
+         free_path_reducer(reducer);
+      }
+   }
+
+   #[test]
+   fn options_select_the_mode() {
+      let mut options = path_reducer_default_options();
+      assert_eq!(ReducerOptions::try_from(&options), Ok(ReducerOptions::default()));
+      options.mode = 1;
+      options.k = 2;
+      let expected = ReducerOptions { mode: ReductionMode::Simple, ..ReducerOptions::new(2) };
+      assert_eq!(ReducerOptions::try_from(&options), Ok(expected));
+      options.mode = 5;
+      assert!(ReducerOptions::try_from(&options).is_err());
+      options.mode = 0;
+      options.elimination = 4;
+      assert_eq!(ReducerOptions::try_from(&options).unwrap().elimination, EliminationOrder::Dominator);
+      options.elimination = 9;
+      assert!(ReducerOptions::try_from(&options).is_err());
+      options.elimination = 0;
+      options.conversion = 1;
+      assert_eq!(ReducerOptions::try_from(&options).unwrap().conversion, Conversion::Structural);
+      options.threads = -1;
+      assert!(ReducerOptions::try_from(&options).is_err());
+      options.threads = 0;
+      options.recursion_k = 3;
+      let converted = ReducerOptions::try_from(&options).unwrap();
+      assert_eq!((converted.loop_k, converted.recursion_k), (None, Some(3)));
+      let log = CString::new("log").unwrap();
+      let function_k = [
+         PathReductionFunctionK { fun_id: -1, name: log.as_ptr(), loop_k: -1, recursion_k: 0 },
+         PathReductionFunctionK { fun_id: 2, name: ptr::null(), loop_k: 4, recursion_k: -1 },
+      ];
+      options.function_k = function_k.as_ptr();
+      options.function_k_count = 2;
+      let expected = [
+         FunctionK { function: FunctionRef::Name("log".to_string()), loop_k: None, recursion_k: Some(0) },
+         FunctionK { function: FunctionRef::Id(2), loop_k: Some(4), recursion_k: None },
+      ];
+      assert_eq!(unsafe { function_k_arg(&options) }, Ok(expected.to_vec()));
+      options.function_k = ptr::null();
+      assert_eq!(unsafe { function_k_arg(&options) }, Err(Error::NullPointer("function_k")));
+      let mut reducer = ptr::null();
+      let status = unsafe { get_path_reducer_with_options(ptr::null(), ptr::null(), &mut reducer) };
+      assert_eq!(status, PathReductionStatus::NullPointer);
+   }
+
+   #[test]
+   fn diagnostics_of_dangling_successor() {
+      let diagnostic = PathReductionDiagnostic::from(&Diagnostic::DanglingSuccessor { block: 1, successor: 9 });
+      assert_eq!(
+         diagnostic,
+         PathReductionDiagnostic { kind: 1, is_error: 1, function: -1, block: 1, other: 9 }
+      );
+      let mut diagnostics = ptr::null_mut();
+      let mut len = 0;
+      let status = unsafe { validate_cfgs(ptr::null(), &mut diagnostics, &mut len) };
+      assert_eq!(status, PathReductionStatus::NullPointer);
+      assert!(diagnostics.is_null());
+   }
+
+   #[test]
+   fn null_reducer_is_reported() {
+      let path = [0];
+      let mut hash = ptr::null_mut();
+      let status = unsafe { reduce_path(ptr::null(), path.as_ptr(), 1, 0, &mut hash) };
+      assert_eq!(status, PathReductionStatus::NullPointer);
+      assert!(hash.is_null());
+   }
+
+   #[test]
+   fn sessions_have_the_hash_of_reduce_path() {
+      let reducer = loop_reducer(ReducerOptions { mode: ReductionMode::Automaton, ..ReducerOptions::new(1) });
+      let path = [1, 2, 3, 2, 3, 2, 3, 4];
+      unsafe {
+         let mut session = ptr::null_mut();
+         assert_eq!(path_reducer_begin(reducer, &mut session), PathReductionStatus::Ok);
+         assert_eq!(path_reducer_feed(session, path.as_ptr(), 3), PathReductionStatus::Ok);
+         assert_eq!(path_reducer_feed(session, path[3..].as_ptr(), 5), PathReductionStatus::Ok);
+         let (mut reduced, mut len, mut hash) = (ptr::null_mut(), 0, ptr::null_mut());
+         assert_eq!(path_reducer_finish(session, &mut reduced, &mut len, &mut hash), PathReductionStatus::Ok);
+         let batch_reduced = (*reducer).reduce(&path, 0).unwrap();
+         assert_eq!(slice::from_raw_parts(reduced, len as usize), batch_reduced);
+         let mut batch_hash = ptr::null_mut();
+         assert_eq!(reduce_path(reducer, path.as_ptr(), 8, 0, &mut batch_hash), PathReductionStatus::Ok);
+         assert_eq!(CStr::from_ptr(hash), CStr::from_ptr(batch_hash));
+         free_boxed_array(reduced, len as usize);
+         free_hash_string(hash);
+         free_hash_string(batch_hash);
+         let status = path_reducer_feed(ptr::null_mut(), path.as_ptr(), 8);
+         assert_eq!(status, PathReductionStatus::NullPointer);
+         free_path_reducer(reducer.cast_mut());
+         let regex_reducer = loop_reducer(ReducerOptions::new(1));
+         let mut session = ptr::null_mut();
+         assert_eq!(path_reducer_begin(regex_reducer, &mut session), PathReductionStatus::InvalidArgument);
+         assert!(session.is_null());
+         free_path_reducer(regex_reducer.cast_mut());
+      }
+   }
+
+   #[test]
+   fn batches_have_the_hashes_of_reduce_path_hash64() {
+      let options = ReducerOptions { threads: 2, on_error: Some(ErrorPolicy::Fail), ..ReducerOptions::new(1) };
+      let reducer = loop_reducer(options);
+      let paths: [&[BlockID]; 3] = [&[1, 2, 3, 2, 3, 2, 3, 4], &[1, 4], &[1, 3]];
+      let pointers: Vec<_> = paths.iter().map(|path| path.as_ptr()).collect();
+      let sizes: Vec<_> = paths.iter().map(|path| path.len() as c_int).collect();
+      let mut hashes = [0; 3];
+      let mut statuses = [PathReductionStatus::Ok; 3];
+      let status = unsafe {
+         reduce_paths_batch(reducer, pointers.as_ptr(), sizes.as_ptr(), 3, hashes.as_mut_ptr(), statuses.as_mut_ptr())
+      };
+      assert_eq!(status, PathReductionStatus::InvalidPath);
+      assert_eq!(statuses, [PathReductionStatus::Ok, PathReductionStatus::Ok, PathReductionStatus::InvalidPath]);
+      for (path, hash) in paths.iter().zip(hashes).take(2) {
+         let mut expected = 0;
+         let status = unsafe { reduce_path_hash64(reducer, path.as_ptr(), path.len() as c_int, 0, &mut expected) };
+         assert_eq!((status, hash), (PathReductionStatus::Ok, expected));
+      }
+      assert_eq!(hashes[2], 0);
+      unsafe { free_path_reducer(reducer.cast_mut()) };
+   }
+
+   #[test]
+   fn stats_of_reduced_paths() {
+      let reducer = loop_reducer(ReducerOptions { on_error: Some(ErrorPolicy::EmptyPath), ..ReducerOptions::new(1) });
+      let path = [1, 2, 3, 2, 3, 2, 3, 4];
+      let (mut hash, mut expected) = (0, 0);
+      let mut stats = PathReductionStats::from(ReductionStats::default());
+      let status = unsafe { reduce_path_stats(reducer, path.as_ptr(), 8, 0, &mut hash, &mut stats) };
+      unsafe { reduce_path_hash64(reducer, path.as_ptr(), 8, 0, &mut expected) };
+      assert_eq!((status, hash), (PathReductionStatus::Ok, expected));
+      let reduced = PathReductionStats { path_len: 8, reduced_len: 4, dropped_iterations: 2, dropped_calls: 0, recovery: -1 };
+      assert_eq!(stats, reduced);
+      let status = unsafe { reduce_path_stats(reducer, [1, 3].as_ptr(), 2, 0, ptr::null_mut(), &mut stats) };
+      assert_eq!(status, PathReductionStatus::Ok);
+      assert_eq!((stats.reduced_len, stats.recovery), (0, ErrorPolicy::EmptyPath as c_int));
+      let status = unsafe { reduce_path_stats(reducer, path.as_ptr(), 8, 0, &mut hash, ptr::null_mut()) };
+      assert_eq!(status, PathReductionStatus::NullPointer);
+      unsafe { free_path_reducer(reducer.cast_mut()) };
+   }
+
+   #[test]
+   fn reducers_are_shared_by_threads() {
+      let paths: Vec<Vec<BlockID>> = (0..40)
+         .map(|i| {
+            let mut path = vec![1];
+            for _ in 0..i % 7 {
+               path.extend([2, 3]);
+            }
+            path.push(4);
+            path
+         })
+         .collect();
+      for mode in [ReductionMode::Regex, ReductionMode::Automaton] {
+         let raw_reducer = loop_reducer(ReducerOptions { mode, ..ReducerOptions::new(2) });
+         let reducer = unsafe { &*raw_reducer };
+         let expected: Vec<_> = paths
+            .iter()
+            .map(|path| hash64(&reducer.reduce(path, 0).unwrap(), reducer.digest_kind()))
+            .collect();
+         std::thread::scope(|scope| {
+            for _ in 0..8 {
+               scope.spawn(|| {
+                  for _ in 0..20 {
+                     for (path, expected) in paths.iter().zip(&expected) {
+                        let mut hash = 0;
+                        let size = path.len() as c_int;
+                        let status = unsafe { reduce_path_hash64(reducer, path.as_ptr(), size, 0, &mut hash) };
+                        assert_eq!((status, hash), (PathReductionStatus::Ok, *expected));
+                     }
+                  }
+               });
+            }
+         });
+         unsafe { free_path_reducer(raw_reducer.cast_mut()) };
+      }
+   }
 }
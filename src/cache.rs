@@ -0,0 +1,424 @@
+//! Binary cache of the regular expressions computed by a `PathReducer`
+//!
+//! Layout, all integers little endian:
+//!
+//! ```text
+//! magic "PRED" | version u32 | cfg checksum [u8; 32]
+//! conversion u8 | elimination order u8 | simplify u8
+//! node count u32 | nodes ...
+//! function count u32 | (fun id i32, root node u32) ...
+//! first count u32 | (block id i32, fun id i32) ...
+//! last count u32 | (block id i32, exit count u32, (exit block id i32, next count u32, next block ids i32 ...) ...) ...
+//! ```
+//!
+//! Regular expressions share sub-expressions, so they are stored as a table of nodes
+//! where children are referred to by their index in the table.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{Read, Write},
+    sync::Arc,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    bytes::{put_i32, put_u32, Reader},
+    convert::{Conversion, EliminationOrder, Node},
+    error::{Error, Result},
+    extern_cfg::{BlockID, FunID},
+    intern_cfg::CFG,
+    re::RegExp,
+};
+
+const MAGIC: &[u8; 4] = b"PRED";
+/// Bumped when the layout changes, or the regular expressions the same options convert CFGs to
+const VERSION: u32 = 2;
+
+const EPSILON: u8 = 0;
+const VAR: u8 = 1;
+const LITERAL: u8 = 2;
+const LITERALS: u8 = 3;
+const CONCAT: u8 = 4;
+const SEQ: u8 = 5;
+const ALTER: u8 = 6;
+const STAR: u8 = 7;
+
+/// Options the regular expressions of a `PathReducer` are converted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConversionOptions {
+    pub conversion: Conversion,
+    pub elimination: EliminationOrder,
+    pub simplify: bool,
+}
+
+/// Data of a `PathReducer` read from the cache
+pub struct CachedReducer {
+    pub cfg_checksum: [u8; 32],
+    pub conversion: ConversionOptions,
+    pub res: BTreeMap<FunID, RegExp<BlockID, FunID>>,
+    pub firsts: BTreeMap<BlockID, FunID>,
+    pub lasts: BTreeMap<BlockID, BTreeMap<BlockID, BTreeSet<BlockID>>>,
+}
+
+/// Returns the checksum of the CFGs a reducer is built from
+pub fn cfg_checksum(cfgs: &BTreeMap<FunID, CFG<BlockID, FunID>>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update((cfgs.len() as u32).to_le_bytes());
+    for (fun_id, cfg) in cfgs {
+        hasher.update(fun_id.to_le_bytes());
+        hasher.update((cfg.entry.index() as u32).to_le_bytes());
+        hasher.update((cfg.exit.index() as u32).to_le_bytes());
+        hasher.update((cfg.extra_exits.len() as u32).to_le_bytes());
+        for extra_exit in &cfg.extra_exits {
+            hasher.update((extra_exit.index() as u32).to_le_bytes());
+        }
+        hasher.update((cfg.graph.node_count() as u32).to_le_bytes());
+        for node in cfg.graph.node_weights() {
+            match node {
+                Node::Literal(block_id) => {
+                    hasher.update([LITERAL]);
+                    hasher.update(block_id.to_le_bytes());
+                }
+                Node::Var(fun_id) => {
+                    hasher.update([VAR]);
+                    hasher.update(fun_id.to_le_bytes());
+                }
+                Node::Vars(fun_ids) => {
+                    hasher.update([ALTER]);
+                    hasher.update((fun_ids.len() as u32).to_le_bytes());
+                    for fun_id in fun_ids {
+                        hasher.update(fun_id.to_le_bytes());
+                    }
+                }
+                Node::Extern => hasher.update([EPSILON]),
+            }
+        }
+        let mut edges: Vec<_> = cfg
+            .graph
+            .raw_edges()
+            .iter()
+            .map(|edge| (edge.source().index() as u32, edge.target().index() as u32))
+            .collect();
+        edges.sort_unstable();
+        hasher.update((edges.len() as u32).to_le_bytes());
+        for (src, dst) in edges {
+            hasher.update(src.to_le_bytes());
+            hasher.update(dst.to_le_bytes());
+        }
+    }
+    hasher.finalize().into()
+}
+
+/// Writes the data of a `PathReducer` to `w`
+pub fn write(
+    w: &mut impl Write,
+    cfg_checksum: &[u8; 32],
+    conversion: &ConversionOptions,
+    res: &BTreeMap<FunID, RegExp<BlockID, FunID>>,
+    firsts: &BTreeMap<BlockID, FunID>,
+    lasts: &BTreeMap<BlockID, BTreeMap<BlockID, BTreeSet<BlockID>>>,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    put_u32(&mut buf, VERSION);
+    buf.extend_from_slice(cfg_checksum);
+    buf.extend_from_slice(&[conversion.conversion as u8, conversion.elimination as u8, conversion.simplify as u8]);
+
+    let mut table = NodeTable::default();
+    let roots: Vec<_> = res
+        .iter()
+        .map(|(fun_id, re)| (*fun_id, table.add_root(re)))
+        .collect();
+    put_u32(&mut buf, table.len);
+    buf.append(&mut table.buf);
+    put_u32(&mut buf, roots.len() as u32);
+    for (fun_id, root) in roots {
+        put_i32(&mut buf, fun_id);
+        put_u32(&mut buf, root);
+    }
+
+    put_u32(&mut buf, firsts.len() as u32);
+    for (block_id, fun_id) in firsts {
+        put_i32(&mut buf, *block_id);
+        put_i32(&mut buf, *fun_id);
+    }
+
+    put_u32(&mut buf, lasts.len() as u32);
+    for (block_id, exits) in lasts {
+        put_i32(&mut buf, *block_id);
+        put_u32(&mut buf, exits.len() as u32);
+        for (exit, nexts) in exits {
+            put_i32(&mut buf, *exit);
+            put_u32(&mut buf, nexts.len() as u32);
+            for next in nexts {
+                put_i32(&mut buf, *next);
+            }
+        }
+    }
+    w.write_all(&buf).map_err(|err| Error::Io(err.to_string()))
+}
+
+/// Reads a reducer written by `write` from `r`
+pub fn read(r: &mut impl Read) -> Result<CachedReducer> {
+    let mut buf = Vec::new();
+    r.read_to_end(&mut buf).map_err(|err| Error::Io(err.to_string()))?;
+    let mut reader = Reader::new(&buf, Error::InvalidCache);
+    if reader.bytes(MAGIC.len())? != MAGIC {
+        return Err(Error::InvalidCache("not a path reducer cache".to_string()));
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(Error::InvalidCache(format!(
+            "unsupported version {}, expected {}",
+            version, VERSION
+        )));
+    }
+    let cfg_checksum = reader.bytes(32)?.try_into().unwrap();
+    let invalid = |err: Error| Error::InvalidCache(err.to_string());
+    let conversion = ConversionOptions {
+        conversion: Conversion::try_from(reader.u8()? as i32).map_err(invalid)?,
+        elimination: EliminationOrder::try_from(reader.u8()? as i32).map_err(invalid)?,
+        simplify: reader.u8()? != 0,
+    };
+
+    let node_count = reader.u32()?;
+    let mut nodes: Vec<Arc<RegExp<BlockID, FunID>>> = Vec::new();
+    for _ in 0..node_count {
+        let node = read_node(&mut reader, &nodes)?;
+        nodes.push(Arc::new(node));
+    }
+    let mut res = BTreeMap::new();
+    for _ in 0..reader.u32()? {
+        let fun_id = reader.i32()?;
+        let root = read_child(&mut reader, &nodes)?;
+        res.insert(fun_id, root.as_ref().clone());
+    }
+
+    let mut firsts = BTreeMap::new();
+    for _ in 0..reader.u32()? {
+        let block_id = reader.i32()?;
+        firsts.insert(block_id, reader.i32()?);
+    }
+
+    let mut lasts = BTreeMap::new();
+    for _ in 0..reader.u32()? {
+        let block_id = reader.i32()?;
+        let mut exits = BTreeMap::new();
+        for _ in 0..reader.u32()? {
+            let exit = reader.i32()?;
+            let mut nexts = BTreeSet::new();
+            for _ in 0..reader.u32()? {
+                nexts.insert(reader.i32()?);
+            }
+            exits.insert(exit, nexts);
+        }
+        lasts.insert(block_id, exits);
+    }
+    if !reader.is_empty() {
+        return Err(Error::InvalidCache("trailing bytes".to_string()));
+    }
+    Ok(CachedReducer {
+        cfg_checksum,
+        conversion,
+        res,
+        firsts,
+        lasts,
+    })
+}
+
+/// Nodes of regular expressions, numbered in the order they are written
+#[derive(Default)]
+struct NodeTable {
+    ids: HashMap<*const RegExp<BlockID, FunID>, u32>,
+    buf: Vec<u8>,
+    len: u32,
+}
+
+impl NodeTable {
+    /// Adds `re` that is not shared, and returns its index
+    fn add_root(&mut self, re: &RegExp<BlockID, FunID>) -> u32 {
+        self.write_node(re)
+    }
+
+    /// Adds a shared node, and returns its index
+    fn add(&mut self, re: &Arc<RegExp<BlockID, FunID>>) -> u32 {
+        if let Some(&id) = self.ids.get(&Arc::as_ptr(re)) {
+            return id;
+        }
+        let id = self.write_node(re);
+        self.ids.insert(Arc::as_ptr(re), id);
+        id
+    }
+
+    /// Writes the children of `re` followed by `re` itself
+    fn write_node(&mut self, re: &RegExp<BlockID, FunID>) -> u32 {
+        let mut node = Vec::new();
+        match re {
+            RegExp::Epsilon => node.push(EPSILON),
+            RegExp::Var(fun_id) => {
+                node.push(VAR);
+                put_i32(&mut node, *fun_id);
+            }
+            RegExp::Literal(block_id) => {
+                node.push(LITERAL);
+                put_i32(&mut node, *block_id);
+            }
+            RegExp::Literals(block_ids) => {
+                node.push(LITERALS);
+                put_u32(&mut node, block_ids.len() as u32);
+                for block_id in block_ids {
+                    put_i32(&mut node, *block_id);
+                }
+            }
+            RegExp::Concat(r1, r2) | RegExp::Alter(r1, r2) => {
+                let tag = if matches!(re, RegExp::Concat(..)) { CONCAT } else { ALTER };
+                let id1 = self.add(r1);
+                let id2 = self.add(r2);
+                node.push(tag);
+                put_u32(&mut node, id1);
+                put_u32(&mut node, id2);
+            }
+            RegExp::Seq(rs) => {
+                let ids: Vec<_> = rs.iter().map(|r| self.add(r)).collect();
+                node.push(SEQ);
+                put_u32(&mut node, ids.len() as u32);
+                for id in ids {
+                    put_u32(&mut node, id);
+                }
+            }
+            RegExp::Star(r) => {
+                let id = self.add(r);
+                node.push(STAR);
+                put_u32(&mut node, id);
+            }
+        }
+        self.buf.append(&mut node);
+        self.len += 1;
+        self.len - 1
+    }
+}
+
+/// Reads a reference to a node that is already read
+fn read_child(
+    reader: &mut Reader,
+    nodes: &[Arc<RegExp<BlockID, FunID>>],
+) -> Result<Arc<RegExp<BlockID, FunID>>> {
+    let id = reader.u32()?;
+    nodes
+        .get(id as usize)
+        .cloned()
+        .ok_or_else(|| reader.invalid(format!("invalid node {}", id)))
+}
+
+fn read_node(reader: &mut Reader, nodes: &[Arc<RegExp<BlockID, FunID>>]) -> Result<RegExp<BlockID, FunID>> {
+    let node = match reader.u8()? {
+        EPSILON => RegExp::Epsilon,
+        VAR => RegExp::Var(reader.i32()?),
+        LITERAL => RegExp::Literal(reader.i32()?),
+        LITERALS => {
+            let len = reader.u32()?;
+            let block_ids = (0..len).map(|_| reader.i32()).collect::<Result<_>>()?;
+            RegExp::Literals(block_ids)
+        }
+        CONCAT => RegExp::Concat(read_child(reader, nodes)?, read_child(reader, nodes)?),
+        SEQ => {
+            let len = reader.u32()?;
+            let rs = (0..len).map(|_| read_child(reader, nodes)).collect::<Result<_>>()?;
+            RegExp::Seq(rs)
+        }
+        ALTER => RegExp::Alter(read_child(reader, nodes)?, read_child(reader, nodes)?),
+        STAR => RegExp::Star(read_child(reader, nodes)?),
+        tag => return Err(reader.invalid(format!("invalid node tag {}", tag))),
+    };
+    Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use petgraph::graph::Graph;
+
+    use super::*;
+    use crate::path_reduction::{PathReducer, ReducerOptions};
+
+    /// `f = 1 (2 3)* 4`, `g = 5 f 6`
+    fn cfgs() -> BTreeMap<FunID, CFG<BlockID, FunID>> {
+        let mut f = Graph::new();
+        let b1 = f.add_node(Node::Literal(1));
+        let b2 = f.add_node(Node::Literal(2));
+        let b3 = f.add_node(Node::Literal(3));
+        let b4 = f.add_node(Node::Literal(4));
+        f.add_edge(b1, b2, ());
+        f.add_edge(b1, b4, ());
+        f.add_edge(b2, b3, ());
+        f.add_edge(b3, b2, ());
+        f.add_edge(b3, b4, ());
+        let mut g = Graph::new();
+        let b5 = g.add_node(Node::Literal(5));
+        let call = g.add_node(Node::Var(0));
+        let b6 = g.add_node(Node::Literal(6));
+        g.add_edge(b5, call, ());
+        g.add_edge(call, b6, ());
+        BTreeMap::from([
+            (0, CFG { entry: b1, exit: b4, extra_exits: Vec::new(), graph: f }),
+            (1, CFG { entry: b5, exit: b6, extra_exits: Vec::new(), graph: g }),
+        ])
+    }
+
+    #[test]
+    fn save_and_load() {
+        let cfgs = cfgs();
+        let checksum = cfg_checksum(&cfgs);
+        let reducer = PathReducer::from_cfgs(cfgs, 1).unwrap();
+        let mut buf = Vec::new();
+        reducer.save(&mut buf).unwrap();
+        let loaded = PathReducer::load(&mut buf.as_slice(), &checksum, 1).unwrap();
+        let path = [5, 1, 2, 3, 2, 3, 2, 3, 4, 6];
+        assert_eq!(reducer.reduce(&path, 1), loaded.reduce(&path, 1));
+        assert_eq!(loaded.cfg_checksum(), &checksum);
+    }
+
+    #[test]
+    fn stale_cache() {
+        let mut cfgs = cfgs();
+        let reducer = PathReducer::from_cfgs(cfgs.clone(), 1).unwrap();
+        let mut buf = Vec::new();
+        reducer.save(&mut buf).unwrap();
+        cfgs.remove(&1);
+        let res = PathReducer::load(&mut buf.as_slice(), &cfg_checksum(&cfgs), 1);
+        assert_eq!(res.err(), Some(Error::StaleCache));
+    }
+
+    #[test]
+    fn cache_of_other_conversion_options() {
+        let cfgs = cfgs();
+        let checksum = cfg_checksum(&cfgs);
+        let options = || ReducerOptions { elimination: EliminationOrder::Dominator, simplify: true, ..ReducerOptions::new(2) };
+        let reducer = PathReducer::from_cfgs_with_options(cfgs, options()).unwrap();
+        let mut buf = Vec::new();
+        reducer.save(&mut buf).unwrap();
+        let loaded = PathReducer::load_with_options(&mut buf.as_slice(), &checksum, options()).unwrap();
+        assert_eq!(loaded.regexes(), reducer.regexes());
+        assert_eq!(loaded.bounds(&0).loops, 2);
+        let res = PathReducer::load(&mut buf.as_slice(), &checksum, 2);
+        assert_eq!(res.err(), Some(Error::StaleCache));
+        let structural = ReducerOptions { conversion: Conversion::Structural, ..options() };
+        let res = PathReducer::load_with_options(&mut buf.as_slice(), &checksum, structural);
+        assert_eq!(res.err(), Some(Error::StaleCache));
+    }
+
+    #[test]
+    fn corrupted_cache() {
+        let cfgs = cfgs();
+        let checksum = cfg_checksum(&cfgs);
+        let reducer = PathReducer::from_cfgs(cfgs, 1).unwrap();
+        let mut buf = Vec::new();
+        reducer.save(&mut buf).unwrap();
+        let truncated = &buf[..buf.len() - 1];
+        let res = PathReducer::load(&mut &truncated[..], &checksum, 1);
+        assert!(matches!(res, Err(Error::InvalidCache(_))));
+        buf[0] = b'X';
+        let res = PathReducer::load(&mut buf.as_slice(), &checksum, 1);
+        assert!(matches!(res, Err(Error::InvalidCache(_))));
+    }
+}
@@ -3,13 +3,77 @@
 use std::fmt::Debug;
 use std::sync::Arc;
 
+use crate::error::{Error, Result};
 use crate::extern_cfg::{BlockID, FunID};
 use crate::intern_cfg::CFG;
+use crate::loops::{reverse_post_order, Loop, LoopNest};
 use crate::re::RegExp;
+use petgraph::algo::dominators;
 use petgraph::graph::{Graph, NodeIndex};
-use petgraph::visit::{EdgeRef, IntoEdges};
+use petgraph::visit::EdgeRef;
 use petgraph::Direction::{Incoming, Outgoing};
-use petgraph::dot::Dot;
+
+/// Label of the transitions from the blocks where a function stops without returning to the accepting state.
+/// No block of a path has this id, so a path can end in such a block, but not go on to a block of the caller.
+pub(crate) const NO_RETURN: BlockID = BlockID::MIN;
+
+/// Algorithm converting CFGs to regular expressions
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Conversion {
+    /// State elimination in an `EliminationOrder`
+    #[default]
+    StateElimination = 0,
+    /// State elimination following the loop-nesting forest, see `GNFA::reduce_structural`
+    Structural = 1,
+}
+
+impl TryFrom<i32> for Conversion {
+    type Error = Error;
+
+    fn try_from(conversion: i32) -> Result<Self> {
+        match conversion {
+            0 => Ok(Conversion::StateElimination),
+            1 => Ok(Conversion::Structural),
+            _ => Err(Error::InvalidArgument(format!("invalid conversion {}", conversion))),
+        }
+    }
+}
+
+/// Order in which `GNFA::reduce_with` eliminates states.
+///
+/// The order doesn't change the language of the regular expression, but can change its size a lot.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EliminationOrder {
+    /// By node index
+    #[default]
+    Index = 0,
+    /// The state with the fewest incoming times outgoing transitions first
+    MinDegree = 1,
+    /// The state whose elimination adds the least to the size of the transitions first,
+    /// as estimated by Delgado and Morais' weight
+    Weight = 2,
+    /// By reverse post-order of a depth-first search from the start state
+    ReversePostOrder = 3,
+    /// The states deepest in the dominator tree first, so that inner loops are eliminated before outer ones
+    Dominator = 4,
+}
+
+impl TryFrom<i32> for EliminationOrder {
+    type Error = Error;
+
+    fn try_from(order: i32) -> Result<Self> {
+        match order {
+            0 => Ok(EliminationOrder::Index),
+            1 => Ok(EliminationOrder::MinDegree),
+            2 => Ok(EliminationOrder::Weight),
+            3 => Ok(EliminationOrder::ReversePostOrder),
+            4 => Ok(EliminationOrder::Dominator),
+            _ => Err(Error::InvalidArgument(format!("invalid elimination order {}", order))),
+        }
+    }
+}
 
 /// Generalized NFA where the transitions are `RegExp<Alphabet, Name>`
 #[derive(Debug)]
@@ -19,27 +83,69 @@ pub struct GNFA<Alphabet, Name> {
     pub the_graph: Graph<(), Arc<RegExp<Alphabet, Name>>>,
 }
 
+/// Headers of the loops of a `GNFA`, see `GNFA::loop_headers`
+struct LoopHeaders {
+    /// The loop whose header each state is, by state index
+    header_of: Vec<Option<usize>>,
+    /// The loops containing each state, by state index
+    loops_of: Vec<Vec<usize>>,
+    /// Number of states left to rip in each loop, other than its header
+    remaining: Vec<usize>,
+}
+
+impl LoopHeaders {
+    /// Whether `v` can be ripped: the header of a loop only after the other states of the loop
+    fn can_rip(&self, v: NodeIndex) -> bool {
+        self.header_of[v.index()].is_none_or(|l| self.remaining[l] == 0)
+    }
+
+    /// Records that `v` is ripped, and that the last state moved to its index
+    fn ripped(&mut self, v: NodeIndex) {
+        for &l in &self.loops_of[v.index()] {
+            if self.header_of[v.index()] != Some(l) {
+                self.remaining[l] -= 1;
+            }
+        }
+        self.header_of.swap_remove(v.index());
+        self.loops_of.swap_remove(v.index());
+    }
+}
+
+/// Node of a `CFG`.
 // In our case, Alphabet is the BlockID, Name is the FunID
 #[derive(Debug, Clone)]
 pub enum Node<Alphabet, Name> {
+    /// A block that is not a call block
     Literal(Alphabet),
+    /// A block calling the function `Name`
     Var(Name),
+    /// A block calling one of the functions, e.g. through a function pointer
+    Vars(Vec<Name>),
+    /// A block calling an external function
     Extern,
 }
 
-impl<Alphabet, Name> Node<Alphabet, Name> {
-    pub fn to_re(self) -> RegExp<Alphabet, Name> {
+impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> Node<Alphabet, Name> {
+    pub fn into_re(self) -> RegExp<Alphabet, Name> {
         match self {
             Node::Literal(char) => RegExp::Literal(char),
             Node::Var(var) => RegExp::Var(var),
+            // calls with no known callee are like external calls,
+            // the callees in the path are parsed as implicit calls
+            Node::Vars(vars) => vars
+                .into_iter()
+                .map(|var| Arc::new(RegExp::Var(var)))
+                .reduce(RegExp::alter)
+                .map_or(RegExp::Epsilon, Arc::unwrap_or_clone),
             Node::Extern => RegExp::Epsilon,
         }
     }
 
-    pub fn to_block_id(self) -> Alphabet {
+    /// Returns the block id of a literal node, and `None` for other nodes
+    pub fn into_block_id(self) -> Option<Alphabet> {
         match self {
-            Node::Literal(block_id) => block_id,
-            _ => unreachable!(),
+            Node::Literal(block_id) => Some(block_id),
+            _ => None,
         }
     }
 }
@@ -48,7 +154,6 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> GNFA<Al
     /// Construct a `GNFA` corresponding to cfg `g`.
     ///
     /// The language accepted is the set of execution paths of `g`.
-    #[allow(dead_code)]
     pub fn from_cfg<E>(g: Graph<Alphabet, E>, entry: NodeIndex, exit: NodeIndex) -> Self {
         let mut the_graph = g.map(
             |_node_id, _weight| (),
@@ -78,15 +183,83 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> GNFA<Al
 
     // Return the idx of the next state to rip.
     // Panics if there are no state left to rip.
-    fn next_to_rip(&self) -> NodeIndex {
+    fn next_to_rip(&self, headers: &LoopHeaders) -> NodeIndex {
         for v in self.the_graph.node_indices() {
-            if v != self.start_state && v != self.accepting_state {
+            if v != self.start_state && v != self.accepting_state && headers.can_rip(v) {
                 return v;
             }
         }
         unreachable!()
     }
 
+    /// Returns the states to rip, by static `order`, or `None` if `order` depends on the transitions
+    fn static_order(&self, order: EliminationOrder) -> Option<Vec<NodeIndex>> {
+        let ordered: Vec<NodeIndex> = match order {
+            EliminationOrder::Index | EliminationOrder::MinDegree | EliminationOrder::Weight => return None,
+            EliminationOrder::ReversePostOrder => reverse_post_order(&self.the_graph, self.start_state),
+            EliminationOrder::Dominator => {
+                let doms = dominators::simple_fast(&self.the_graph, self.start_state);
+                let depth = |mut v: NodeIndex| {
+                    let mut depth = 0;
+                    while v != self.start_state {
+                        // unreachable states first
+                        let Some(idom) = doms.immediate_dominator(v) else {
+                            return usize::MAX;
+                        };
+                        v = idom;
+                        depth += 1;
+                    }
+                    depth
+                };
+                let mut ordered: Vec<_> = self.the_graph.node_indices().collect();
+                ordered.sort_by_key(|&v| std::cmp::Reverse(depth(v)));
+                ordered
+            }
+        };
+        Some(self.without_start_and_end(ordered))
+    }
+
+    fn without_start_and_end(&self, states: Vec<NodeIndex>) -> Vec<NodeIndex> {
+        states
+            .into_iter()
+            .filter(|&v| v != self.start_state && v != self.accepting_state)
+            .collect()
+    }
+
+    /// Returns the state to rip with the lowest `cost`
+    fn min_cost_to_rip(&self, headers: &LoopHeaders, cost: impl Fn(NodeIndex) -> i64) -> NodeIndex {
+        self.the_graph
+            .node_indices()
+            .filter(|&v| v != self.start_state && v != self.accepting_state && headers.can_rip(v))
+            .min_by_key(|&v| cost(v))
+            .unwrap()
+    }
+
+    /// Returns the transitions to and from `v`, and the size of its self loop, if any
+    fn transitions(&self, v: NodeIndex) -> (Vec<usize>, Vec<usize>, Option<usize>) {
+        let sizes = |direction| {
+            self.the_graph
+                .edges_directed(v, direction)
+                .filter(|e| e.source() != e.target())
+                .map(|e| e.weight().size())
+                .collect::<Vec<_>>()
+        };
+        let self_loop = self.the_graph.find_edge(v, v).map(|e| self.the_graph[e].size());
+        (sizes(Incoming), sizes(Outgoing), self_loop)
+    }
+
+    fn degree_product(&self, v: NodeIndex) -> i64 {
+        let (ins, outs, _) = self.transitions(v);
+        (ins.len() * outs.len()) as i64
+    }
+
+    fn weight(&self, v: NodeIndex) -> i64 {
+        let (ins, outs, self_loop) = self.transitions(v);
+        let (n_in, n_out) = (ins.len() as i64, outs.len() as i64);
+        let sum = |sizes: Vec<usize>| sizes.into_iter().sum::<usize>() as i64;
+        sum(ins) * (n_out - 1) + sum(outs) * (n_in - 1) + self_loop.unwrap_or(0) as i64 * (n_in * n_out - 1)
+    }
+
     // add edge, if the edge already exist, add the weight to it with RegExp:Alter
     fn add_arrow(&mut self, s: NodeIndex, t: NodeIndex, arrow: Arc<RegExp<Alphabet, Name>>) {
         match self.the_graph.find_edge(s, t) {
@@ -109,8 +282,11 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> GNFA<Al
     // Rip state s_rip.
     // Panics if s is the start or end state.
     fn rip_state(&mut self, s_rip: NodeIndex) {
-        let e_rip = self.the_graph.find_edge(s_rip, s_rip);
-        let e_rip_weight = e_rip.map(|e| self.the_graph.edge_weight(e).unwrap().clone());
+        // the same `Star` is shared by all the new arrows
+        let e_rip_star = self
+            .the_graph
+            .find_edge(s_rip, s_rip)
+            .map(|e| Arc::new(RegExp::star(self.the_graph.edge_weight(e).unwrap().clone())));
         let in_edges = self
             .the_graph
             .edges_directed(s_rip, Incoming)
@@ -131,17 +307,9 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> GNFA<Al
                 }
                 let e_in = self.the_graph.edge_weight(in_edge).unwrap().clone();
                 let e_out = self.the_graph.edge_weight(out_edge).unwrap().clone();
-                let e_new = match e_rip {
-                    Some(_e_rip_id) => {
-                        // e_in(e_rip)*e_out
-                        RegExp::concat(
-                            e_in,
-                            RegExp::concat(
-                                Arc::new(RegExp::star(e_rip_weight.as_ref().unwrap().clone())),
-                                e_out,
-                            ),
-                        )
-                    }
+                let e_new = match &e_rip_star {
+                    // e_in(e_rip)*e_out
+                    Some(e_rip_star) => RegExp::concat(e_in, RegExp::concat(e_rip_star.clone(), e_out)),
                     None => RegExp::concat(e_in, e_out),
                 };
                 self.add_arrow(s_in, s_out, e_new);
@@ -161,10 +329,98 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> GNFA<Al
     /// Reduce `self` so that it ends with only 2 states.
     /// The language accepted doesn't change.
     pub fn reduce(&mut self) {
-        while self.num_states() > 2 {
-        let s_rip = self.next_to_rip();
+        self.reduce_with(EliminationOrder::Index)
+    }
+
+    /// Reduce `self` so that it ends with only 2 states, ripping them in `order`.
+    /// The language accepted doesn't change.
+    ///
+    /// Whatever the order, each loop of `self` yields one `Star`, see `GNFA::loop_headers`.
+    pub fn reduce_with(&mut self, order: EliminationOrder) {
+        let mut headers = self.loop_headers();
+        match self.static_order(order) {
+            Some(to_rip) => self.rip_in_order(to_rip, &mut headers),
+            None => {
+                while self.num_states() > 2 {
+                    let s_rip = match order {
+                        EliminationOrder::MinDegree => self.min_cost_to_rip(&headers, |v| self.degree_product(v)),
+                        EliminationOrder::Weight => self.min_cost_to_rip(&headers, |v| self.weight(v)),
+                        _ => self.next_to_rip(&headers),
+                    };
+                    self.rip_state(s_rip);
+                    headers.ripped(s_rip);
+                    // println!("after ripping {:?} {:?}", s_rip, Dot::new(&self.the_graph));
+                }
+            }
+        }
+    }
+
+    /// Returns the headers of the loops of `self`, which are ripped after the other states of their loops.
+    ///
+    /// The self loop of a header is then the only `Star` of its loop, and reads the iterations
+    /// from the header back to it, each one ending when the header is read again.
+    /// The first visit of the header is read before the `Star`, and the last iteration after it,
+    /// so that a `Star` keeping `k - 1` iterations keeps `k` iterations of its loop, in any elimination order:
+    /// the first `k - 1` ones and the last one.
+    fn loop_headers(&self) -> LoopHeaders {
+        fn add_loop(l: &Loop, outer: &mut Vec<usize>, headers: &mut LoopHeaders) {
+            let id = headers.remaining.len();
+            headers.remaining.push(0);
+            headers.header_of[l.header.index()] = Some(id);
+            outer.push(id);
+            for &v in std::iter::once(&l.header).chain(&l.body) {
+                headers.loops_of[v.index()] = outer.clone();
+            }
+            for inner in &l.inner {
+                add_loop(inner, outer, headers);
+            }
+            outer.pop();
+        }
+        let num_states = self.num_states();
+        let mut headers = LoopHeaders {
+            header_of: vec![None; num_states],
+            loops_of: vec![Vec::new(); num_states],
+            remaining: Vec::new(),
+        };
+        for l in LoopNest::new(&self.the_graph, self.start_state).loops {
+            add_loop(&l, &mut Vec::new(), &mut headers);
+        }
+        for v in self.without_start_and_end(self.the_graph.node_indices().collect()) {
+            for &l in &headers.loops_of[v.index()] {
+                if headers.header_of[v.index()] != Some(l) {
+                    headers.remaining[l] += 1;
+                }
+            }
+        }
+        headers
+    }
+
+    /// Reduce `self` so that it ends with only 2 states, ripping the states of each loop
+    /// after its inner loops, and its header last.
+    /// The language accepted doesn't change.
+    ///
+    /// As in any elimination order, the regular expression has one `Star` per loop, in the same nesting
+    /// as the loops, and keeps the same iterations, see `GNFA::loop_headers`. The expression of each loop
+    /// is built from those of its inner loops, and the other entries of an irreducible loop
+    /// are entered through their paths to the header.
+    pub fn reduce_structural(&mut self) {
+        let mut headers = self.loop_headers();
+        let to_rip = LoopNest::new(&self.the_graph, self.start_state).inner_first();
+        self.rip_in_order(self.without_start_and_end(to_rip), &mut headers)
+    }
+
+    /// Rips all the states in `to_rip`, in order, except that the headers of loops wait for their loops
+    fn rip_in_order(&mut self, mut to_rip: Vec<NodeIndex>, headers: &mut LoopHeaders) {
+        while !to_rip.is_empty() {
+            let i = to_rip.iter().position(|&v| headers.can_rip(v)).unwrap();
+            let s_rip = to_rip.remove(i);
+            // ripping moves the last state to the index of `s_rip`
+            let last = NodeIndex::new(self.num_states() - 1);
             self.rip_state(s_rip);
-            // println!("after ripping {:?} {:?}", s_rip, Dot::new(&self.the_graph));
+            headers.ripped(s_rip);
+            for v in to_rip.iter_mut().filter(|v| **v == last) {
+                *v = s_rip;
+            }
         }
     }
 
@@ -183,41 +439,51 @@ impl GNFA<BlockID, FunID> {
     /// Construct a `GNFA` corresponding to cfg `g`.
     ///
     /// The language accepted is the set of execution paths of `g`.
-    pub fn from_intern_cfg(graph: CFG<BlockID, FunID>) -> Self {
-        let CFG { entry, exit: _, graph } = graph;
+    /// If `g` may not return, the paths that don't return are prefixes of accepted paths,
+    /// which are read up to a `NO_RETURN` transition.
+    pub fn from_intern_cfg(cfg: CFG<BlockID, FunID>) -> Result<Self> {
+        let exit_nodes = cfg.exit_nodes();
+        let non_returning = cfg.non_returning_loops(&exit_nodes);
+        let CFG { entry, graph, .. } = cfg;
         let mut the_graph = graph.map(
             |_node_id, _weight| (),
             |edge_id, _weight| {
                 let dst_node_id = graph.edge_endpoints(edge_id).unwrap().1;
                 let dst_node_weight = graph.node_weight(dst_node_id).unwrap();
-                Arc::new(dst_node_weight.clone().to_re())
+                Arc::new(dst_node_weight.clone().into_re())
             },
         );
         let start_state = the_graph.add_node(());
         the_graph.add_edge(
             start_state,
             entry,
-            Arc::new(graph.node_weight(entry).unwrap().clone().to_re()),
+            Arc::new(graph.node_weight(entry).unwrap().clone().into_re()),
         );
-        let exit_nodes: Vec<_> = the_graph.node_indices().filter(|node_idx| the_graph.neighbors(*node_idx).count() == 0).collect();
-        if exit_nodes.len() > 1 {
+        // the accepting state must not have outgoing edges
+        if let ([exit_node], []) = (&exit_nodes[..], &non_returning[..]) {
+            if graph.neighbors(*exit_node).next().is_none() {
+                return Ok(Self {
+                    start_state,
+                    accepting_state: *exit_node,
+                    the_graph,
+                });
+            }
+        }
+        if !exit_nodes.is_empty() || !non_returning.is_empty() {
             let exit_node = the_graph.add_node(());
             for node in exit_nodes {
                 the_graph.add_edge(node, exit_node, Arc::new(RegExp::Epsilon));
             }
-            Self {
+            for node in non_returning {
+                the_graph.add_edge(node, exit_node, Arc::new(RegExp::Literal(NO_RETURN)));
+            }
+            Ok(Self {
                 start_state,
                 accepting_state: exit_node,
                 the_graph
-            }
-        } else if exit_nodes.len() == 0 {
-            unimplemented!("no exit node");
+            })
         } else {
-            Self {
-                start_state,
-                accepting_state: exit_nodes[0],
-                the_graph,
-            }
+            Err(Error::NoExit(format!("with entry node {:?}", graph.node_weight(entry).unwrap())))
         }
     }
 }
@@ -259,4 +525,75 @@ mod tests {
         gnfa.reduce();
         println!("{:?}", gnfa.start_to_end());
     }
+
+    #[test]
+    fn elimination_orders() {
+        // 1 -> 2 -> 3 -> 4 -> 5 with back edges 3 -> 2 and 4 -> 2
+        let mut g: Graph<usize, ()> = Graph::new();
+        let v: Vec<_> = (1..=5).map(|i| g.add_node(i)).collect();
+        for (from, to) in [(0, 1), (1, 2), (2, 3), (3, 4), (2, 1), (3, 1)] {
+            g.add_edge(v[from], v[to], ());
+        }
+        let orders = [
+            EliminationOrder::Index,
+            EliminationOrder::MinDegree,
+            EliminationOrder::Weight,
+            EliminationOrder::ReversePostOrder,
+            EliminationOrder::Dominator,
+        ];
+        for order in orders {
+            let mut gnfa: GNFA<_, ()> = GNFA::from_cfg(g.clone(), v[0], v[4]);
+            gnfa.reduce_with(order);
+            let re = gnfa.start_to_end();
+            let path = [1, 2, 3, 2, 3, 4, 2, 3, 4, 5];
+            let (parsed, rest) = re.parse_inf(&path, &Default::default()).unwrap();
+            assert!(rest.is_empty(), "{:?}", order);
+            assert_eq!(parsed.into_vec(), path);
+            assert!(re.size() > 0);
+        }
+        assert_eq!(EliminationOrder::try_from(4), Ok(EliminationOrder::Dominator));
+        assert!(EliminationOrder::try_from(5).is_err());
+    }
+
+    /// Returns the distinct `Star` nodes of `re`
+    fn stars<A, N>(re: &RegExp<A, N>, found: &mut Vec<*const RegExp<A, N>>) {
+        let children = match re {
+            RegExp::Concat(r1, r2) | RegExp::Alter(r1, r2) => vec![r1, r2],
+            RegExp::Seq(rs) => rs.iter().collect(),
+            RegExp::Star(r) => vec![r],
+            _ => vec![],
+        };
+        for child in children {
+            if matches!(child.as_ref(), RegExp::Star(_)) && !found.contains(&Arc::as_ptr(child)) {
+                found.push(Arc::as_ptr(child));
+            }
+            stars(child, found);
+        }
+    }
+
+    #[test]
+    fn structural_conversion() {
+        // 1 -> 2 -> 3 -> 4 -> 2, 3 -> 3, 4 -> 5, and 5 <-> 6 entered at both nodes, then 7
+        let mut g: Graph<usize, ()> = Graph::new();
+        let v: Vec<_> = (1..=7).map(|i| g.add_node(i)).collect();
+        for (from, to) in [(0, 1), (1, 2), (2, 3), (3, 1), (2, 2), (3, 4), (4, 5), (5, 4), (0, 5), (5, 6)] {
+            g.add_edge(v[from], v[to], ());
+        }
+        let mut gnfa: GNFA<_, ()> = GNFA::from_cfg(g, v[0], v[6]);
+        gnfa.reduce_structural();
+        let re = gnfa.start_to_end();
+        let mut found = Vec::new();
+        stars(re, &mut found);
+        assert_eq!(found.len(), 3);
+        for path in [&[1, 2, 3, 3, 4, 2, 3, 4, 5, 6, 5, 6, 7][..], &[1, 6, 5, 6, 7], &[1, 6, 7]] {
+            let (parsed, rest) = re.parse_inf(path, &Default::default()).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed.into_vec(), path);
+        }
+        // one iteration of each star keeps two iterations of each loop, the first one and the last one
+        let path = [1, 2, 3, 3, 3, 4, 2, 3, 4, 2, 3, 3, 4, 5, 6, 5, 6, 5, 6, 7];
+        let (parsed, rest) = re.parse_k(&path, &Default::default(), &Default::default(), 1).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed.into_vec(), [1, 2, 3, 3, 4, 2, 3, 3, 4, 5, 6, 5, 6, 7]);
+    }
 }
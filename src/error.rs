@@ -0,0 +1,72 @@
+//! Errors reported while building reducers and reducing paths
+
+use std::fmt;
+
+/// Errors of the path reduction library.
+///
+/// Block and function ids are stored in their printed form,
+/// so that the error type doesn't depend on the id types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A pointer that must not be NULL is NULL
+    NullPointer(&'static str),
+    /// An argument is out of range
+    InvalidArgument(String),
+    /// A block id that is not in the block table
+    UnknownBlock(String),
+    /// A function id that is not defined
+    UnknownFunction(String),
+    /// Two functions start with the same block
+    DuplicateEntry {
+        block: String,
+        first: String,
+        second: String,
+    },
+    /// The first block of a function can't be determined
+    NoFirstBlock(String),
+    /// A function has no exit block
+    NoExit(String),
+    /// No function starts with the first block of a path
+    NoEntryFunction(String),
+    /// The path is not an execution path of the CFGs
+    InvalidPath(String),
+    /// Reading or writing a file failed
+    Io(String),
+    /// A CFG file is malformed
+    InvalidCfgFile(String),
+    /// A reducer cache is corrupted or has an unsupported version
+    InvalidCache(String),
+    /// A reducer cache was built from different CFGs, or with different conversion options
+    StaleCache,
+    /// A panic caught at the C boundary
+    Panic(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NullPointer(what) => write!(f, "{} is NULL", what),
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::UnknownBlock(block) => write!(f, "invalid block id {}", block),
+            Error::UnknownFunction(fun) => write!(f, "invalid function id {}", fun),
+            Error::DuplicateEntry { block, first, second } => write!(
+                f,
+                "functions {} {} both start with block {}",
+                first, second, block
+            ),
+            Error::NoFirstBlock(fun) => write!(f, "can't find the first block of {}", fun),
+            Error::NoExit(fun) => write!(f, "function {} has no exit block", fun),
+            Error::NoEntryFunction(block) => write!(f, "no function starts with block {}", block),
+            Error::InvalidPath(msg) => write!(f, "invalid path: {}", msg),
+            Error::Io(msg) => write!(f, "io error: {}", msg),
+            Error::InvalidCfgFile(msg) => write!(f, "invalid CFG file: {}", msg),
+            Error::InvalidCache(msg) => write!(f, "invalid reducer cache: {}", msg),
+            Error::StaleCache => write!(f, "reducer cache was built from different CFGs or conversion options"),
+            Error::Panic(msg) => write!(f, "panicked: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,13 +1,14 @@
 //! Structures of external C CFGs, and utilities for converting them to internal CFGs
 
 use std::{
-    collections::{BTreeMap, BTreeSet}, iter::once_with, os::raw::{c_char, c_int}, slice
+    collections::BTreeMap, os::raw::{c_char, c_int}, slice
 };
 
-use crate::{convert::Node, intern_cfg::CFG};
-use petgraph::graph::{Graph, NodeIndex};
-use petgraph::dot::Dot;
-
+use crate::{
+    error::{Error, Result},
+    intern_cfg::CFG,
+    program::{Block, Function, Program, INDIRECT_CALL},
+};
 
 pub type FunID = c_int;
 pub type BlockID = c_int;
@@ -25,17 +26,33 @@ struct CFGEntry {
     exit: BlockID,
 }
 
+#[repr(C)]
+#[derive(Debug)]
+struct ExitEntry {
+    /// ID of the function
+    function: FunID,
+    /// Block other than the exit block where the function returns, e.g. an early return
+    block: BlockID,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct BlockEntry {
     /// If the block is a call block,
     /// then the field contains the id of the function called,
-    /// and -1 if the block is not a call block
+    /// -1 if the block is not a call block, -2 for calls to external functions,
+    /// and -3 for indirect calls to one of `callees_arr`
     calls: FunID,
     /// Number of successors
     successor_size: c_int,
     /// Successor blocks
     successors_arr: *const BlockID,
+    /// Number of possible callees of an indirect call.
+    /// This and `callees_arr` are only read if `calls` is -3,
+    /// so that other blocks can be allocated without them.
+    callee_size: c_int,
+    /// Possible callees of an indirect call, unknown if empty
+    callees_arr: *const FunID,
 }
 
 #[repr(C)]
@@ -48,135 +65,171 @@ pub struct TopLevel {
     block_arr: *const *const BlockEntry,
 }
 
-/// Requires: `top_level` is not NULL
-pub unsafe fn process_top_level(
+/// Version of `TopLevelV2`, negative so that it can't be the `cfg_size` of a `TopLevel`
+pub const TOP_LEVEL_V2: c_int = -2;
+
+/// CFGs with extra exits, passed where a `TopLevel` is expected
+#[repr(C)]
+pub struct TopLevelV2 {
+    /// `TOP_LEVEL_V2`
+    version: c_int,
+    top_level: TopLevel,
+    /// size of `extra_exit_arr`
+    extra_exit_size: c_int,
+    /// Extra exits of the functions, kept out of `CFGEntry` so that its layout is unchanged
+    extra_exit_arr: *const ExitEntry,
+}
+
+/// Reads the CFGs of all functions from `top_level`.
+///
+/// Requires: `top_level` is NULL or points to a valid `TopLevel`
+pub(crate) unsafe fn process_top_level(
     top_level: *const TopLevel,
-) -> BTreeMap<FunID, CFG<BlockID, FunID>> {
-    let top_level = top_level.as_ref().expect("top level");
-    let cfgs = slice::from_raw_parts(top_level.cfg_arr, top_level.cfg_size as usize);
-    let blocks = slice::from_raw_parts(top_level.block_arr, top_level.block_size as usize);
+) -> Result<BTreeMap<FunID, CFG<BlockID, FunID>>> {
+    read_top_level(top_level)?.to_cfgs()
+}
+
+/// Copies the CFGs in `top_level` to a `Program`.
+///
+/// Requires: `top_level` is NULL or points to a valid `TopLevel`, or to a valid `TopLevelV2`
+pub(crate) unsafe fn read_top_level(top_level: *const TopLevel) -> Result<Program> {
+    if top_level.is_null() {
+        return Err(Error::NullPointer("top_level"));
+    }
+    // both structures start with a `c_int`
+    let (top_level, extra_exits) = if *top_level.cast::<c_int>() == TOP_LEVEL_V2 {
+        let top_level_v2 = &*top_level.cast::<TopLevelV2>();
+        let extra_exits = raw_slice(top_level_v2.extra_exit_arr, top_level_v2.extra_exit_size, "extra_exit_arr")?;
+        (&top_level_v2.top_level, extra_exits)
+    } else {
+        (&*top_level, &[][..])
+    };
+    let cfgs = raw_slice(top_level.cfg_arr, top_level.cfg_size, "cfg_arr")?;
+    let blocks = raw_slice(top_level.block_arr, top_level.block_size, "block_arr")?;
+    let mut functions: Vec<Function> = cfgs
+        .iter()
+        .map(|cfg_entry| Function {
+            name: function_name(cfg_entry),
+            entry: cfg_entry.entry,
+            exit: cfg_entry.exit,
+            extra_exits: Vec::new(),
+        })
+        .collect();
+    for extra_exit in extra_exits {
+        let function = usize::try_from(extra_exit.function)
+            .ok()
+            .and_then(|fun_id| functions.get_mut(fun_id))
+            .ok_or_else(|| Error::InvalidArgument(format!("extra exit of unknown function {}", extra_exit.function)))?;
+        function.extra_exits.push(extra_exit.block);
+    }
     let mut block_id_to_entry = BTreeMap::new();
     for (i, block) in blocks.iter().enumerate() {
         if !block.is_null() {
-            let block_entry = &**block;
-            block_id_to_entry.insert(i as BlockID, block_entry);
+            // the entry is accessed through the pointer,
+            // since blocks that are not indirect calls may lack the callee fields
+            let block_entry = *block;
+            let calls = (*block_entry).calls;
+            let successors = raw_slice((*block_entry).successors_arr, (*block_entry).successor_size, "successors_arr")?;
+            let callees = if calls == INDIRECT_CALL {
+                raw_slice((*block_entry).callees_arr, (*block_entry).callee_size, "callees_arr")?.to_vec()
+            } else {
+                Vec::new()
+            };
+            block_id_to_entry.insert(
+                i as BlockID,
+                Block {
+                    calls,
+                    callees,
+                    successors: successors.to_vec(),
+                },
+            );
         }
     }
-    process_cfgs(cfgs, &block_id_to_entry)
+    Ok(Program {
+        functions,
+        blocks: block_id_to_entry,
+    })
 }
 
-fn process_cfgs(cfgs: &[CFGEntry], blocks: &BTreeMap<BlockID, &BlockEntry>) -> BTreeMap<FunID, CFG<BlockID, FunID>> {
-    cfgs.iter()
-        .enumerate()
-        .map(|(fun_id, cfg_entry)| (fun_id as FunID, process_cfg(cfg_entry, blocks)))
-        .collect()
+/// Returns the name of the function, up to the first NUL
+fn function_name(cfg: &CFGEntry) -> String {
+    let bytes: Vec<u8> = cfg
+        .function_name
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
 }
 
-/// Returns the control flow graph of the given CFGEntry
-fn process_cfg(cfg: &CFGEntry, blocks: &BTreeMap<BlockID, &BlockEntry>) -> CFG<BlockID, FunID> {
-    // println!("cfg {:?}\n blocks {:?}", cfg, blocks);
-    let entry_block_id = cfg.entry;
-    let exit_block_id = cfg.exit;
-    let res = get_cfg_with_root(entry_block_id, exit_block_id, blocks);
-    // println!("graph {:?}", Dot::new(&res.graph));
-    res
-}
-
-/// Given the block entries indexed by `BlockID`,
-/// returns the control flow graph with root `entry`
-fn get_cfg_with_root(entry: BlockID, _exit: BlockID, blocks: &BTreeMap<BlockID, &BlockEntry>) -> CFG<BlockID, FunID> {
-    let mut graph = Graph::new();
-    let mut block_id_to_node_idx = BTreeMap::new();
-    // add node to graph for each block
-    for block_id in DFS::new(blocks, entry) {
-        let block_entry = blocks.get(&block_id).expect("invalid block id");
-        let node_weight = if block_entry.calls == -1 {
-            Node::Literal(block_id)
-        } else if block_entry.calls == -2 {
-            Node::Extern
-        } else {
-            debug_assert!(
-                block_entry.successor_size >= 0,
-                "call block {} has {} successors",
-                block_id,
-                block_entry.successor_size
-            );
-            Node::Var(block_entry.calls)
-        };
-        let node_idx = graph.add_node(node_weight);
-        let no_dup = block_id_to_node_idx.insert(block_id, node_idx).is_none();
-        debug_assert!(no_dup, "duplicate block id{}", block_id);
+/// Returns the slice of `size` elements starting at `ptr`.
+///
+/// Requires: `ptr` is NULL or points to at least `size` elements
+unsafe fn raw_slice<'a, T>(ptr: *const T, size: c_int, what: &'static str) -> Result<&'a [T]> {
+    if size < 0 {
+        return Err(Error::InvalidArgument(format!("{} has negative size {}", what, size)));
     }
-    // add edges to the graph
-    for block_id in DFS::new(blocks, entry as BlockID) {
-        let node_idx = *block_id_to_node_idx.get(&block_id).unwrap();
-        for succ_block in get_successors(blocks, block_id).iter().cloned().collect::<BTreeSet<_>>().into_iter() {
-            let succ_node_idx = *block_id_to_node_idx.get(&succ_block).unwrap();
-            graph.add_edge(node_idx, succ_node_idx, ());
-        }
+    if size == 0 {
+        return Ok(&[]);
     }
-    CFG {
-        entry: *block_id_to_node_idx.get(&entry).expect("entry block idx"),
-        exit: NodeIndex::new(0),
-        graph,
+    if ptr.is_null() {
+        return Err(Error::NullPointer(what));
     }
+    Ok(slice::from_raw_parts(ptr, size as usize))
 }
 
-/// Given the block entries indexed by `BlockID`,
-/// returns the id of the successor blocks of the given block
-fn get_successors<'a>(blocks: &'a BTreeMap<BlockID, &'a BlockEntry>, block_id: BlockID) -> &'a [BlockID] {
-    let block_entry = blocks.get(&block_id).expect("invalid block id");
-    unsafe {
-        slice::from_raw_parts(
-            block_entry.successors_arr,
-            block_entry.successor_size as usize,
-        )
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
 
-/// State for DFS traversal of the CFG
-struct DFS<'a> {
-    to_visit: Vec<BlockID>,
-    visited: BTreeSet<BlockID>,
-    blocks: &'a BTreeMap<BlockID, &'a BlockEntry>,
-}
-
-impl<'a> DFS<'a> {
-    /// Traverse the CFG with root `entry`
-    fn new(blocks: &'a BTreeMap<BlockID, &BlockEntry>, entry: BlockID) -> Self {
-        Self {
-            to_visit: vec![entry],
-            visited: BTreeSet::new(),
-            blocks,
-        }
+    fn cfg_entry(entry: BlockID, exit: BlockID) -> CFGEntry {
+        CFGEntry { function_name: [0; FUN_NAME_LEN], entry, exit }
     }
 
-    /// Gets the next scheduled unvisited block
-    fn get_next_unvisited(&mut self) -> Option<BlockID> {
-        while let Some(next_scheduled) = self.to_visit.pop() {
-            if !self.visited.contains(&next_scheduled) {
-                return Some(next_scheduled);
-            }
+    fn block_entry(successors: &[BlockID]) -> BlockEntry {
+        BlockEntry {
+            calls: -1,
+            successor_size: successors.len() as c_int,
+            successors_arr: successors.as_ptr(),
+            callee_size: 0,
+            callees_arr: ptr::null(),
         }
-        None
     }
-}
 
-impl<'a> Iterator for DFS<'a> {
-    type Item = BlockID;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let next_unvisited = self.get_next_unvisited()?;
-        self.visited.insert(next_unvisited);
-        let block = self
-            .blocks
-            .get(&(next_unvisited as BlockID))
-            .expect("invalid block id");
-        for suc_block_id in
-            unsafe { slice::from_raw_parts(block.successors_arr, block.successor_size as usize) }
-        {
-            self.to_visit.push(*suc_block_id);
-        }
-        Some(next_unvisited)
+    #[test]
+    fn extra_exits_are_read_from_the_top_level_v2() {
+        assert_eq!(std::mem::size_of::<CFGEntry>(), FUN_NAME_LEN + 2 * std::mem::size_of::<BlockID>());
+        // the layout of the original `TopLevel`, two sizes and two pointers
+        assert_eq!(std::mem::size_of::<TopLevel>(), 4 * std::mem::size_of::<*const BlockEntry>());
+        let cfgs = [cfg_entry(0, 2), cfg_entry(3, 3)];
+        let (succ_0, succ_1) = ([1, 2], [2]);
+        let blocks = [block_entry(&succ_0), block_entry(&succ_1), block_entry(&[]), block_entry(&[])];
+        let block_ptrs: Vec<*const BlockEntry> = blocks.iter().map(|block| block as *const _).collect();
+        let top_level = || TopLevel {
+            cfg_size: cfgs.len() as c_int,
+            cfg_arr: cfgs.as_ptr(),
+            block_size: block_ptrs.len() as c_int,
+            block_arr: block_ptrs.as_ptr(),
+        };
+        // a `TopLevel` without the extra exits, as built against older headers
+        let program = unsafe { read_top_level(&top_level()) }.unwrap();
+        assert_eq!(program.functions.len(), 2);
+        assert!(program.functions.iter().all(|function| function.extra_exits.is_empty()));
+
+        let extra_exits = [ExitEntry { function: 0, block: 1 }];
+        let mut top_level_v2 = TopLevelV2 {
+            version: TOP_LEVEL_V2,
+            top_level: top_level(),
+            extra_exit_size: extra_exits.len() as c_int,
+            extra_exit_arr: extra_exits.as_ptr(),
+        };
+        let program = unsafe { read_top_level(ptr::from_ref(&top_level_v2).cast()) }.unwrap();
+        assert_eq!(program.functions[0].extra_exits, vec![1]);
+        assert!(program.functions[1].extra_exits.is_empty());
+
+        let unknown = [ExitEntry { function: 2, block: 1 }];
+        top_level_v2.extra_exit_arr = unknown.as_ptr();
+        assert!(unsafe { read_top_level(ptr::from_ref(&top_level_v2).cast()) }.is_err());
     }
 }
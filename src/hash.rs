@@ -1,12 +1,91 @@
 use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::xxh3_64;
 
-use crate::extern_cfg::BlockID;
+use crate::{
+    error::{Error, Result},
+    extern_cfg::BlockID,
+};
 
+/// Digest used to identify reduced paths
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DigestKind {
+    /// 32 bytes SHA-256 digest
+    #[default]
+    Sha256 = 0,
+    /// 8 bytes non-cryptographic XXH3 digest
+    Xxh3 = 1,
+}
+
+impl DigestKind {
+    /// Size of the digest in bytes
+    pub fn size(self) -> usize {
+        match self {
+            DigestKind::Sha256 => 32,
+            DigestKind::Xxh3 => 8,
+        }
+    }
+}
+
+impl TryFrom<i32> for DigestKind {
+    type Error = Error;
+
+    fn try_from(kind: i32) -> Result<Self> {
+        match kind {
+            0 => Ok(DigestKind::Sha256),
+            1 => Ok(DigestKind::Xxh3),
+            _ => Err(Error::InvalidArgument(format!("invalid digest kind {}", kind))),
+        }
+    }
+}
+
+/// Returns the hex encoded SHA-256 digest of `path`
 pub fn hash_path(path: &[BlockID]) -> String {
+    hex::encode(sha256_path(path))
+}
+
+/// Returns the SHA-256 digest of `path`
+pub fn sha256_path(path: &[BlockID]) -> [u8; 32] {
     let mut hasher = Sha256::new();
     for &value in path {
         hasher.update(value.to_ne_bytes());
     }
-    let result = hasher.finalize();
-    hex::encode(result)
+    hasher.finalize().into()
+}
+
+/// Returns the XXH3 digest of `path`, hashed at once as the bytes of its blocks in native byte order
+pub fn xxh3_path(path: &[BlockID]) -> u64 {
+    // block ids are integers without padding, so their bytes can be read as such
+    let bytes = unsafe { std::slice::from_raw_parts(path.as_ptr().cast::<u8>(), std::mem::size_of_val(path)) };
+    xxh3_64(bytes)
+}
+
+/// Writes the digest of `path` to the start of `out`, and returns the size of the digest.
+/// 64-bit digests are written in native byte order.
+pub fn write_digest(path: &[BlockID], kind: DigestKind, out: &mut [u8]) -> Result<usize> {
+    let size = kind.size();
+    if out.len() < size {
+        return Err(Error::InvalidArgument(format!(
+            "digest buffer of {} bytes is smaller than {} bytes",
+            out.len(),
+            size
+        )));
+    }
+    match kind {
+        DigestKind::Sha256 => out[..size].copy_from_slice(&sha256_path(path)),
+        DigestKind::Xxh3 => out[..size].copy_from_slice(&xxh3_path(path).to_ne_bytes()),
+    }
+    Ok(size)
+}
+
+/// Returns a 64-bit digest of `path`.
+/// For SHA-256, this is the first 8 bytes of the digest in native byte order.
+pub fn hash64(path: &[BlockID], kind: DigestKind) -> u64 {
+    match kind {
+        DigestKind::Sha256 => {
+            let digest = sha256_path(path);
+            u64::from_ne_bytes(digest[..8].try_into().unwrap())
+        }
+        DigestKind::Xxh3 => xxh3_path(path),
+    }
 }
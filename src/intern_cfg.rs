@@ -2,12 +2,58 @@
 
 use crate::convert::Node;
 
-use petgraph::graph::{Graph, NodeIndex};
+use petgraph::{
+    algo::tarjan_scc,
+    graph::{Graph, NodeIndex},
+};
 
 /// Control flow graph of a single function
 #[derive(Debug, Clone)]
 pub struct CFG<BlockID, FunID> {
+    /// Node of the entry block
     pub entry: NodeIndex,
+    /// Node of the exit block, `NodeIndex::end()` if it is not in the graph
     pub exit: NodeIndex,
+    /// Nodes of other blocks where the function returns, e.g. early returns
+    pub extra_exits: Vec<NodeIndex>,
     pub graph: Graph<Node<BlockID, FunID>, ()>,
 }
+
+impl<BlockID, FunID> CFG<BlockID, FunID> {
+    /// Returns the nodes where the function returns, the exit and the extra exits in the graph.
+    ///
+    /// Other blocks without successors don't return, e.g. calls to `abort`.
+    pub fn exit_nodes(&self) -> Vec<NodeIndex> {
+        let mut exits = Vec::new();
+        for &node_idx in std::iter::once(&self.exit).chain(&self.extra_exits) {
+            if self.graph.node_weight(node_idx).is_some() && !exits.contains(&node_idx) {
+                exits.push(node_idx);
+            }
+        }
+        exits
+    }
+
+    /// Returns a node of each loop that the function can neither leave nor return from,
+    /// e.g. the main loop of a server, and of each block without successors that isn't an exit.
+    ///
+    /// Any path entering such a loop is a prefix of a path ending at its node,
+    /// so these nodes model the paths of a function that doesn't return, if no block can follow them.
+    pub fn non_returning_loops(&self, exits: &[NodeIndex]) -> Vec<NodeIndex> {
+        let sccs = tarjan_scc(&self.graph);
+        let mut scc_of = vec![0; self.graph.node_count()];
+        for (i, scc) in sccs.iter().enumerate() {
+            for node_idx in scc {
+                scc_of[node_idx.index()] = i;
+            }
+        }
+        sccs.iter()
+            .enumerate()
+            .filter(|(i, scc)| {
+                // no edge leaves the loop
+                scc.iter().all(|&node_idx| self.graph.neighbors(node_idx).all(|succ| scc_of[succ.index()] == *i))
+                    && !scc.iter().any(|node_idx| exits.contains(node_idx))
+            })
+            .map(|(_, scc)| scc[0])
+            .collect()
+    }
+}
@@ -1,7 +1,57 @@
+//! Regular expression based path reduction.
+//!
+//! The CFG of every function is converted to a regular expression over its blocks,
+//! where calls are variables referring to the regular expressions of the callees.
+//! An execution path is reduced by parsing it with these regular expressions,
+//! keeping at most `k` iterations of each loop.
+//!
+//! The crate is usable both as a C library, see [`c_api`], and as a Rust library:
+//!
+//! ```
+//! use path_reduction::{PathReducer, Program};
+//!
+//! let program = Program::from_json(br#"{
+//!     "functions": [{ "name": "main", "entry": 1, "exit": 4 }],
+//!     "blocks": {
+//!         "1": { "successors": [2, 4] },
+//!         "2": { "successors": [3] },
+//!         "3": { "successors": [2, 4] },
+//!         "4": {}
+//!     }
+//! }"#)?;
+//! let reducer = PathReducer::from_program(&program, 1)?;
+//! assert_eq!(reducer.reduce(&[1, 2, 3, 2, 3, 2, 3, 4], 0)?, reducer.reduce(&[1, 2, 3, 2, 3, 4], 0)?);
+//! println!("{:?}", reducer.regex(&0));
+//! # Ok::<(), path_reduction::Error>(())
+//! ```
+#![allow(clippy::upper_case_acronyms)]
 pub mod c_api;
-mod convert;
-mod extern_cfg;
-mod hash;
-mod intern_cfg;
-mod path_reduction;
-mod re;
+mod automaton;
+pub mod builder;
+mod bytes;
+mod cache;
+pub mod convert;
+pub mod error;
+pub mod extern_cfg;
+pub mod hash;
+pub mod intern_cfg;
+mod loops;
+pub mod path_reduction;
+pub mod program;
+pub mod re;
+mod simplify;
+pub mod validate;
+
+pub use builder::CfgBuilder;
+pub use convert::{Conversion, EliminationOrder};
+pub use error::{Error, Result};
+pub use extern_cfg::{BlockID, FunID};
+pub use hash::DigestKind;
+pub use intern_cfg::CFG;
+pub use path_reduction::{
+    DiagnosticSink, ErrorPolicy, FunctionK, FunctionRef, PathReducer, ReducerOptions, ReductionMode, ReductionSession,
+    ReductionStats,
+};
+pub use program::Program;
+pub use re::RegExp;
+pub use validate::Diagnostic;
@@ -0,0 +1,120 @@
+//! Loop-nesting forests of control flow graphs
+//!
+//! The loops of a graph are its strongly connected components. The header of a loop is its first node
+//! in reverse post-order, which for a natural loop is the node dominating the loop.
+//! The inner loops are the strongly connected components of the loop without its header,
+//! so that irreducible loops, entered at several nodes, are nested in the same way.
+
+use petgraph::{
+    algo::tarjan_scc,
+    graph::{Graph, NodeIndex},
+    visit::{DfsPostOrder, NodeFiltered},
+};
+
+/// A loop of a loop-nesting forest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Loop {
+    pub header: NodeIndex,
+    /// Nodes of the loop outside its inner loops, except the header
+    pub body: Vec<NodeIndex>,
+    pub inner: Vec<Loop>,
+}
+
+/// Loop-nesting forest of a graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LoopNest {
+    pub loops: Vec<Loop>,
+    /// Nodes outside every loop
+    pub outside: Vec<NodeIndex>,
+}
+
+impl LoopNest {
+    /// Returns the loop-nesting forest of `graph`, whose nodes are ordered from `start`
+    pub fn new<N, E>(graph: &Graph<N, E>, start: NodeIndex) -> Self {
+        let order = reverse_post_order(graph, start);
+        let mut rank = vec![0; graph.node_count()];
+        for (i, node) in order.iter().enumerate() {
+            rank[node.index()] = i;
+        }
+        let (loops, outside) = loops_of(graph, &rank, &order);
+        Self { loops, outside }
+    }
+
+    /// Returns the nodes with inner loops first, then the other nodes of each loop, and its header last
+    pub fn inner_first(&self) -> Vec<NodeIndex> {
+        fn push_loop(l: &Loop, nodes: &mut Vec<NodeIndex>) {
+            for inner in &l.inner {
+                push_loop(inner, nodes);
+            }
+            nodes.extend(&l.body);
+            nodes.push(l.header);
+        }
+        let mut nodes = Vec::new();
+        for l in &self.loops {
+            push_loop(l, &mut nodes);
+        }
+        nodes.extend(&self.outside);
+        nodes
+    }
+}
+
+/// Returns the nodes of `graph` in reverse post-order of a depth-first search from `start`,
+/// after the nodes unreachable from `start`
+pub(crate) fn reverse_post_order<N, E>(graph: &Graph<N, E>, start: NodeIndex) -> Vec<NodeIndex> {
+    let mut dfs = DfsPostOrder::new(graph, start);
+    let mut post_order = Vec::new();
+    while let Some(node) = dfs.next(graph) {
+        post_order.push(node);
+    }
+    let mut order: Vec<_> = graph.node_indices().filter(|node| !dfs.discovered[node.index()]).collect();
+    order.extend(post_order.into_iter().rev());
+    order
+}
+
+/// Returns the loops of the subgraph of `nodes` and the nodes outside them, ordered by `rank`
+fn loops_of<N, E>(graph: &Graph<N, E>, rank: &[usize], nodes: &[NodeIndex]) -> (Vec<Loop>, Vec<NodeIndex>) {
+    let mut in_subgraph = vec![false; graph.node_count()];
+    for node in nodes {
+        in_subgraph[node.index()] = true;
+    }
+    let subgraph = NodeFiltered::from_fn(graph, |node: NodeIndex| in_subgraph[node.index()]);
+    let mut loops = Vec::new();
+    let mut outside = Vec::new();
+    for mut scc in tarjan_scc(&subgraph) {
+        if scc.len() == 1 && graph.find_edge(scc[0], scc[0]).is_none() {
+            outside.push(scc[0]);
+            continue;
+        }
+        scc.sort_by_key(|node| rank[node.index()]);
+        let header = scc.remove(0);
+        let (inner, body) = loops_of(graph, rank, &scc);
+        loops.push(Loop { header, body, inner });
+    }
+    loops.sort_by_key(|l| rank[l.header.index()]);
+    outside.sort_by_key(|node| rank[node.index()]);
+    (loops, outside)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_and_irreducible_loops() {
+        // 0 -> 1 -> 2 -> 3 -> 1, 2 -> 2, 3 -> 4, and 4 <-> 5 entered at both nodes from 0
+        let mut g: Graph<(), ()> = Graph::new();
+        let v: Vec<_> = (0..6).map(|_| g.add_node(())).collect();
+        for (from, to) in [(0, 1), (1, 2), (2, 3), (3, 1), (2, 2), (3, 4), (4, 5), (5, 4), (0, 5)] {
+            g.add_edge(v[from], v[to], ());
+        }
+        let nest = LoopNest::new(&g, v[0]);
+        assert_eq!(nest.outside, [v[0]]);
+        assert_eq!(nest.loops.len(), 2);
+        let outer = &nest.loops[0];
+        assert_eq!((outer.header, &outer.body[..]), (v[1], &[v[3]][..]));
+        assert_eq!(outer.inner, [Loop { header: v[2], body: vec![], inner: vec![] }]);
+        let irreducible = &nest.loops[1];
+        assert_eq!(irreducible.body.len() + 1, 2);
+        assert_eq!(nest.inner_first(), [v[2], v[3], v[1], irreducible.body[0], irreducible.header, v[0]]);
+    }
+}
@@ -1,122 +1,628 @@
-use std::{collections::{BTreeMap, BTreeSet}, env, fmt::Debug};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::{self, Debug},
+    io::{Read, Write},
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use petgraph::graph::NodeIndex;
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 
 use crate::{
-    convert::GNFA,
+    automaton::{Automata, Parser},
+    cache::{self, cfg_checksum, CachedReducer, ConversionOptions},
+    convert::{Conversion, EliminationOrder, Node, GNFA},
+    error::{Error, Result},
     extern_cfg::{BlockID, FunID},
+    hash::DigestKind,
     intern_cfg::CFG,
-    re::{RegExp, ParseErr},
+    program::Program,
+    re::{Bounds, Dropped, FunctionBounds, RegExp, ParseErr},
 };
 
-const PATH_REDUCTION_DEBUG: &'static str = "PATH_REDUCTION_DEBUG";
-const PATH_REDUCTION_ON_ERROR: &'static str = "PATH_REDUCTION_ON_ERROR";
-const FULL_PATH : &'static str = "FULL_PATH";
-const EMPTY_PATH : &'static str = "EMPTY_PATH";
+const PATH_REDUCTION_DEBUG: &str = "PATH_REDUCTION_DEBUG";
+const PATH_REDUCTION_ON_ERROR: &str = "PATH_REDUCTION_ON_ERROR";
+const FULL_PATH : &str = "FULL_PATH";
+const EMPTY_PATH : &str = "EMPTY_PATH";
+const LONGEST_VALID_PREFIX : &str = "LONGEST_VALID_PREFIX";
+const RESYNC : &str = "RESYNC";
+const FAIL : &str = "FAIL";
+
+/// Algorithm used to reduce paths
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReductionMode {
+    /// Parses paths with the regular expressions of the CFGs
+    #[default]
+    Regex = 0,
+    /// Drops repeated loop iterations with a stack of the blocks of each call,
+    /// without the regular expressions.
+    /// A block repeated in the iterations of an enclosing loop counts as an iteration of its loop.
+    Simple = 1,
+    /// Parses paths in a single pass with position automata compiled from the regular expressions,
+    /// with the same result as `Regex` on paths that can only be parsed in one way
+    Automaton = 2,
+}
+
+impl TryFrom<i32> for ReductionMode {
+    type Error = Error;
+
+    fn try_from(mode: i32) -> Result<Self> {
+        match mode {
+            0 => Ok(ReductionMode::Regex),
+            1 => Ok(ReductionMode::Simple),
+            2 => Ok(ReductionMode::Automaton),
+            _ => Err(Error::InvalidArgument(format!("invalid reduction mode {}", mode))),
+        }
+    }
+}
+
+/// Result of reducing a path that is not an execution path of the CFGs
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Fails with `Error::InvalidPath`
+    #[default]
+    Fail = 0,
+    /// The unreduced path
+    FullPath = 1,
+    /// The empty path
+    EmptyPath = 2,
+    /// The reduction of the longest prefix of the path that starts an execution path
+    LongestValidPrefix = 3,
+    /// The reduction of the longest valid prefix, followed by the reduction of the rest of the path
+    /// from the next block starting a function, recovering from further invalid blocks in the same way
+    Resync = 4,
+}
+
+impl TryFrom<i32> for ErrorPolicy {
+    type Error = Error;
+
+    fn try_from(policy: i32) -> Result<Self> {
+        match policy {
+            0 => Ok(ErrorPolicy::Fail),
+            1 => Ok(ErrorPolicy::FullPath),
+            2 => Ok(ErrorPolicy::EmptyPath),
+            3 => Ok(ErrorPolicy::LongestValidPrefix),
+            4 => Ok(ErrorPolicy::Resync),
+            _ => Err(Error::InvalidArgument(format!("invalid error policy {}", policy))),
+        }
+    }
+}
+
+impl ErrorPolicy {
+    /// Reads the policy from `PATH_REDUCTION_ON_ERROR`, `Fail` if it is not set
+    fn from_env() -> Result<Self> {
+        let Ok(on_error) = env::var(PATH_REDUCTION_ON_ERROR) else {
+            return Ok(ErrorPolicy::Fail);
+        };
+        match on_error.as_str() {
+            FAIL => Ok(ErrorPolicy::Fail),
+            FULL_PATH => Ok(ErrorPolicy::FullPath),
+            EMPTY_PATH => Ok(ErrorPolicy::EmptyPath),
+            LONGEST_VALID_PREFIX => Ok(ErrorPolicy::LongestValidPrefix),
+            RESYNC => Ok(ErrorPolicy::Resync),
+            _ => Err(Error::InvalidArgument(format!(
+                "invalid value for {}: {}",
+                PATH_REDUCTION_ON_ERROR, on_error
+            ))),
+        }
+    }
+}
+
+/// What the reduction of a path did, see `PathReducer::reduce_with_stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReductionStats {
+    /// Length of the path
+    pub path_len: usize,
+    /// Length of the reduced path
+    pub reduced_len: usize,
+    /// Loop iterations dropped because a loop already ran `k` times
+    pub dropped_iterations: usize,
+    /// Calls dropped because the recursion was already `k` calls deep
+    pub dropped_calls: usize,
+    /// The policy applied if the path is not an execution path of the CFGs
+    pub recovery: Option<ErrorPolicy>,
+}
+
+impl ReductionStats {
+    /// Number of blocks removed by the reduction, 0 if the reduced path is longer,
+    /// e.g. with `ErrorPolicy::Resync`
+    pub fn removed_blocks(&self) -> usize {
+        self.path_len.saturating_sub(self.reduced_len)
+    }
+}
+
+/// Receives the invalid paths met by a reducer, as `Error::InvalidPath`
+#[derive(Clone)]
+pub struct DiagnosticSink(Arc<dyn Fn(&Error) + Send + Sync>);
+
+impl DiagnosticSink {
+    pub fn new(f: impl Fn(&Error) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub fn report(&self, err: &Error) {
+        (self.0)(err)
+    }
+}
+
+impl Debug for DiagnosticSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DiagnosticSink")
+    }
+}
+
+impl PartialEq for DiagnosticSink {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for DiagnosticSink {}
+
+/// A function, by id or by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FunctionRef {
+    Id(FunID),
+    /// Every function with this name
+    Name(String),
+}
+
+/// Bounds of a function overriding those of `ReducerOptions`,
+/// e.g. to keep more of a parser and less of a logging helper
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionK {
+    pub function: FunctionRef,
+    /// Bound on the iterations of each loop of the function, the bound of the reducer if `None`.
+    /// It must be positive.
+    pub loop_k: Option<usize>,
+    /// Bound on the depth of the recursive calls of the function, the bound of the reducer if `None`.
+    /// With 0, the calls of the function are dropped.
+    pub recursion_k: Option<usize>,
+}
+
+/// Options of a `PathReducer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReducerOptions {
+    /// Bound on the iterations of each loop, and on the depth of recursive calls,
+    /// kept in a reduced path, unless set apart below. Loop bounds must be positive.
+    pub k: usize,
+    /// Bound on the iterations of each loop, `k` if `None`
+    pub loop_k: Option<usize>,
+    /// Bound on the depth of recursive calls, `k` if `None`
+    pub recursion_k: Option<usize>,
+    /// Bounds of some functions, a later entry overriding an earlier one for the same function.
+    /// Functions can only be named if the reducer is built from a `Program` or a CFG file.
+    pub function_k: Vec<FunctionK>,
+    pub mode: ReductionMode,
+    pub digest: DigestKind,
+    /// Policy for invalid paths.
+    /// If `None`, it is read from `PATH_REDUCTION_ON_ERROR` when the reducer is built.
+    pub on_error: Option<ErrorPolicy>,
+    /// Sink of invalid paths.
+    /// If `None` and `PATH_REDUCTION_DEBUG` is set when the reducer is built, they are printed.
+    pub diagnostics: Option<DiagnosticSink>,
+    /// Algorithm converting the CFGs.
+    /// A reducer loaded from a cache keeps the regular expressions it was saved with.
+    pub conversion: Conversion,
+    /// Order of the states eliminated by `Conversion::StateElimination`
+    pub elimination: EliminationOrder,
+    /// Whether the converted regular expressions are simplified, see `RegExp::simplify`
+    pub simplify: bool,
+    /// Number of threads converting the CFGs and reducing the paths of `PathReducer::reduce_batch`,
+    /// 0 for one per core
+    pub threads: usize,
+}
+
+impl ReducerOptions {
+    /// Options reducing paths with `k` in the default mode
+    pub fn new(k: usize) -> Self {
+        Self { k, ..Self::default() }
+    }
+}
+
+impl Default for ReducerOptions {
+    fn default() -> Self {
+        Self {
+            k: 1,
+            loop_k: None,
+            recursion_k: None,
+            function_k: Vec::new(),
+            mode: ReductionMode::default(),
+            digest: DigestKind::default(),
+            on_error: None,
+            diagnostics: None,
+            conversion: Conversion::default(),
+            elimination: EliminationOrder::default(),
+            simplify: false,
+            threads: 0,
+        }
+    }
+}
+
+impl ReducerOptions {
+    /// Fills the options left to the environment
+    fn with_env_defaults(mut self) -> Result<Self> {
+        if self.on_error.is_none() {
+            self.on_error = Some(ErrorPolicy::from_env()?);
+        }
+        if self.diagnostics.is_none() && env::var_os(PATH_REDUCTION_DEBUG).is_some() {
+            self.diagnostics = Some(DiagnosticSink::new(|err| println!("{}", err)));
+        }
+        Ok(self)
+    }
+
+    /// Returns the options the regular expressions are converted with
+    fn conversion_options(&self) -> ConversionOptions {
+        ConversionOptions { conversion: self.conversion, elimination: self.elimination, simplify: self.simplify }
+    }
+
+    /// Returns the bounds of each function, given the name of each function, empty if unknown
+    fn bounds(&self, functions: &BTreeMap<FunID, &str>) -> Result<FunctionBounds<FunID>> {
+        let default = Bounds { loops: self.loop_k.unwrap_or(self.k), recursion: self.recursion_k.unwrap_or(self.k) };
+        let mut bounds = FunctionBounds { default, functions: BTreeMap::new() };
+        for function_k in &self.function_k {
+            let fun_ids: Vec<FunID> = match &function_k.function {
+                FunctionRef::Id(fun_id) => functions.keys().filter(|id| *id == fun_id).copied().collect(),
+                FunctionRef::Name(name) => functions
+                    .iter()
+                    .filter(|(_, fun_name)| !name.is_empty() && *fun_name == name)
+                    .map(|(fun_id, _)| *fun_id)
+                    .collect(),
+            };
+            if fun_ids.is_empty() {
+                return Err(Error::InvalidArgument(format!("unknown function {:?}", function_k.function)));
+            }
+            for fun_id in fun_ids {
+                let fun_bounds = bounds.functions.entry(fun_id).or_insert(default);
+                fun_bounds.loops = function_k.loop_k.unwrap_or(fun_bounds.loops);
+                fun_bounds.recursion = function_k.recursion_k.unwrap_or(fun_bounds.recursion);
+            }
+        }
+        if let Some(fun_bounds) = std::iter::once(&bounds.default).chain(bounds.functions.values()).find(|b| b.loops == 0) {
+            return Err(Error::InvalidArgument(format!("no loop iteration kept with {:?}", fun_bounds)));
+        }
+        Ok(bounds)
+    }
+}
 
+/// Returns the bounds of the parsers given those of the options.
+///
+/// The header of each loop is ripped after the rest of the loop, see `GNFA::loop_headers`,
+/// so that the first visit of the header is read before the `Star` of the loop and its last iteration after it:
+/// the star keeps one iteration less than the loop.
+fn star_bounds<FunID: Ord + Clone>(bounds: &FunctionBounds<FunID>) -> FunctionBounds<FunID> {
+    let star = |bounds: Bounds| Bounds { loops: bounds.loops - 1, ..bounds };
+    FunctionBounds {
+        default: star(bounds.default),
+        functions: bounds.functions.iter().map(|(fun_id, &fun_bounds)| (fun_id.clone(), star(fun_bounds))).collect(),
+    }
+}
+
+/// Reduces execution paths with the regular expressions of the CFGs of a program.
+///
+/// Each loop keeps at most `k` iterations in a reduced path, see `ReducerOptions`.
+/// The environment variables read by the reducer are only read when it is built.
+///
+/// A reducer is `Send` and `Sync`: any number of threads can reduce paths with it at once.
+/// Reducing a path only reads the reducer, and the memory of each reduction is local to the call,
+/// except the automata, which are built once by the first thread needing them.
 pub struct PathReducer<BlockID, FunID> {
     res: BTreeMap<FunID, RegExp<BlockID, FunID>>,
     firsts: BTreeMap<BlockID, FunID>,
-    lasts: BTreeMap<BlockID, BTreeSet<BlockID>>,
-    k: usize,
+    /// Exits of each function by its first block, with the blocks that can follow them in the function
+    lasts: BTreeMap<BlockID, BTreeMap<BlockID, BTreeSet<BlockID>>>,
+    /// Automata of the regular expressions, built when first used
+    automata: OnceLock<Automata<BlockID, FunID>>,
+    /// Pool of `ReducerOptions::threads` threads, `None` for the global pool
+    pool: Option<ThreadPool>,
+    options: ReducerOptions,
+    /// Bounds of each function, from the options
+    bounds: FunctionBounds<FunID>,
+    /// Bounds of the parsers, see `star_bounds`
+    star_bounds: FunctionBounds<FunID>,
+    /// Checksum of the CFGs the reducer is built from
+    cfg_checksum: [u8; 32],
 }
 
+// the C API shares reducers between threads, and sessions may be fed from any thread
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<PathReducer<BlockID, FunID>>();
+    assert_send_sync::<ReductionSession<'static, BlockID, FunID>>();
+};
+
 impl<BlockID: Eq + Clone + Ord+ Debug, FunID: Eq + Clone + Ord + Debug> PathReducer<BlockID, FunID> {
-    pub fn reduce(&self, mut path: &[BlockID], _cfg: FunID) -> Vec<BlockID> {
-        if self.k == 42 {
-            // println!("reducing path {:?}", path);
-            let reduced = self.simple_reduce(&mut path);
-            // println!("reduced path {:?}", reduced);
-            return reduced;
-        }
-        let unreduced = path;
-        if path.is_empty() {
-            return Vec::new();
-        }
-        let cfg = self.firsts.get(&path[0]).expect(&format!("no fun starts with {:?}", path[0]));
-        // let re = self.res.get(&cfg).expect("invalid fun_id");
-        let re = RegExp::Var(cfg.clone());
-        let mut reduced_paths = Vec::new();
-        while !path.is_empty() {
-            match re.parse_k(path, &self.res, &self.firsts, self.k) {
+    /// Returns the bound on loop iterations and recursion depth, see `bounds` for those of each function
+    pub fn k(&self) -> usize {
+        self.options.k
+    }
+
+    /// Returns the options of the reducer
+    pub fn options(&self) -> &ReducerOptions {
+        &self.options
+    }
+
+    /// Returns the bounds of the function `fun_id`
+    pub fn bounds(&self, fun_id: &FunID) -> Bounds {
+        self.bounds.of(fun_id)
+    }
+
+    /// Returns the regular expression of the function `fun_id`
+    pub fn regex(&self, fun_id: &FunID) -> Option<&RegExp<BlockID, FunID>> {
+        self.res.get(fun_id)
+    }
+
+    /// Returns the regular expressions of all functions
+    pub fn regexes(&self) -> &BTreeMap<FunID, RegExp<BlockID, FunID>> {
+        &self.res
+    }
+
+    /// Returns the size of the regular expression of each function, see `RegExp::size`
+    pub fn regex_sizes(&self) -> BTreeMap<FunID, usize> {
+        self.res.iter().map(|(fun_id, re)| (fun_id.clone(), re.size())).collect()
+    }
+
+    /// Returns the function whose first block is `block`
+    pub fn entry_function(&self, block: &BlockID) -> Option<&FunID> {
+        self.firsts.get(block)
+    }
+
+    /// Returns the digest used for reduced paths
+    pub fn digest_kind(&self) -> DigestKind {
+        self.options.digest
+    }
+
+    /// Sets the digest used for reduced paths
+    pub fn set_digest_kind(&mut self, digest: DigestKind) {
+        self.options.digest = digest;
+    }
+
+    /// Returns the automata of the regular expressions
+    fn automata(&self) -> &Automata<BlockID, FunID> {
+        self.automata.get_or_init(|| Automata::new(&self.res))
+    }
+
+    /// Starts reducing a path given in parts, see `ReductionSession`.
+    ///
+    /// Fails with `Error::InvalidArgument` unless the mode is `ReductionMode::Automaton`.
+    pub fn begin(&self) -> Result<ReductionSession<'_, BlockID, FunID>> {
+        if self.options.mode != ReductionMode::Automaton {
+            return Err(Error::InvalidArgument(format!(
+                "only the automaton reduction reduces paths in parts, not {:?}",
+                self.options.mode
+            )));
+        }
+        let path = (self.options.on_error.unwrap_or_default() == ErrorPolicy::FullPath).then(Vec::new);
+        Ok(ReductionSession {
+            reducer: self,
+            parser: None,
+            orphans: LoopStack::default(),
+            reduced: Vec::new(),
+            read: 0,
+            path,
+            error: None,
+            invalid: false,
+            skipping: false,
+        })
+    }
+
+    /// Reduces `path`, an execution path.
+    ///
+    /// The second argument is unused, the functions are determined by their first blocks in `path`.
+    /// Blocks of functions whose first block isn't in `path` keep at most `k` iterations of each loop.
+    pub fn reduce(&self, path: &[BlockID], cfg: FunID) -> Result<Vec<BlockID>> {
+        let mut reduced = Vec::new();
+        self.reduce_into(path, cfg, &mut reduced)?;
+        Ok(reduced)
+    }
+
+    /// Reduces `path` into `reduced`, reusing its memory, see `reduce`
+    pub fn reduce_into(&self, path: &[BlockID], cfg: FunID, reduced: &mut Vec<BlockID>) -> Result<()> {
+        self.reduce_into_with_stats(path, cfg, reduced).map(|_| ())
+    }
+
+    /// Reduces `path`, see `reduce`, and returns what the reduction did
+    pub fn reduce_with_stats(&self, path: &[BlockID], cfg: FunID) -> Result<(Vec<BlockID>, ReductionStats)> {
+        let mut reduced = Vec::new();
+        let stats = self.reduce_into_with_stats(path, cfg, &mut reduced)?;
+        Ok((reduced, stats))
+    }
+
+    /// Reduces `path` into `reduced`, reusing its memory, and returns what the reduction did
+    pub fn reduce_into_with_stats(
+        &self,
+        path: &[BlockID],
+        _cfg: FunID,
+        reduced: &mut Vec<BlockID>,
+    ) -> Result<ReductionStats> {
+        let mut stats = ReductionStats { path_len: path.len(), ..ReductionStats::default() };
+        reduced.clear();
+        let mut dropped = Dropped::default();
+        if self.options.mode == ReductionMode::Simple {
+            self.simple_reduce_into(path, reduced, &mut dropped)?;
+        } else if let Err((valid, msg)) = self.regex_reduce_into(path, reduced, &mut dropped)? {
+            reduced.clear();
+            dropped = Dropped::default();
+            let err = Error::InvalidPath(format!("{:?}, error: {}", path, msg));
+            if let Some(diagnostics) = &self.options.diagnostics {
+                diagnostics.report(&err);
+            }
+            let policy = self.options.on_error.unwrap_or_default();
+            match policy {
+                ErrorPolicy::Fail => return Err(err),
+                ErrorPolicy::FullPath => reduced.extend_from_slice(path),
+                ErrorPolicy::EmptyPath => {}
+                ErrorPolicy::LongestValidPrefix => {
+                    self.reduce_valid_prefix(path, valid, reduced, &mut dropped)?;
+                }
+                ErrorPolicy::Resync => self.resync_reduce(path, valid, reduced, &mut dropped)?,
+            }
+            stats.recovery = Some(policy);
+        }
+        stats.reduced_len = reduced.len();
+        stats.dropped_iterations = dropped.iterations;
+        stats.dropped_calls = dropped.calls;
+        Ok(stats)
+    }
+
+    /// Appends the reduction of the valid parts of `path` to `reduced`, `path[..valid]` being its longest valid prefix.
+    /// After the longest valid prefix, reduction restarts at the next block starting a function.
+    fn resync_reduce(
+        &self,
+        mut path: &[BlockID],
+        mut valid: usize,
+        reduced: &mut Vec<BlockID>,
+        dropped: &mut Dropped,
+    ) -> Result<()> {
+        loop {
+            // at least the block where the parse failed is dropped, so that each search starts further,
+            // even if the parse failed at the first block
+            valid = self.reduce_valid_prefix(path, valid, reduced, dropped)?.max(1);
+            // the block after the prefix may itself start a function, e.g. a signal handler
+            let Some(next) = path[valid..].iter().position(|block| self.firsts.contains_key(block)) else {
+                return Ok(());
+            };
+            path = &path[valid + next..];
+            let (len, before) = (reduced.len(), *dropped);
+            match self.regex_reduce_into(path, reduced, dropped)? {
+                Ok(()) => return Ok(()),
+                Err((rest_valid, _)) => {
+                    reduced.truncate(len);
+                    *dropped = before;
+                    valid = rest_valid;
+                }
+            }
+        }
+    }
+
+    /// Appends the reduction of `path[..valid]` to `reduced`, `valid` being where the parse of `path` failed,
+    /// and returns the length of the prefix reduced
+    fn reduce_valid_prefix(
+        &self,
+        path: &[BlockID],
+        mut valid: usize,
+        reduced: &mut Vec<BlockID>,
+        dropped: &mut Dropped,
+    ) -> Result<usize> {
+        let (len, before) = (reduced.len(), *dropped);
+        // a parse of the prefix doesn't fail, as it ends where the parse of `path` failed,
+        // but fall back to where it fails if it does
+        while let Err((prefix_valid, _)) = self.regex_reduce_into(&path[..valid], reduced, dropped)? {
+            reduced.truncate(len);
+            *dropped = before;
+            valid = prefix_valid;
+        }
+        Ok(valid)
+    }
+
+    /// Appends the reduction of `path` with the regular expressions to `reduced_paths`, and what it drops to `dropped`,
+    /// or returns the length of the longest valid prefix of `path` and the parse error if `path` is invalid
+    fn regex_reduce_into(
+        &self,
+        mut path: &[BlockID],
+        reduced_paths: &mut Vec<BlockID>,
+        dropped: &mut Dropped,
+    ) -> Result<std::result::Result<(), (usize, String)>> {
+        let len = path.len();
+        let mut orphans = LoopStack::default();
+        while let Some(block) = path.first() {
+            let Some(fun_id) = self.firsts.get(block) else {
+                // a block of a function whose entry isn't in the path,
+                // e.g. the path starts in the middle of a thread or a callback
+                if orphans.push(reduced_paths, block.clone(), self.bounds.default.loops) {
+                    dropped.iterations += 1;
+                }
+                path = &path[1..];
+                continue;
+            };
+            let parsed = if self.options.mode == ReductionMode::Automaton {
+                self.automata().parse_k(fun_id, path, &self.firsts, &self.star_bounds)
+            } else {
+                RegExp::Var(fun_id.clone()).parse_bounded(path, &self.res, &self.firsts, &self.star_bounds)
+            };
+            match parsed {
                 Ok((reduced_path, res)) => {
-                    // assert!(res.len() < path.len());
-                    let mut this_path = reduced_path.into_vec();
-                    reduced_paths.append(&mut this_path);
+                    reduced_path.append_counting(reduced_paths, dropped);
                     path = res;
                 }
                 Err(ParseErr::Abort(val)) => {
-                    reduced_paths.append(&mut val.into_vec());
-                    return reduced_paths
-                }
-                Err(ParseErr::Invalid(s)) => {
-                    if let Ok(on_error) = env::var(PATH_REDUCTION_ON_ERROR) {
-                        match on_error.as_str() {
-                            FULL_PATH => {
-                                if env::var(PATH_REDUCTION_DEBUG).is_ok() {
-                                    println!("invalid path: {:?}", unreduced);
-                                }
-                                return unreduced.to_vec();
-                            }
-                            EMPTY_PATH => {
-                                if env::var(PATH_REDUCTION_DEBUG).is_ok() {
-                                    println!("invalid path: {:?}", unreduced);
-                                }
-                                return vec![];
-                            }
-                            _ => {
-                                panic!("invalid value for PATH_REDUCTION_ON_ERROR: {}", on_error);
-                            }
-                        }
-                    } else {
-                        panic!("invalid path: {:?}, error: {}", unreduced, s);
-                    }
+                    val.append_counting(reduced_paths, dropped);
+                    break;
                 }
-                
+                Err(ParseErr::Invalid(msg, rest)) => return Ok(Err((len - rest, msg))),
+                Err(ParseErr::Fatal(err)) => return Err(err),
             }
         }
-        reduced_paths
+        Ok(Ok(()))
     }
 
-    fn simple_reduce(&self, mut path: &[BlockID]) -> Vec<BlockID> {
-        let mut res = Vec::new();
-        while !path.is_empty() {
-            let mut stack = vec![];
-            let mut reduced = self.simple_reduce_one_fun(&mut path, &mut stack, false);
-            // println!("reduced one {:?}", reduced);
+    /// Appends the reduction of `path` by the simple reduction to `res`
+    fn simple_reduce_into(&self, mut path: &[BlockID], res: &mut Vec<BlockID>, dropped: &mut Dropped) -> Result<()> {
+        // blocks of functions whose entry isn't in the path
+        let mut orphans = LoopStack::default();
+        // first blocks of the calls in progress, shared by the calls of the path
+        let mut stack = vec![];
+        while let Some(block) = path.first() {
+            if !self.firsts.contains_key(block) {
+                if orphans.push(res, block.clone(), self.bounds.default.loops) {
+                    dropped.iterations += 1;
+                }
+                path = &path[1..];
+                continue;
+            }
+            stack.clear();
+            // a function with recursion bound 0 drops its calls, even the first in the path
+            let skip = self.firsts.get(block).is_some_and(|fun_id| self.bounds.of(fun_id).recursion == 0);
+            if skip {
+                dropped.calls += 1;
+            }
+            let mut reduced = self.simple_reduce_one_fun(&mut path, &mut stack, skip, dropped)?;
             res.append(&mut reduced);
         }
-        res
+        Ok(())
     }
 
-    fn get_last_blocks(&self, block: &BlockID) -> &BTreeSet<BlockID> {
-        self.lasts.get(block).expect(&format!("failed to get last blocks for block {:?}", block))
+    fn get_last_blocks(&self, block: &BlockID) -> Result<&BTreeMap<BlockID, BTreeSet<BlockID>>> {
+        self.lasts
+            .get(block)
+            .ok_or_else(|| Error::NoEntryFunction(format!("{:?}", block)))
     }
 
-    fn simple_reduce_one_fun(&self, path: &mut &[BlockID], stack: &mut Vec<BlockID>, skip: bool) -> Vec<BlockID> {
+    fn simple_reduce_one_fun(
+        &self,
+        path: &mut &[BlockID],
+        stack: &mut Vec<BlockID>,
+        skip: bool,
+        dropped: &mut Dropped,
+    ) -> Result<Vec<BlockID>> {
         // holds the reduced path of the current function call (including all sub-calls)
         let mut buffer = Vec::new();
-        // maps a block to where it last appears in the buffer
         // this local to this function call
-        let mut loop_stack: BTreeMap<BlockID, usize> = BTreeMap::new();
+        let mut loop_stack = LoopStack::default();
         let first = if let Some(first) = path.first() {
             first.clone()
         } else {
-            return buffer;
+            return Ok(buffer);
         };
         if skip {
             // println!("skipping {:?}", first);
         } else {
             // println!("reducing {:?}", first);
         }
+        let loops = self.firsts.get(&first).map_or(self.bounds.default, |fun_id| self.bounds.of(fun_id)).loops;
         // read the first block
         *path = &path[1..];
         stack.push(first.clone());
         if !skip {
-            buffer.push(first.clone());
-            loop_stack.insert(first.clone(), 0);
+            loop_stack.push(&mut buffer, first.clone(), loops);
         }
-        let lasts = self.get_last_blocks(&first);
+        let lasts = self.get_last_blocks(&first)?;
         // println!("first {:?} lasts {:?}", first, lasts);
-        if lasts.contains(&first) {
+        if returns_at(lasts, &first, path.first()) {
             // the function contains only one block
             // reach the end of the call
             while let Some(last) = stack.pop() {
@@ -124,20 +630,23 @@ impl<BlockID: Eq + Clone + Ord+ Debug, FunID: Eq + Clone + Ord + Debug> PathRedu
                     break;
                 }
             }
-            return buffer;
+            return Ok(buffer);
         }
         loop {
             if let Some(block) = path.first().cloned() {
                 // block is the start of a new function
-                if self.firsts.contains_key(&block) {
-                    // the function is on stack
-                    if skip || stack.iter().rev().find(|frame| frame == &&block).is_some() {
-                        self.simple_reduce_one_fun(path, stack, true);
+                if let Some(fun_id) = self.firsts.get(&block) {
+                    // the function is on stack k times
+                    if skip {
+                        self.simple_reduce_one_fun(path, stack, true, dropped)?;
+                    } else if stack.iter().filter(|frame| *frame == &block).count() >= self.bounds.of(fun_id).recursion {
+                        dropped.calls += 1;
+                        self.simple_reduce_one_fun(path, stack, true, dropped)?;
                     } else {
                         // reduce the path of this function call
-                        buffer.append(&mut self.simple_reduce_one_fun(path, stack, skip));
+                        buffer.append(&mut self.simple_reduce_one_fun(path, stack, skip, dropped)?);
                     }
-                } else if lasts.contains(&block) { // we reach the end of the current function call
+                } else if returns_at(lasts, &block, path.get(1)) { // we reach the end of the current function call
                     *path = &path[1..];
                     // stack.remove(&first);
                     while let Some(last) = stack.pop() {
@@ -149,75 +658,894 @@ impl<BlockID: Eq + Clone + Ord+ Debug, FunID: Eq + Clone + Ord + Debug> PathRedu
                         // since we return immediately, we don't need to update the loop stack
                         buffer.push(block.clone());
                     }
-                    return buffer;
+                    return Ok(buffer);
                 } else { // another block in the current function call
                     if skip {
                         *path = &path[1..];
                         continue;
                     }
-                    // appears in the buffer at `last_off`
-                    if let Some(&last_off) = loop_stack.get(&block) {
-                        // remove the blocks starting from `last_off`
-                        // println!("before drain {:?}", buffer);
-                        buffer.truncate(last_off);
-                        // println!("after drain {:?}", buffer);
-                        loop_stack.retain(|_, &mut off| off < last_off);
-                    }
                     *path = &path[1..];
-                    buffer.push(block.clone());
-                    loop_stack.insert(block.clone(), buffer.len() - 1);
+                    if loop_stack.push(&mut buffer, block, loops) {
+                        dropped.iterations += 1;
+                    }
                 }
             } else {
                 // the current function call aborts
-                return buffer;
+                return Ok(buffer);
+            }
+        }
+    }
+}
+
+/// Blocks of a function call, with where they appear in the reduced path of the call
+struct LoopStack<BlockID> {
+    /// Offsets of each block in the reduced path, in increasing order
+    offsets: BTreeMap<BlockID, Vec<usize>>,
+}
+
+impl<BlockID> Default for LoopStack<BlockID> {
+    fn default() -> Self {
+        Self { offsets: BTreeMap::new() }
+    }
+}
+
+impl<BlockID: Ord + Clone> LoopStack<BlockID> {
+    /// Appends `block` to `buffer`, the reduced path of the call.
+    /// If `block` already appears `k` times, the last iteration of the loop ending with `block` is removed first,
+    /// and `true` is returned.
+    fn push(&mut self, buffer: &mut Vec<BlockID>, block: BlockID, k: usize) -> bool {
+        let offs = self.offsets.get(&block).map_or(&[][..], Vec::as_slice);
+        let last_off = offs.last().filter(|_| offs.len() >= k).copied();
+        if let Some(last_off) = last_off {
+            // remove the blocks starting from `last_off`, i.e. the last iteration
+            buffer.truncate(last_off);
+            self.offsets.retain(|_, offs| {
+                offs.retain(|&off| off < last_off);
+                !offs.is_empty()
+            });
+        }
+        self.offsets.entry(block.clone()).or_default().push(buffer.len());
+        buffer.push(block);
+        last_off.is_some()
+    }
+}
+
+/// Reduction of a path read in parts, with the same result as `PathReducer::reduce` in `ReductionMode::Automaton`.
+///
+/// The path is parsed with the automata of the reducer, so that only the calls in progress
+/// and the reduced path are kept, except with `ErrorPolicy::FullPath`, which keeps the whole path.
+pub struct ReductionSession<'r, BlockID, FunID> {
+    reducer: &'r PathReducer<BlockID, FunID>,
+    /// The call in progress of a function whose first block is in the path
+    parser: Option<Parser<'r, BlockID, FunID>>,
+    /// Blocks of functions whose first block isn't in the path
+    orphans: LoopStack<BlockID>,
+    reduced: Vec<BlockID>,
+    /// Number of blocks read
+    read: usize,
+    /// The blocks read, with `ErrorPolicy::FullPath`
+    path: Option<Vec<BlockID>>,
+    /// The error of an invalid path, with `ErrorPolicy::Fail`
+    error: Option<Error>,
+    invalid: bool,
+    /// Whether the blocks are skipped after an invalid block, with `ErrorPolicy::Resync` until a function starts
+    skipping: bool,
+}
+
+impl<BlockID: Eq + Clone + Ord + Debug, FunID: Eq + Clone + Ord + Debug> ReductionSession<'_, BlockID, FunID> {
+    /// Reads the next blocks of the path.
+    ///
+    /// With `ErrorPolicy::Fail`, fails with `Error::InvalidPath` at the first invalid block, and then at every call.
+    pub fn feed(&mut self, blocks: &[BlockID]) -> Result<()> {
+        if let Some(err) = &self.error {
+            return Err(err.clone());
+        }
+        if let Some(path) = &mut self.path {
+            path.extend_from_slice(blocks);
+        }
+        for block in blocks {
+            self.push(block)?;
+            self.read += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the reduced path
+    pub fn finish(mut self) -> Result<Vec<BlockID>> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        if self.invalid {
+            match self.reducer.options.on_error.unwrap_or_default() {
+                ErrorPolicy::FullPath => return Ok(self.path.unwrap_or_default()),
+                ErrorPolicy::EmptyPath => return Ok(Vec::new()),
+                _ => {}
+            }
+        }
+        if let Some(parser) = self.parser {
+            parser.finish().0.append_to(&mut self.reduced);
+        }
+        Ok(self.reduced)
+    }
+
+    fn push(&mut self, block: &BlockID) -> Result<()> {
+        let reducer = self.reducer;
+        if self.skipping {
+            if reducer.options.on_error != Some(ErrorPolicy::Resync) || !reducer.firsts.contains_key(block) {
+                return Ok(());
+            }
+            self.skipping = false;
+        }
+        loop {
+            let Some(parser) = &mut self.parser else {
+                match reducer.firsts.get(block) {
+                    Some(fun_id) => {
+                        let parser = Parser::new(reducer.automata(), fun_id, &reducer.star_bounds);
+                        self.parser = Some(parser.map_err(|err| match err {
+                            ParseErr::Fatal(err) => err,
+                            err => Error::InvalidPath(format!("{:?}", err)),
+                        })?);
+                    }
+                    None => {
+                        self.orphans.push(&mut self.reduced, block.clone(), reducer.bounds.default.loops);
+                        return Ok(());
+                    }
+                }
+                continue;
+            };
+            match parser.feed(block, &reducer.firsts) {
+                Ok(None) => return Ok(()),
+                Ok(Some(val)) => {
+                    val.append_to(&mut self.reduced);
+                    self.parser = None;
+                }
+                Err(ParseErr::Invalid(msg, _)) => return self.invalid(block, msg),
+                Err(ParseErr::Fatal(err)) => return Err(err),
+                Err(ParseErr::Abort(_)) => return self.invalid(block, "the parse of the block was aborted".to_string()),
             }
         }
     }
+
+    /// Applies the error policy at the invalid block `block`
+    fn invalid(&mut self, block: &BlockID, msg: String) -> Result<()> {
+        let err = Error::InvalidPath(format!("block {} of the path, error: {}", self.read, msg));
+        if let Some(diagnostics) = &self.reducer.options.diagnostics {
+            diagnostics.report(&err);
+        }
+        let policy = self.reducer.options.on_error.unwrap_or_default();
+        if policy == ErrorPolicy::Fail {
+            self.error = Some(err.clone());
+            return Err(err);
+        }
+        if let Some(parser) = self.parser.take() {
+            parser.finish().0.append_to(&mut self.reduced);
+        }
+        self.invalid = true;
+        self.skipping = true;
+        if policy == ErrorPolicy::Resync {
+            self.orphans = LoopStack::default();
+            // the invalid block may itself start a function
+            return self.push(block);
+        }
+        Ok(())
+    }
 }
 
 impl PathReducer<BlockID, FunID> {
-    pub fn from_cfgs(cfgs: BTreeMap<FunID, CFG<BlockID, FunID>>, k: usize) -> Self {
-        let lasts = last_map(&cfgs);
-        let res = convert_cfgs(cfgs);
+    /// Reduces `paths` in parallel on the threads of the reducer, see `reduce`
+    pub fn reduce_batch(&self, paths: &[&[BlockID]], cfg: FunID) -> Vec<Result<Vec<BlockID>>> {
+        self.map_batch(paths, cfg, |reduced| reduced.map(<[BlockID]>::to_vec))
+    }
+
+    /// Reduces `paths` in parallel on the threads of the reducer, and returns `f` of each reduced path.
+    /// The reduced paths are written to buffers of each thread, reused across paths.
+    pub fn map_batch<R: Send>(
+        &self,
+        paths: &[&[BlockID]],
+        cfg: FunID,
+        f: impl Fn(Result<&[BlockID]>) -> R + Send + Sync,
+    ) -> Vec<R> {
+        install(self.pool.as_ref(), || {
+            paths
+                .par_iter()
+                .map_init(Vec::new, |reduced, path| {
+                    let res = self.reduce_into(path, cfg, reduced);
+                    f(res.map(|()| &reduced[..]))
+                })
+                .collect()
+        })
+    }
+
+    /// Builds a reducer from the CFGs of all functions, indexed by function id
+    pub fn from_cfgs(cfgs: BTreeMap<FunID, CFG<BlockID, FunID>>, k: usize) -> Result<Self> {
+        Self::from_cfgs_with_options(cfgs, ReducerOptions::new(k))
+    }
+
+    /// Builds a reducer from the CFGs of all functions with `options`
+    pub fn from_cfgs_with_options(
+        cfgs: BTreeMap<FunID, CFG<BlockID, FunID>>,
+        options: ReducerOptions,
+    ) -> Result<Self> {
+        let functions = cfgs.keys().map(|&fun_id| (fun_id, "")).collect();
+        Self::from_named_cfgs(cfgs, &functions, options)
+    }
+
+    /// Builds a reducer from the CFGs of all functions with `options`, given the name of each function
+    fn from_named_cfgs(
+        cfgs: BTreeMap<FunID, CFG<BlockID, FunID>>,
+        functions: &BTreeMap<FunID, &str>,
+        options: ReducerOptions,
+    ) -> Result<Self> {
+        let options = options.with_env_defaults()?;
+        let bounds = options.bounds(functions)?;
+        let pool = thread_pool(options.threads)?;
+        let cfg_checksum = cfg_checksum(&cfgs);
+        let lasts = install(pool.as_ref(), || last_map(&cfgs))?;
+        let res = install(pool.as_ref(), || convert_cfgs(cfgs, &options))?;
         let mut firsts = BTreeMap::new();
         for (fun_id, re) in res.iter() {
-            let first = re.first();
-            let old = firsts.insert(first, fun_id.clone());
+            let first = re.first()?;
+            let old = firsts.insert(first, *fun_id);
             if let Some(old_fun_id) = old {
-                panic!("functions {} {} both start with block {}", old_fun_id, fun_id, first);
+                return Err(Error::DuplicateEntry {
+                    block: first.to_string(),
+                    first: old_fun_id.to_string(),
+                    second: fun_id.to_string(),
+                });
             }
         }
-        Self { res, firsts, lasts, k }
+        let star_bounds = star_bounds(&bounds);
+        Ok(Self { res, firsts, lasts, automata: OnceLock::new(), pool, options, bounds, star_bounds, cfg_checksum })
+    }
+
+    /// Builds a reducer from the CFGs of `program`
+    pub fn from_program(program: &Program, k: usize) -> Result<Self> {
+        Self::from_program_with_options(program, ReducerOptions::new(k))
+    }
+
+    /// Builds a reducer from the CFGs of `program` with `options`
+    pub fn from_program_with_options(program: &Program, options: ReducerOptions) -> Result<Self> {
+        let functions = function_names(program);
+        Self::from_named_cfgs(program.to_cfgs()?, &functions, options)
+    }
+
+    /// Builds a reducer from the CFG file at `file_path`, see `Program::load`
+    pub fn from_cfg_file(file_path: impl AsRef<Path>, k: usize) -> Result<Self> {
+        Self::from_cfg_file_with_options(file_path, ReducerOptions::new(k))
+    }
+
+    /// Builds a reducer from the CFG file at `file_path` with `options`
+    pub fn from_cfg_file_with_options(file_path: impl AsRef<Path>, options: ReducerOptions) -> Result<Self> {
+        Self::from_program_with_options(&Program::load(file_path)?, options)
+    }
+
+    /// Returns the checksum of the CFGs the reducer is built from
+    pub fn cfg_checksum(&self) -> &[u8; 32] {
+        &self.cfg_checksum
+    }
+
+    /// Writes the regular expressions of the reducer to `w`,
+    /// so that they don't have to be computed again by `load`.
+    pub fn save(&self, w: &mut impl Write) -> Result<()> {
+        let conversion = self.options.conversion_options();
+        cache::write(w, &self.cfg_checksum, &conversion, &self.res, &self.firsts, &self.lasts)
+    }
+
+    /// Reads a reducer written by `save` from `r`.
+    ///
+    /// Fails with `Error::StaleCache` if the reducer isn't built from CFGs with checksum `cfg_checksum`,
+    /// or with other `conversion`, `elimination` or `simplify` options.
+    pub fn load(r: &mut impl Read, cfg_checksum: &[u8; 32], k: usize) -> Result<Self> {
+        Self::load_with_options(r, cfg_checksum, ReducerOptions::new(k))
+    }
+
+    /// Reads a reducer written by `save` from `r`, see `load`.
+    /// The functions of `options.function_k` can only be given by id.
+    pub fn load_with_options(r: &mut impl Read, cfg_checksum: &[u8; 32], options: ReducerOptions) -> Result<Self> {
+        Self::load_named(r, cfg_checksum, &BTreeMap::new(), options)
+    }
+
+    /// Reads a reducer written by `save` for the CFGs of `program` from `r`, see `load`
+    pub fn load_for_program(r: &mut impl Read, program: &Program, options: ReducerOptions) -> Result<Self> {
+        let functions = function_names(program);
+        Self::load_named(r, &cfg_checksum(&program.to_cfgs()?), &functions, options)
+    }
+
+    /// Reads a reducer written by `save` from `r`, given the name of each function
+    fn load_named(
+        r: &mut impl Read,
+        cfg_checksum: &[u8; 32],
+        functions: &BTreeMap<FunID, &str>,
+        options: ReducerOptions,
+    ) -> Result<Self> {
+        let options = options.with_env_defaults()?;
+        let pool = thread_pool(options.threads)?;
+        let CachedReducer { cfg_checksum: cached_checksum, conversion, res, firsts, lasts } = cache::read(r)?;
+        if &cached_checksum != cfg_checksum || conversion != options.conversion_options() {
+            return Err(Error::StaleCache);
+        }
+        let functions = res.keys().map(|fun_id| (*fun_id, functions.get(fun_id).copied().unwrap_or(""))).collect();
+        let bounds = options.bounds(&functions)?;
+        let star_bounds = star_bounds(&bounds);
+        Ok(Self {
+            res,
+            firsts,
+            lasts,
+            automata: OnceLock::new(),
+            pool,
+            options,
+            bounds,
+            star_bounds,
+            cfg_checksum: cached_checksum,
+        })
+    }
+}
+
+/// Returns the name of each function of `program`
+fn function_names(program: &Program) -> BTreeMap<FunID, &str> {
+    program
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(fun_id, function)| (fun_id as FunID, function.name.as_str()))
+        .collect()
+}
+
+/// Returns a pool of `threads` threads, or `None` for the global pool with one thread per core
+fn thread_pool(threads: usize) -> Result<Option<ThreadPool>> {
+    if threads == 0 {
+        return Ok(None);
+    }
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|err| Error::InvalidArgument(format!("can't start {} threads: {}", threads, err)))?;
+    Ok(Some(pool))
+}
+
+/// Runs `f` in `pool`, or in the global pool
+fn install<R: Send>(pool: Option<&ThreadPool>, f: impl FnOnce() -> R + Send) -> R {
+    match pool {
+        Some(pool) => pool.install(f),
+        None => f(),
     }
 }
 
 fn convert_cfgs(
     cfgs: BTreeMap<FunID, CFG<BlockID, FunID>>,
-) -> BTreeMap<FunID, RegExp<BlockID, FunID>> {
-    cfgs.into_iter()
-        // .par_bridge()
+    options: &ReducerOptions,
+) -> Result<BTreeMap<FunID, RegExp<BlockID, FunID>>> {
+    cfgs.into_par_iter()
         .map(|(fun_id, cfg)| {
-            let mut gnfa = GNFA::from_intern_cfg(cfg);
+            let mut gnfa = GNFA::from_intern_cfg(cfg).map_err(|err| match err {
+                Error::NoExit(_) => Error::NoExit(fun_id.to_string()),
+                err => err,
+            })?;
             // println!("before reduce {:?}", Dot::new(&gnfa.the_graph));
-            gnfa.reduce();
+            match options.conversion {
+                Conversion::StateElimination => gnfa.reduce_with(options.elimination),
+                Conversion::Structural => gnfa.reduce_structural(),
+            }
             // println!("after reduce {:?}", Dot::new(&gnfa.the_graph));
-            let re = gnfa.start_to_end().clone();
-            (fun_id, re)
+            let re = if options.simplify {
+                gnfa.start_to_end().simplify()
+            } else {
+                gnfa.start_to_end().clone()
+            };
+            Ok((fun_id, re))
         })
         .collect()
 }
 
-/// Returns a map from the first block of a function to the set of exit blocks
+/// Returns whether a call returns at its exit `block` followed by `next`,
+/// i.e. unless `next` can follow `block` in the function, as an exit may have successors
+fn returns_at<BlockID: Ord>(exits: &BTreeMap<BlockID, BTreeSet<BlockID>>, block: &BlockID, next: Option<&BlockID>) -> bool {
+    exits.get(block).is_some_and(|nexts| next.is_none_or(|next| !nexts.contains(next)))
+}
+
+/// Returns the first block of the function of `cfg`
+fn first_block(cfg: &CFG<BlockID, FunID>) -> Option<BlockID> {
+    cfg.graph.node_weight(cfg.entry).and_then(|node| node.clone().into_block_id())
+}
+
+/// Returns a map from the first block of a function to its exit blocks,
+/// each with the blocks that can follow it in the function
 fn last_map(
     cfgs: &BTreeMap<FunID, CFG<BlockID, FunID>>,
-) -> BTreeMap<BlockID, BTreeSet<BlockID>> {
-    cfgs.iter()
-        // .par_bridge()
-        .map(|(_fun_id, cfg)| {
-            let first = cfg.graph.node_weight(cfg.entry).unwrap().clone().to_block_id();
-            let exit_node_indices: Vec<_> = cfg.graph.node_indices().filter(|node_idx| cfg.graph.neighbors(*node_idx).count() == 0).collect();
-            let exit_nodes = exit_node_indices.iter().map(|node_idx| cfg.graph.node_weight(*node_idx).unwrap().clone().to_block_id()).collect();
-            (first, exit_nodes)
+) -> Result<BTreeMap<BlockID, BTreeMap<BlockID, BTreeSet<BlockID>>>> {
+    cfgs.par_iter()
+        .map(|(fun_id, cfg)| {
+            let first = first_block(cfg).ok_or_else(|| Error::NoFirstBlock(fun_id.to_string()))?;
+            // empty for functions that don't return
+            let exit_nodes = cfg
+                .exit_nodes()
+                .iter()
+                .filter_map(|&node_idx| {
+                    let exit = cfg.graph[node_idx].clone().into_block_id()?;
+                    Some((exit, next_blocks(cfgs, cfg, node_idx)))
+                })
+                .collect();
+            Ok((first, exit_nodes))
         })
         .collect()
 }
+
+/// Returns the blocks that can follow `node_idx` in a path of `cfg`:
+/// its successor blocks, the first blocks of the functions they call,
+/// and the blocks following its successors that call no known function
+fn next_blocks(
+    cfgs: &BTreeMap<FunID, CFG<BlockID, FunID>>,
+    cfg: &CFG<BlockID, FunID>,
+    node_idx: NodeIndex,
+) -> BTreeSet<BlockID> {
+    let mut blocks = BTreeSet::new();
+    let mut visited = BTreeSet::new();
+    let mut stack: Vec<_> = cfg.graph.neighbors(node_idx).collect();
+    while let Some(succ) = stack.pop() {
+        if !visited.insert(succ) {
+            continue;
+        }
+        let callees = match &cfg.graph[succ] {
+            Node::Literal(block_id) => {
+                blocks.insert(*block_id);
+                continue;
+            }
+            Node::Var(fun_id) => std::slice::from_ref(fun_id),
+            Node::Vars(fun_ids) => fun_ids.as_slice(),
+            Node::Extern => &[],
+        };
+        blocks.extend(callees.iter().filter_map(|fun_id| cfgs.get(fun_id)).filter_map(first_block));
+        if callees.is_empty() {
+            stack.extend(cfg.graph.neighbors(succ));
+        }
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::CfgBuilder;
+
+    fn cfgs() -> BTreeMap<FunID, CFG<BlockID, FunID>> {
+        builder().build().unwrap()
+    }
+
+    /// `main` is `0 f 2`, `f` is `3 (f | ε) 5`, and `g` is `6 (7 8)* 9`
+    fn builder() -> CfgBuilder {
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 2);
+        let f = builder.add_function("f", 3, 5);
+        builder.add_function("g", 6, 9);
+        for block_id in 0..10 {
+            builder.add_block(block_id);
+        }
+        builder.set_call(1, f).add_edge(0, 1).add_edge(1, 2);
+        builder.set_call(4, f).add_edge(3, 4).add_edge(3, 5).add_edge(4, 5);
+        builder.add_edge(6, 7).add_edge(6, 9).add_edge(7, 8).add_edge(8, 7).add_edge(8, 9);
+        builder
+    }
+
+    fn simple_reducer(k: usize) -> PathReducer<BlockID, FunID> {
+        let options = ReducerOptions { mode: ReductionMode::Simple, ..ReducerOptions::new(k) };
+        PathReducer::from_cfgs_with_options(cfgs(), options).unwrap()
+    }
+
+    fn reducer(on_error: ErrorPolicy, diagnostics: Option<DiagnosticSink>) -> PathReducer<BlockID, FunID> {
+        let options = ReducerOptions { on_error: Some(on_error), diagnostics, ..ReducerOptions::new(1) };
+        PathReducer::from_cfgs_with_options(cfgs(), options).unwrap()
+    }
+
+    #[test]
+    fn simple_mode_bounds_loops_by_k() {
+        let path = [6, 7, 8, 7, 8, 7, 8, 9];
+        assert_eq!(simple_reducer(1).reduce(&path, 2), Ok(vec![6, 7, 8, 9]));
+        assert_eq!(simple_reducer(2).reduce(&path, 2), Ok(vec![6, 7, 8, 7, 8, 9]));
+        assert_eq!(simple_reducer(3).reduce(&path, 2), Ok(path.to_vec()));
+    }
+
+    #[test]
+    fn simple_mode_bounds_recursion_by_k() {
+        let path = [0, 3, 3, 3, 5, 5, 5, 2];
+        assert_eq!(simple_reducer(1).reduce(&path, 0), Ok(vec![0, 3, 5, 2]));
+        assert_eq!(simple_reducer(2).reduce(&path, 0), Ok(vec![0, 3, 3, 5, 5, 2]));
+        assert_eq!(simple_reducer(3).reduce(&path, 0), Ok(path.to_vec()));
+    }
+
+    #[test]
+    fn every_mode_keeps_k_iterations() {
+        let path = [6, 7, 8, 7, 8, 7, 8, 9];
+        for mode in [ReductionMode::Regex, ReductionMode::Simple, ReductionMode::Automaton] {
+            for conversion in [Conversion::StateElimination, Conversion::Structural] {
+                for k in 1..=3 {
+                    let options = ReducerOptions { mode, conversion, ..ReducerOptions::new(k) };
+                    let reducer = PathReducer::from_cfgs_with_options(cfgs(), options).unwrap();
+                    let mut expected = vec![6];
+                    expected.extend([7, 8].repeat(k));
+                    expected.push(9);
+                    assert_eq!(reducer.reduce(&path, 2), Ok(expected), "{:?} {:?}", mode, conversion);
+                }
+            }
+            let options = ReducerOptions { mode, ..ReducerOptions::new(0) };
+            assert!(matches!(PathReducer::from_cfgs_with_options(cfgs(), options), Err(Error::InvalidArgument(_))));
+            // a recursion bound of 0 drops the calls of a function, even the first one
+            let options = ReducerOptions { mode, recursion_k: Some(0), ..ReducerOptions::new(1) };
+            let reducer = PathReducer::from_cfgs_with_options(cfgs(), options).unwrap();
+            assert_eq!(reducer.reduce(&[0, 3, 5, 2], 0), Ok(Vec::new()), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn every_elimination_order_keeps_the_same_iterations() {
+        // `a` is `20 (21 (22 | 23) 24)* 25`, `b` is `30 (31 32)* 31 33`, and `c` is `10 (11 12+ 13)+ 14`
+        let mut builder = CfgBuilder::new();
+        builder.add_function("a", 20, 25);
+        builder.add_function("b", 30, 33);
+        builder.add_function("c", 10, 14);
+        for block_id in (20..26).chain(30..34).chain(10..15) {
+            builder.add_block(block_id);
+        }
+        builder.add_edge(20, 21).add_edge(20, 25).add_edge(21, 22).add_edge(21, 23).add_edge(22, 24).add_edge(23, 24);
+        builder.add_edge(24, 21).add_edge(24, 25);
+        builder.add_edge(30, 31).add_edge(31, 32).add_edge(32, 31).add_edge(31, 33);
+        builder.add_edge(10, 11).add_edge(11, 12).add_edge(12, 12).add_edge(12, 13).add_edge(13, 11).add_edge(13, 14);
+        let a = [20, 21, 22, 24, 21, 23, 24, 21, 23, 24, 21, 22, 24, 25];
+        let b = [30, 31, 32, 31, 32, 31, 32, 31, 33];
+        let c = [10, 11, 12, 12, 12, 13, 11, 12, 13, 11, 12, 12, 12, 12, 13, 14];
+        // the first `k - 1` iterations and the last one, which a path ending in a loop ends in
+        let expected: [(FunID, &[BlockID], usize, &[BlockID]); 7] = [
+            (0, &a, 1, &[20, 21, 22, 24, 25]),
+            (0, &a, 2, &[20, 21, 22, 24, 21, 22, 24, 25]),
+            (0, &a[..6], 1, &[20, 21, 23]),
+            (1, &b, 1, &[30, 31, 33]),
+            (1, &b[..7], 1, &[30, 31, 32]),
+            (2, &c, 1, &[10, 11, 12, 13, 14]),
+            (2, &c, 2, &[10, 11, 12, 12, 13, 11, 12, 12, 13, 14]),
+        ];
+        let orders = [
+            EliminationOrder::Index,
+            EliminationOrder::MinDegree,
+            EliminationOrder::Weight,
+            EliminationOrder::ReversePostOrder,
+            EliminationOrder::Dominator,
+        ];
+        for (fun_id, path, k, reduced) in expected {
+            for mode in [ReductionMode::Regex, ReductionMode::Automaton] {
+                for elimination in orders {
+                    for simplify in [false, true] {
+                        let options = ReducerOptions { mode, elimination, simplify, ..ReducerOptions::new(k) };
+                        let reducer = PathReducer::from_cfgs_with_options(builder.clone().build().unwrap(), options).unwrap();
+                        let res = reducer.reduce(path, fun_id);
+                        assert_eq!(res, Ok(reduced.to_vec()), "{:?} {:?} {} k={}", mode, elimination, simplify, k);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn both_conversions_keep_the_same_iterations() {
+        // `h` is `10 (11 12+ 13)+ 14`
+        let mut builder = CfgBuilder::new();
+        builder.add_function("h", 10, 14);
+        for block_id in 10..15 {
+            builder.add_block(block_id);
+        }
+        builder.add_edge(10, 11).add_edge(11, 12).add_edge(12, 12).add_edge(12, 13).add_edge(13, 11).add_edge(13, 14);
+        let path = [10, 11, 12, 12, 12, 13, 11, 12, 13, 11, 12, 12, 12, 12, 13, 14];
+        for mode in [ReductionMode::Regex, ReductionMode::Automaton] {
+            let reduced = |conversion, k| {
+                let options = ReducerOptions { mode, conversion, ..ReducerOptions::new(k) };
+                let reducer = PathReducer::from_cfgs_with_options(builder.clone().build().unwrap(), options).unwrap();
+                reducer.reduce_with_stats(&path, 0)
+            };
+            for k in 1..=3 {
+                let (structural, stats) = reduced(Conversion::Structural, k).unwrap();
+                assert_eq!(reduced(Conversion::StateElimination, k), Ok((structural, stats)), "{:?} k={}", mode, k);
+            }
+            let (reduced_1, stats) = reduced(Conversion::Structural, 1).unwrap();
+            assert_eq!(reduced_1, [10, 11, 12, 13, 14]);
+            // all the outer iterations but the last one, and the inner iterations but the last one in the last one
+            assert_eq!(stats.dropped_iterations, 2 + 3);
+            // the first outer iteration and the last one, each with its first inner iteration and its last one
+            let (reduced_2, _) = reduced(Conversion::Structural, 2).unwrap();
+            assert_eq!(reduced_2, [10, 11, 12, 12, 13, 11, 12, 12, 13, 14]);
+        }
+    }
+
+    #[test]
+    fn simplified_regexes() {
+        let options = ReducerOptions { simplify: true, ..ReducerOptions::new(1) };
+        let simplified = PathReducer::from_cfgs_with_options(cfgs(), options).unwrap();
+        let reducer = reducer(ErrorPolicy::Fail, None);
+        for fun_id in 0..3 {
+            assert!(simplified.regex(&fun_id).unwrap().size() <= reducer.regex(&fun_id).unwrap().size());
+        }
+        // an implicit call to `f` in `g`
+        let implicit_call = [6, 7, 3, 5, 8, 7, 8, 9];
+        for (path, fun_id) in [(&[0, 3, 3, 5, 5, 2][..], 0), (&[6, 7, 8, 7, 8, 9], 2), (&[6, 9], 2), (&implicit_call, 2)] {
+            assert_eq!(simplified.reduce(path, fun_id), reducer.reduce(path, fun_id));
+        }
+        assert!(simplified.reduce(&[6, 7, 9], 2).is_err());
+    }
+
+    #[test]
+    fn automaton_mode() {
+        for k in 1..=2 {
+            let options = ReducerOptions { mode: ReductionMode::Automaton, ..ReducerOptions::new(k) };
+            let automaton = PathReducer::from_cfgs_with_options(cfgs(), options).unwrap();
+            let regex = PathReducer::from_cfgs_with_options(cfgs(), ReducerOptions::new(k)).unwrap();
+            let paths: [(&[i32], i32); 5] = [
+                (&[0, 3, 3, 3, 5, 5, 5, 2], 0),
+                (&[6, 7, 8, 7, 8, 7, 8, 9], 2),
+                (&[6, 7, 3, 5, 8, 7, 8, 9], 2),
+                (&[6, 7, 8, 9, 6, 9, 0, 3, 5, 2], 0),
+                (&[6, 7, 8, 7], 2),
+            ];
+            for (path, fun_id) in paths {
+                assert_eq!(automaton.reduce(path, fun_id), regex.reduce(path, fun_id));
+            }
+            assert!(automaton.reduce(&[6, 7, 9], 2).is_err());
+        }
+    }
+
+    #[test]
+    fn sessions_reduce_paths_in_parts() {
+        let automaton_reducer = |on_error| {
+            let options = ReducerOptions { mode: ReductionMode::Automaton, on_error: Some(on_error), ..ReducerOptions::new(1) };
+            PathReducer::from_cfgs_with_options(cfgs(), options).unwrap()
+        };
+        let paths: [&[i32]; 5] = [
+            &[0, 3, 3, 3, 5, 5, 5, 2],
+            &[6, 7, 3, 5, 8, 7, 8, 7, 8, 9, 6, 9],
+            &[7, 8, 7, 8, 9, 0, 3, 5, 2],
+            &[6, 7, 8, 7],
+            // invalid at 1, resynchronized at 6
+            &[6, 7, 8, 7, 1, 8, 9, 6, 7, 8, 7, 8, 9],
+        ];
+        let policies = [ErrorPolicy::FullPath, ErrorPolicy::EmptyPath, ErrorPolicy::LongestValidPrefix, ErrorPolicy::Resync];
+        for policy in policies {
+            let reducer = automaton_reducer(policy);
+            for path in paths {
+                for part in 1..4 {
+                    let mut session = reducer.begin().unwrap();
+                    for blocks in path.chunks(part) {
+                        session.feed(blocks).unwrap();
+                    }
+                    assert_eq!(session.finish(), reducer.reduce(path, 0), "{:?} {:?}", policy, path);
+                }
+            }
+        }
+        let fail_reducer = automaton_reducer(ErrorPolicy::Fail);
+        let mut session = fail_reducer.begin().unwrap();
+        session.feed(&[6, 7]).unwrap();
+        assert!(matches!(session.feed(&[9, 6]), Err(Error::InvalidPath(_))));
+        assert!(session.feed(&[9]).is_err() && session.finish().is_err());
+        assert!(matches!(simple_reducer(1).begin(), Err(Error::InvalidArgument(_))));
+        assert!(matches!(reducer(ErrorPolicy::Fail, None).begin(), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn batches_are_reduced_in_parallel() {
+        let paths: [&[i32]; 4] = [&[0, 3, 3, 3, 5, 5, 5, 2], &[6, 7, 8, 7, 8, 9], &[6, 7, 9], &[7, 8, 7, 8]];
+        for threads in [0, 3] {
+            let options = ReducerOptions { threads, on_error: Some(ErrorPolicy::Fail), ..ReducerOptions::new(1) };
+            let reducer = PathReducer::from_cfgs_with_options(cfgs(), options).unwrap();
+            let expected: Vec<_> = paths.iter().map(|path| reducer.reduce(path, 0)).collect();
+            assert_eq!(reducer.reduce_batch(&paths, 0), expected);
+        }
+    }
+
+    #[test]
+    fn reduction_stats() {
+        let loops = [6, 7, 8, 7, 8, 7, 8, 9];
+        let recursion = [0, 3, 3, 3, 5, 5, 5, 2];
+        // every mode keeps the first iteration of `7 8`
+        for mode in [ReductionMode::Regex, ReductionMode::Simple, ReductionMode::Automaton] {
+            let options = ReducerOptions { mode, ..ReducerOptions::new(1) };
+            let reducer = PathReducer::from_cfgs_with_options(cfgs(), options).unwrap();
+            let (reduced, stats) = reducer.reduce_with_stats(&loops, 2).unwrap();
+            assert_eq!(reduced, vec![6, 7, 8, 9], "{:?}", mode);
+            assert_eq!(Ok(reduced), reducer.reduce(&loops, 2));
+            let expected = ReductionStats { path_len: 8, reduced_len: 4, dropped_iterations: 2, ..ReductionStats::default() };
+            assert_eq!(stats, expected, "{:?}", mode);
+            assert_eq!(stats.removed_blocks(), 4);
+            let (_, stats) = reducer.reduce_with_stats(&recursion, 0).unwrap();
+            let expected = ReductionStats { path_len: 8, reduced_len: 4, dropped_calls: 1, ..ReductionStats::default() };
+            assert_eq!(stats, expected, "{:?}", mode);
+        }
+        let invalid = [6, 7, 8, 1, 4, 6, 7, 8, 7, 8, 7, 8, 9];
+        let (reduced, stats) = reducer(ErrorPolicy::Resync, None).reduce_with_stats(&invalid, 2).unwrap();
+        assert_eq!(Ok(reduced), reducer(ErrorPolicy::Resync, None).reduce(&invalid, 2));
+        assert_eq!((stats.dropped_iterations, stats.recovery), (2, Some(ErrorPolicy::Resync)));
+        assert!(reducer(ErrorPolicy::Fail, None).reduce_with_stats(&[6, 8], 2).is_err());
+    }
+
+    #[test]
+    fn loop_and_recursion_bounds() {
+        let loops = [6, 7, 8, 7, 8, 7, 8, 9];
+        let recursion = [0, 3, 3, 3, 5, 5, 5, 2];
+        let function_k = |function, loop_k, recursion_k| FunctionK { function, loop_k, recursion_k };
+        for mode in [ReductionMode::Regex, ReductionMode::Simple, ReductionMode::Automaton] {
+            let reducer = |options| PathReducer::from_program_with_options(builder().program().unwrap(), options).unwrap();
+            let k1 = reducer(ReducerOptions { mode, ..ReducerOptions::new(1) });
+            let k2 = reducer(ReducerOptions { mode, ..ReducerOptions::new(2) });
+            let loop_k = reducer(ReducerOptions { mode, loop_k: Some(2), ..ReducerOptions::new(1) });
+            assert_eq!(loop_k.reduce(&loops, 2), k2.reduce(&loops, 2));
+            assert_eq!(loop_k.reduce(&recursion, 0), k1.reduce(&recursion, 0));
+            let recursion_k = reducer(ReducerOptions { mode, recursion_k: Some(2), ..ReducerOptions::new(1) });
+            assert_eq!(recursion_k.reduce(&loops, 2), k1.reduce(&loops, 2));
+            assert_eq!(recursion_k.reduce(&recursion, 0), k2.reduce(&recursion, 0));
+            let function_k = vec![
+                function_k(FunctionRef::Name("g".to_string()), Some(2), None),
+                function_k(FunctionRef::Name("f".to_string()), Some(2), Some(2)),
+                function_k(FunctionRef::Id(1), None, Some(0)),
+            ];
+            let functions = reducer(ReducerOptions { mode, function_k, ..ReducerOptions::new(1) });
+            assert_eq!(functions.reduce(&loops, 2), k2.reduce(&loops, 2));
+            // the calls of `f` are dropped
+            assert_eq!(functions.reduce(&recursion, 0), Ok(vec![0, 2]));
+            assert_eq!(functions.bounds(&1), Bounds { loops: 2, recursion: 0 });
+            assert_eq!(functions.bounds(&0), Bounds { loops: 1, recursion: 1 });
+        }
+        let named = ReducerOptions { function_k: vec![function_k(FunctionRef::Name("f".to_string()), None, Some(2))], ..ReducerOptions::new(1) };
+        assert!(matches!(PathReducer::from_cfgs_with_options(cfgs(), named), Err(Error::InvalidArgument(_))));
+        let unknown = ReducerOptions { function_k: vec![function_k(FunctionRef::Id(3), None, Some(2))], ..ReducerOptions::new(1) };
+        assert!(PathReducer::from_cfgs_with_options(cfgs(), unknown).is_err());
+    }
+
+    #[test]
+    fn error_policies() {
+        let invalid = [6, 7, 8, 7, 1, 9];
+        let prefix = reducer(ErrorPolicy::Fail, None).reduce(&invalid[..4], 2).unwrap();
+        assert!(matches!(reducer(ErrorPolicy::Fail, None).reduce(&invalid, 2), Err(Error::InvalidPath(_))));
+        assert_eq!(reducer(ErrorPolicy::FullPath, None).reduce(&invalid, 2), Ok(invalid.to_vec()));
+        assert_eq!(reducer(ErrorPolicy::EmptyPath, None).reduce(&invalid, 2), Ok(vec![]));
+        assert_eq!(reducer(ErrorPolicy::LongestValidPrefix, None).reduce(&invalid, 2), Ok(prefix));
+    }
+
+    #[test]
+    fn resync_after_invalid_blocks() {
+        let reducer = reducer(ErrorPolicy::Resync, None);
+        let valid = [6, 7, 8, 7, 8, 9];
+        let mut reduced = reducer.reduce(&valid[..3], 2).unwrap();
+        reduced.extend(reducer.reduce(&valid, 2).unwrap());
+        // unexpected blocks 1 and 4 before the next call to `g`
+        let invalid = [6, 7, 8, 1, 4, 6, 7, 8, 7, 8, 9];
+        assert_eq!(reducer.reduce(&invalid, 2), Ok(reduced));
+        let invalid = [6, 7, 3, 5, 1];
+        let mut expected = reducer.reduce(&valid[..2], 2).unwrap();
+        expected.extend([3, 5]);
+        assert_eq!(reducer.reduce(&invalid, 2), Ok(expected));
+        // every call is cut short, and reduction resumes after each of them
+        let invalid = [6, 7, 8, 7, 1].repeat(1000);
+        let expected = reducer.reduce(&[6, 7, 8, 7], 2).unwrap().repeat(1000);
+        assert_eq!(reducer.reduce(&invalid, 2), Ok(expected));
+        // a parse failing at the first block drops it, rather than starting from it again
+        let (mut reduced, mut dropped) = (Vec::new(), Dropped::default());
+        reducer.resync_reduce(&[6, 6, 7, 8, 9], 0, &mut reduced, &mut dropped).unwrap();
+        assert_eq!(Ok(reduced), reducer.reduce(&[6, 7, 8, 9], 2));
+    }
+
+    #[test]
+    fn invalid_paths_are_reported() {
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = {
+            let reported = reported.clone();
+            DiagnosticSink::new(move |err| reported.lock().unwrap().push(err.clone()))
+        };
+        let reducer = reducer(ErrorPolicy::EmptyPath, Some(sink));
+        assert_eq!(reducer.reduce(&[6, 9], 2), Ok(vec![6, 9]));
+        assert_eq!(reducer.reduce(&[6, 8], 2), Ok(vec![]));
+        let reported = reported.lock().unwrap();
+        assert_eq!(reported.len(), 1);
+        assert!(matches!(reported[0], Error::InvalidPath(_)));
+    }
+
+    #[test]
+    fn paths_starting_mid_function() {
+        let path = [8, 7, 8, 7, 8, 9, 0, 3, 5, 2];
+        let expected = vec![8, 9, 0, 3, 5, 2];
+        assert_eq!(reducer(ErrorPolicy::Fail, None).reduce(&path, 2), Ok(expected.clone()));
+        assert_eq!(simple_reducer(1).reduce(&path, 2), Ok(expected));
+        assert_eq!(reducer(ErrorPolicy::Fail, None).reduce(&[5, 2], 0), Ok(vec![5, 2]));
+    }
+
+    #[test]
+    fn functions_that_dont_return() {
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 2);
+        // the exit block of `serve` is unreachable
+        let serve = builder.add_function("serve", 10, 13);
+        builder.add_function("f", 20, 21);
+        for block_id in [0, 1, 2, 10, 11, 12, 13, 20, 21, 22] {
+            builder.add_block(block_id);
+        }
+        builder.set_call(1, serve).add_edge(0, 1).add_edge(1, 2);
+        builder.add_edge(10, 11).add_edge(11, 12).add_edge(12, 11);
+        builder.add_edge(20, 21).add_edge(20, 22).add_edge(22, 22);
+        let reducer = PathReducer::from_cfgs(builder.build().unwrap(), 1).unwrap();
+        let path = [0, 10, 11, 12, 11, 12, 11, 12, 11, 12];
+        assert_eq!(reducer.reduce(&path, 0), reducer.reduce(&path[..6], 0));
+        assert_eq!(reducer.reduce(&[20, 22, 22, 22, 22], 2), reducer.reduce(&[20, 22, 22], 2));
+        assert_eq!(reducer.reduce(&[20, 21], 2), Ok(vec![20, 21]));
+    }
+
+    #[test]
+    fn no_return_after_a_function_that_doesnt_return() {
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 2);
+        let serve = builder.add_function("serve", 10, 13);
+        // `f` aborts at 43
+        let f = builder.add_function("f", 40, 41);
+        for block_id in [0, 1, 2, 3, 10, 11, 12, 13, 40, 41, 43] {
+            builder.add_block(block_id);
+        }
+        builder.set_call(1, serve).set_call(3, f).add_edge(0, 1).add_edge(1, 2).add_edge(0, 3).add_edge(3, 2);
+        builder.add_edge(10, 11).add_edge(11, 12).add_edge(12, 11);
+        builder.add_edge(40, 41).add_edge(40, 43);
+        for mode in [ReductionMode::Regex, ReductionMode::Automaton] {
+            let options = ReducerOptions { mode, ..ReducerOptions::new(1) };
+            let reducer = PathReducer::from_cfgs_with_options(builder.clone().build().unwrap(), options).unwrap();
+            assert_eq!(reducer.reduce(&[0, 10, 11, 12], 0), Ok(vec![0, 10, 11, 12]));
+            assert!(reducer.reduce(&[0, 10, 11, 12, 2], 0).is_err());
+            assert!(reducer.reduce(&[0, 10, 11, 12, 11, 2], 0).is_err());
+            assert_eq!(reducer.reduce(&[0, 40, 43], 0), Ok(vec![0, 40, 43]));
+            assert!(reducer.reduce(&[0, 40, 43, 2], 0).is_err());
+            assert_eq!(reducer.reduce(&[0, 40, 41, 2], 0), Ok(vec![0, 40, 41, 2]));
+        }
+    }
+
+    #[test]
+    fn declared_and_extra_exits() {
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 2);
+        // `f` returns at 41, which has successors, and at 44, but not at 43, e.g. a call to `abort`
+        let f = builder.add_function("f", 40, 41);
+        builder.add_exit(f, 44);
+        for block_id in [0, 1, 2, 40, 41, 42, 43, 44] {
+            builder.add_block(block_id);
+        }
+        builder.set_call(1, f).add_edge(0, 1).add_edge(1, 2);
+        builder.add_edge(40, 41).add_edge(41, 42).add_edge(42, 41).add_edge(40, 43).add_edge(40, 44);
+        let reducer = PathReducer::from_cfgs(builder.build().unwrap(), 1).unwrap();
+        assert_eq!(reducer.reduce(&[0, 40, 41, 2], 0), Ok(vec![0, 40, 41, 2]));
+        assert!(reducer.reduce(&[0, 40, 41, 42, 41, 2], 0).is_ok());
+        assert_eq!(reducer.reduce(&[0, 40, 44, 2], 0), Ok(vec![0, 40, 44, 2]));
+        assert!(reducer.reduce(&[0, 40, 41, 42, 2], 0).is_err());
+        // the paths aborting in `f` are prefixes
+        assert_eq!(reducer.reduce(&[0, 40, 43], 0), Ok(vec![0, 40, 43]));
+    }
+
+    #[test]
+    fn extra_exit_with_successors() {
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 2);
+        // `f` returns at 44, and at 41 unless it calls itself in a loop at 42
+        let f = builder.add_function("f", 40, 44);
+        builder.add_exit(f, 41);
+        for block_id in [0, 1, 2, 40, 41, 42, 44] {
+            builder.add_block(block_id);
+        }
+        builder.set_call(1, f).add_edge(0, 1).add_edge(1, 2);
+        builder.set_call(42, f).add_edge(40, 41).add_edge(40, 44).add_edge(41, 42).add_edge(42, 41);
+        for mode in [ReductionMode::Regex, ReductionMode::Simple, ReductionMode::Automaton] {
+            let options = ReducerOptions { mode, loop_k: Some(2), ..ReducerOptions::new(1) };
+            let reducer = PathReducer::from_cfgs_with_options(builder.clone().build().unwrap(), options).unwrap();
+            assert_eq!(reducer.reduce(&[0, 40, 41, 2], 0), Ok(vec![0, 40, 41, 2]), "{:?}", mode);
+            // the recursive call is dropped, since the call of `f` goes on after 41
+            assert_eq!(reducer.reduce(&[0, 40, 41, 40, 44, 41, 2], 0), Ok(vec![0, 40, 41, 41, 2]), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn loop_of_external_calls() {
+        // `main` loops on an external call at 1 before returning at 2
+        let mut builder = CfgBuilder::new();
+        builder.add_function("main", 0, 2);
+        builder.add_block(0).add_block(1).add_block(2);
+        builder.set_extern_call(1).add_edge(0, 1).add_edge(1, 1).add_edge(1, 2);
+        let reducer = PathReducer::from_cfgs(builder.build().unwrap(), 1).unwrap();
+        assert_eq!(reducer.reduce(&[0, 2], 0), Ok(vec![0, 2]));
+    }
+}
@@ -0,0 +1,403 @@
+//! Owned CFGs of a whole program, and the CFG file formats
+//!
+//! A CFG file is either JSON or a compact binary form.
+//! The JSON form looks like
+//!
+//! ```json
+//! {
+//!   "functions": [
+//!     { "name": "main", "entry": 0, "exit": 2 },
+//!     { "name": "f", "entry": 3, "exit": 3 }
+//!   ],
+//!   "blocks": {
+//!     "0": { "successors": [1] },
+//!     "1": { "calls": 1, "successors": [2] },
+//!     "2": {},
+//!     "3": {}
+//!   }
+//! }
+//! ```
+//!
+//! The id of a function is its index in `functions`.
+//! `calls` is the id of the function called by the block,
+//! `-1` (the default) if the block is not a call block, `-2` for calls to external functions,
+//! and `-3` for indirect calls, whose possible callees are listed in `callees`, e.g.
+//! `{ "calls": -3, "callees": [1, 4], "successors": [2] }`.
+//!
+//! A function returns at its `exit` block, and at the blocks listed in its optional `extra_exits`,
+//! e.g. early returns: `{ "name": "g", "entry": 5, "exit": 9, "extra_exits": [7] }`.
+//! Blocks without successors that are not exits don't return.
+//!
+//! The binary form, all integers little endian:
+//!
+//! ```text
+//! magic "PCFG" | version u32
+//! function count u32 | (name length u32, name bytes, entry i32, exit i32,
+//!                       extra exit count u32, extra exit ids i32 ...) ...
+//! block count u32 | (block id i32, calls i32, callee count u32, callee ids i32 ...,
+//!                    successor count u32, successor ids i32 ...) ...
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use petgraph::graph::{Graph, NodeIndex};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bytes::{put_i32, put_u32, Reader},
+    convert::Node,
+    error::{Error, Result},
+    extern_cfg::{BlockID, FunID},
+    intern_cfg::CFG,
+    validate::{self, Diagnostic},
+};
+
+/// `Block::calls` of blocks that are not call blocks
+pub const NOT_A_CALL: FunID = -1;
+/// `Block::calls` of blocks calling external functions
+pub const EXTERN_CALL: FunID = -2;
+/// `Block::calls` of blocks calling one of `Block::callees`
+pub const INDIRECT_CALL: FunID = -3;
+
+const MAGIC: &[u8; 4] = b"PCFG";
+const VERSION: u32 = 1;
+
+/// CFGs of all functions of a program
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Program {
+    /// Functions indexed by `FunID`
+    pub functions: Vec<Function>,
+    pub blocks: BTreeMap<BlockID, Block>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Function {
+    #[serde(default)]
+    pub name: String,
+    /// ID of the entry block
+    pub entry: BlockID,
+    /// ID of the exit block
+    pub exit: BlockID,
+    /// IDs of other blocks where the function returns
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_exits: Vec<BlockID>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Block {
+    /// ID of the function called by the block,
+    /// `NOT_A_CALL`, `EXTERN_CALL` or `INDIRECT_CALL`
+    #[serde(default = "not_a_call")]
+    pub calls: FunID,
+    /// Possible callees of an indirect call.
+    /// If empty, the callees are unknown and parsed as they appear in paths.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub callees: Vec<FunID>,
+    #[serde(default)]
+    pub successors: Vec<BlockID>,
+}
+
+fn not_a_call() -> FunID {
+    NOT_A_CALL
+}
+
+impl Default for Block {
+    fn default() -> Self {
+        Self {
+            calls: NOT_A_CALL,
+            callees: Vec::new(),
+            successors: Vec::new(),
+        }
+    }
+}
+
+impl Program {
+    /// Reads a CFG file in either format
+    pub fn load(file_path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = fs::read(file_path).map_err(|err| Error::Io(err.to_string()))?;
+        if bytes.starts_with(MAGIC) {
+            Self::from_binary(&bytes)
+        } else {
+            Self::from_json(&bytes)
+        }
+    }
+
+    /// Writes a CFG file, in the binary format if `binary` holds, and in JSON otherwise
+    pub fn save(&self, file_path: impl AsRef<Path>, binary: bool) -> Result<()> {
+        let bytes = if binary { self.to_binary() } else { self.to_json()? };
+        fs::write(file_path, bytes).map_err(|err| Error::Io(err.to_string()))
+    }
+
+    pub fn from_json(bytes: &[u8]) -> Result<Self> {
+        serde_json::from_slice(bytes).map_err(|err| Error::InvalidCfgFile(err.to_string()))
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec_pretty(self).map_err(|err| Error::InvalidCfgFile(err.to_string()))
+    }
+
+    pub fn from_binary(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes, Error::InvalidCfgFile);
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(reader.invalid("not a binary CFG file"));
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(reader.invalid(format!(
+                "unsupported version {}, expected {}",
+                version, VERSION
+            )));
+        }
+        let mut functions = Vec::new();
+        for _ in 0..reader.u32()? {
+            let name_len = reader.u32()?;
+            let name = String::from_utf8_lossy(reader.bytes(name_len as usize)?).into_owned();
+            let entry = reader.i32()?;
+            let exit = reader.i32()?;
+            let extra_exits = (0..reader.u32()?).map(|_| reader.i32()).collect::<Result<_>>()?;
+            functions.push(Function { name, entry, exit, extra_exits });
+        }
+        let mut blocks = BTreeMap::new();
+        for _ in 0..reader.u32()? {
+            let block_id = reader.i32()?;
+            let calls = reader.i32()?;
+            let callees = (0..reader.u32()?).map(|_| reader.i32()).collect::<Result<_>>()?;
+            let successors = (0..reader.u32()?)
+                .map(|_| reader.i32())
+                .collect::<Result<_>>()?;
+            blocks.insert(block_id, Block { calls, callees, successors });
+        }
+        if !reader.is_empty() {
+            return Err(reader.invalid("trailing bytes"));
+        }
+        Ok(Self { functions, blocks })
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        put_u32(&mut buf, VERSION);
+        put_u32(&mut buf, self.functions.len() as u32);
+        for function in &self.functions {
+            put_u32(&mut buf, function.name.len() as u32);
+            buf.extend_from_slice(function.name.as_bytes());
+            put_i32(&mut buf, function.entry);
+            put_i32(&mut buf, function.exit);
+            put_u32(&mut buf, function.extra_exits.len() as u32);
+            for extra_exit in &function.extra_exits {
+                put_i32(&mut buf, *extra_exit);
+            }
+        }
+        put_u32(&mut buf, self.blocks.len() as u32);
+        for (block_id, block) in &self.blocks {
+            put_i32(&mut buf, *block_id);
+            put_i32(&mut buf, block.calls);
+            put_u32(&mut buf, block.callees.len() as u32);
+            for callee in &block.callees {
+                put_i32(&mut buf, *callee);
+            }
+            put_u32(&mut buf, block.successors.len() as u32);
+            for succ in &block.successors {
+                put_i32(&mut buf, *succ);
+            }
+        }
+        buf
+    }
+
+    /// Returns all the problems found in the CFGs, see `validate::validate`
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        validate::validate(self)
+    }
+
+    /// Returns the control flow graphs of all functions
+    pub fn to_cfgs(&self) -> Result<BTreeMap<FunID, CFG<BlockID, FunID>>> {
+        self.functions
+            .iter()
+            .enumerate()
+            .map(|(fun_id, function)| {
+                Ok((fun_id as FunID, self.get_cfg_with_root(function)?))
+            })
+            .collect()
+    }
+
+    /// Returns the entry of the given block
+    fn get_block(&self, block_id: BlockID) -> Result<&Block> {
+        self.blocks
+            .get(&block_id)
+            .ok_or_else(|| Error::UnknownBlock(block_id.to_string()))
+    }
+
+    /// Returns the control flow graph of `function`, made of the blocks reachable from its entry
+    fn get_cfg_with_root(&self, function: &Function) -> Result<CFG<BlockID, FunID>> {
+        let entry = function.entry;
+        let mut graph = Graph::new();
+        let mut block_id_to_node_idx = BTreeMap::new();
+        let reachable = DFS::new(self, entry).collect::<Result<Vec<_>>>()?;
+        // add node to graph for each block
+        for &block_id in &reachable {
+            let block = self.get_block(block_id)?;
+            let node_weight = match block.calls {
+                NOT_A_CALL => Node::Literal(block_id),
+                EXTERN_CALL => Node::Extern,
+                INDIRECT_CALL => Node::Vars(block.callees.clone()),
+                fun_id => Node::Var(fun_id),
+            };
+            let node_idx = graph.add_node(node_weight);
+            let no_dup = block_id_to_node_idx.insert(block_id, node_idx).is_none();
+            debug_assert!(no_dup, "duplicate block id{}", block_id);
+        }
+        // add edges to the graph
+        for &block_id in &reachable {
+            let node_idx = block_id_to_node_idx[&block_id];
+            let successors: BTreeSet<_> = self.get_block(block_id)?.successors.iter().collect();
+            for succ_block in successors {
+                let succ_node_idx = block_id_to_node_idx[succ_block];
+                graph.add_edge(node_idx, succ_node_idx, ());
+            }
+        }
+        Ok(CFG {
+            entry: block_id_to_node_idx[&entry],
+            exit: block_id_to_node_idx.get(&function.exit).copied().unwrap_or_else(NodeIndex::end),
+            // unreachable extra exits are left out
+            extra_exits: function
+                .extra_exits
+                .iter()
+                .filter_map(|block_id| block_id_to_node_idx.get(block_id).copied())
+                .collect(),
+            graph,
+        })
+    }
+}
+
+/// State for DFS traversal of the CFG
+struct DFS<'a> {
+    to_visit: Vec<BlockID>,
+    visited: BTreeSet<BlockID>,
+    program: &'a Program,
+}
+
+impl<'a> DFS<'a> {
+    /// Traverse the CFG with root `entry`
+    fn new(program: &'a Program, entry: BlockID) -> Self {
+        Self {
+            to_visit: vec![entry],
+            visited: BTreeSet::new(),
+            program,
+        }
+    }
+
+    /// Gets the next scheduled unvisited block
+    fn get_next_unvisited(&mut self) -> Option<BlockID> {
+        while let Some(next_scheduled) = self.to_visit.pop() {
+            if !self.visited.contains(&next_scheduled) {
+                return Some(next_scheduled);
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for DFS<'_> {
+    type Item = Result<BlockID>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next_unvisited = self.get_next_unvisited()?;
+        self.visited.insert(next_unvisited);
+        let block = match self.program.get_block(next_unvisited) {
+            Ok(block) => block,
+            Err(err) => return Some(Err(err)),
+        };
+        self.to_visit.extend_from_slice(&block.successors);
+        Some(Ok(next_unvisited))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_reduction::PathReducer;
+
+    fn program() -> Program {
+        Program::from_json(
+            br#"{
+                "functions": [
+                    { "name": "main", "entry": 0, "exit": 2 },
+                    { "name": "f", "entry": 3, "exit": 3 }
+                ],
+                "blocks": {
+                    "0": { "successors": [1] },
+                    "1": { "calls": 1, "successors": [2] },
+                    "2": {},
+                    "3": {}
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn json_format() {
+        let program = program();
+        assert_eq!(program.functions[1].name, "f");
+        assert_eq!(program.blocks[&1].calls, 1);
+        assert_eq!(program.blocks[&2], Block::default());
+        let json = program.to_json().unwrap();
+        assert_eq!(Program::from_json(&json).unwrap(), program);
+    }
+
+    #[test]
+    fn binary_format() {
+        let program = program();
+        let bytes = program.to_binary();
+        assert_eq!(Program::from_binary(&bytes).unwrap(), program);
+        assert!(matches!(
+            Program::from_binary(&bytes[..bytes.len() - 1]),
+            Err(Error::InvalidCfgFile(_))
+        ));
+    }
+
+    #[test]
+    fn reduce_from_file() {
+        let file_path = std::env::temp_dir().join(format!("path-reduction-{}.cfg", std::process::id()));
+        program().save(&file_path, true).unwrap();
+        let reducer = PathReducer::from_cfg_file(&file_path, 1).unwrap();
+        fs::remove_file(&file_path).unwrap();
+        assert_eq!(reducer.reduce(&[0, 3, 2], 0), Ok(vec![0, 3, 2]));
+    }
+
+    #[test]
+    fn indirect_calls() {
+        let mut program = program();
+        program.functions.push(Function { name: "g".to_string(), entry: 4, exit: 4, extra_exits: Vec::new() });
+        program.blocks.insert(4, Block::default());
+        let call = program.blocks.get_mut(&1).unwrap();
+        call.calls = INDIRECT_CALL;
+        call.callees = vec![1, 2];
+        let bytes = program.to_binary();
+        assert_eq!(Program::from_binary(&bytes).unwrap(), program);
+        let json = program.to_json().unwrap();
+        assert_eq!(Program::from_json(&json).unwrap(), program);
+        let reducer = PathReducer::from_program(&program, 1).unwrap();
+        assert_eq!(reducer.reduce(&[0, 3, 2], 0), Ok(vec![0, 3, 2]));
+        assert_eq!(reducer.reduce(&[0, 4, 2], 0), Ok(vec![0, 4, 2]));
+        assert!(reducer.reduce(&[0, 2], 0).is_err());
+    }
+
+    #[test]
+    fn unsupported_binary_version() {
+        let mut bytes = program().to_binary();
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&2u32.to_le_bytes());
+        assert!(Program::from_binary(&bytes).is_err());
+    }
+
+    #[test]
+    fn dangling_successor() {
+        let mut program = program();
+        program.blocks.get_mut(&0).unwrap().successors.push(9);
+        assert_eq!(program.to_cfgs().err(), Some(Error::UnknownBlock("9".to_string())));
+    }
+}
@@ -1,40 +1,75 @@
 //! Regular expressions
 
-use core::panic;
 use std::{
     collections::BTreeMap,
     fmt::Debug,
+    ops::AddAssign,
     sync::Arc,
 };
 
+use crate::error::Error;
+
 /// Regular expressions over alphabet set `Alphabet`, and variable set `Name`
 /// a variable refers to an external regular expression
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum RegExp<Alphabet, Name> {
     Epsilon,
+    /// A call, matches a path of the regular expression named `Name`
     Var(Name),
     Literal(Alphabet),
+    /// A sequence of literals
     Literals(Vec<Alphabet>),
     Concat(Arc<RegExp<Alphabet, Name>>, Arc<RegExp<Alphabet, Name>>),
+    /// Concatenation of all the regular expressions
     Seq(Vec<Arc<RegExp<Alphabet, Name>>>),
     Alter(Arc<RegExp<Alphabet, Name>>, Arc<RegExp<Alphabet, Name>>),
     Star(Arc<RegExp<Alphabet, Name>>),
 }
 
+/// Failures of parsing
 #[derive(Debug, Clone)]
 pub enum ParseErr<Alphabet> {
+    /// The path ends in the middle of the regular expression,
+    /// holds the result of parsing the path
     Abort(Val<Alphabet>),
-    Invalid(String),
+    /// The path doesn't match the regular expression,
+    /// holds the error and the length of the rest of the path from the furthest block that can't be read
+    Invalid(String, usize),
+    /// Errors that are not caused by the path, e.g. an undefined variable
+    Fatal(Error),
+}
+
+pub(crate) type ParseResult<'a, Alphabet, T> = Result<(T, &'a [Alphabet]), ParseErr<Alphabet>>;
+
+type Memo<'a, Alphabet, Name> = BTreeMap<(Name, usize), ParseResult<'a, Alphabet, Val<Alphabet>>>;
+
+/// State of `RegExp::parse_bounded`
+struct ParseState<'a, Alphabet, Name> {
+    /// The number of calls in progress of each function
+    stack: BTreeMap<Name, usize>,
+    memo: Memo<'a, Alphabet, Name>,
+    /// The length of the shortest rest of the path at which a parse failed.
+    /// The path up to there is the longest prefix that parses, as the parses that read it would then be aborted.
+    furthest: usize,
+}
+
+impl<'a, Alphabet: Debug, Name> ParseState<'a, Alphabet, Name> {
+    /// Fails to read the first block of `s`, where `expected` is expected
+    fn invalid(&mut self, s: &'a [Alphabet], expected: &Alphabet) -> ParseErr<Alphabet> {
+        self.furthest = self.furthest.min(s.len());
+        ParseErr::Invalid(format!("expected {:?} found {:?}", expected, &s[0]), s.len())
+    }
 }
 
 impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<Alphabet, Name> {
+    /// Returns the number of nodes of the expression, counting shared sub-expressions once per use
     pub fn size(&self) -> usize {
         match self {
             RegExp::Epsilon | RegExp::Var(_) | RegExp::Literal(_) => 1,
-            RegExp::Literals(vs) => 1,
-            RegExp::Concat(r1, r2) => 1 + r1.size().max(r2.size()),
-            RegExp::Seq(rs) => rs.iter().map(|r| r.size()).max().unwrap_or_default(),
-            RegExp::Alter(r1, r2) => 1 + r1.size().max(r2.size()),
+            RegExp::Literals(lits) => lits.len(),
+            RegExp::Concat(r1, r2) => 1 + r1.size() + r2.size(),
+            RegExp::Seq(rs) => 1 + rs.iter().map(|r| r.size()).sum::<usize>(),
+            RegExp::Alter(r1, r2) => 1 + r1.size() + r2.size(),
             RegExp::Star(r) => 1 + r.size(),
         }
     }
@@ -52,7 +87,16 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
                 y.debug();
                 println!(")");
             }
-            RegExp::Seq(_) => todo!(),
+            RegExp::Seq(xs) => {
+                println!("Seq(");
+                for (i, x) in xs.iter().enumerate() {
+                    if i > 0 {
+                        println!(",");
+                    }
+                    x.debug();
+                }
+                println!(")");
+            }
             RegExp::Alter(x, y) => {
                 println!("Alter(");
                 x.debug();
@@ -68,7 +112,7 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
         }
     }
 
-    pub fn first(&self) -> Alphabet {
+    pub fn first(&self) -> Result<Alphabet, Error> {
         // match self {
         //     RegExp::Epsilon => panic!("first: start with epsilon"),
         //     RegExp::Var(_) => panic!("first: start with var"),
@@ -87,41 +131,46 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
         //     },
         //     RegExp::Star(re) => re.first(),
         // }
-        self.first_opt().unwrap()
+        self.first_opt()?
+            .ok_or_else(|| Error::NoFirstBlock(format!("{:?}", self)))
     }
 
-    pub fn first_opt(&self) -> Option<Alphabet> {
+    pub fn first_opt(&self) -> Result<Option<Alphabet>, Error> {
         match self {
-            RegExp::Epsilon => None,
-            RegExp::Var(x) => panic!("first: start with var {:?}", x),
-            RegExp::Literal(c) => Some(c.clone()),
-            RegExp::Literals(cs) => Some(cs[0].clone()),
+            RegExp::Epsilon => Ok(None),
+            RegExp::Var(x) => Err(Error::NoFirstBlock(format!("starts with var {:?}", x))),
+            RegExp::Literal(c) => Ok(Some(c.clone())),
+            RegExp::Literals(cs) => Ok(cs.first().cloned()),
             RegExp::Concat(re1, re2) => {
-                if let Some(c) = re1.first_opt() {
-                    Some(c)
+                if let Some(c) = re1.first_opt()? {
+                    Ok(Some(c))
                 } else {
                     re2.first_opt()
                 }
             }
-            RegExp::Seq(res) => todo!(),
+            RegExp::Seq(res) => {
+                for re in res {
+                    if let Some(c) = re.first_opt()? {
+                        return Ok(Some(c));
+                    }
+                }
+                Ok(None)
+            }
             RegExp::Alter(re1, re2) => {
-                let f1 = re1.first_opt();
+                let f1 = re1.first_opt()?;
                 if f1.is_some() {
-                    return f1;
+                    return Ok(f1);
                 }
-                let f2 = re2.first_opt();
-                f2
+                re2.first_opt()
             }
             RegExp::Star(re) => re.first_opt(),
         }
     }
 
-    #[allow(dead_code)]
     pub fn var(x: Name) -> Self {
         Self::Var(x)
     }
 
-    #[allow(dead_code)]
     pub fn literal(c: Alphabet) -> Self {
         Self::Literal(c)
     }
@@ -168,11 +217,13 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
     }
 
     pub fn alter(r1: Arc<Self>, r2: Arc<Self>) -> Arc<Self> {
-        assert!(!matches!(r1.as_ref(), RegExp::Epsilon));
-        assert!(!matches!(r2.as_ref(), RegExp::Epsilon));
         if r1 == r2 {
             return r1;
         }
+        // `Epsilon` goes last, so that the other alternative is tried first when parsing
+        if matches!(r1.as_ref(), RegExp::Epsilon) {
+            return Arc::new(RegExp::Alter(r2, r1));
+        }
         // let (prefix, r1, r2) = Self::alter_prefix_acc(Arc::new(RegExp::Epsilon), r1, r2);
         // RegExp::concat(prefix, Arc::new(RegExp::Alter(r1, r2)))
         // let (r1, r2, postfix) = Self::alter_post_acc(r1, r2, Arc::new(RegExp::Epsilon));
@@ -224,7 +275,7 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
         use RegExp::*;
         let epsilon = Arc::new(Epsilon);
         match (r1.as_ref(), r2.as_ref()) {
-            (r1_, r2_) if r1 == r2 => (epsilon.clone(), epsilon, RegExp::concat(r1, postfix)),
+            (_r1, _r2) if r1 == r2 => (epsilon.clone(), epsilon, RegExp::concat(r1, postfix)),
             (Concat(a, b), Concat(c, d)) => {
                 let (b_, d_, p1) = Self::alter_post_acc(b.clone(), d.clone(), postfix);
                 if matches!(b_.as_ref(), Epsilon) && matches!(d_.as_ref(), Epsilon) {
@@ -245,27 +296,22 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
         Self::Star(r)
     }
 
-    #[allow(dead_code)]
     pub fn parse_inf<'a>(
         &self,
         s: &'a [Alphabet],
         env: &BTreeMap<Name, RegExp<Alphabet, Name>>,
     ) -> Option<(Val<Alphabet>, &'a [Alphabet])> {
         match self {
-            RegExp::Epsilon => todo!(),
+            RegExp::Epsilon => Some((Val::Epsilon, s)),
             RegExp::Var(x) => {
-                let re = env.get(x).expect("name {x} doesn't exist in env");
+                let re = env.get(x)?;
                 re.parse_inf(s, env)
             }
             RegExp::Literal(c) => {
-                if s.is_empty() {
+                if s.is_empty() || c != &s[0] {
                     None
                 } else {
-                    if c == &s[0] {
-                        Some((Val::Literal(c.clone()), &s[1..]))
-                    } else {
-                        None
-                    }
+                    Some((Val::Literal(c.clone()), &s[1..]))
                 }
             }
             RegExp::Concat(r1, r2) => {
@@ -281,8 +327,23 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
                 let (vs, s1) = r.parse_star_inf(s, env);
                 Some((Val::Star(vs), s1))
             }
-            RegExp::Literals(_) => todo!(),
-            RegExp::Seq(_) => todo!(),
+            RegExp::Literals(lits) => {
+                if s.starts_with(lits) {
+                    Some((Val::Literals(lits.clone()), &s[lits.len()..]))
+                } else {
+                    None
+                }
+            }
+            RegExp::Seq(rs) => {
+                let mut vals = Vec::new();
+                let mut rest = s;
+                for r in rs {
+                    let (v, s1) = r.parse_inf(rest, env)?;
+                    vals.push(v);
+                    rest = s1;
+                }
+                Some((Val::Seq(vals), rest))
+            }
         }
     }
 
@@ -292,56 +353,68 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
         env: &BTreeMap<Name, RegExp<Alphabet, Name>>,
         firsts: &BTreeMap<Alphabet, Name>,
         k: usize,
-    ) -> Result<(Val<Alphabet>, &'a [Alphabet]), ParseErr<Alphabet>> {
-        let mut stack = BTreeMap::new();
-        let mut memo = BTreeMap::new();
-        self._parse_k(s, env, firsts, k, &mut stack, &mut memo)
+    ) -> ParseResult<'a, Alphabet, Val<Alphabet>> {
+        self.parse_bounded(s, env, firsts, &FunctionBounds::new(k))
     }
 
-    pub fn _parse_k<'a>(
+    /// Parses as `parse_k`, with the bounds of each function in `bounds`
+    pub fn parse_bounded<'a>(
         &self,
         s: &'a [Alphabet],
         env: &BTreeMap<Name, RegExp<Alphabet, Name>>,
         firsts: &BTreeMap<Alphabet, Name>,
-        k: usize,
-        stack: &mut BTreeMap<Name, usize>,
-        memo: &mut BTreeMap<
-            (Name, usize),
-            Result<(Val<Alphabet>, &'a [Alphabet]), ParseErr<Alphabet>>,
-        >,
-    ) -> Result<(Val<Alphabet>, &'a [Alphabet]), ParseErr<Alphabet>> {
-        let res = match self {
+        bounds: &FunctionBounds<Name>,
+    ) -> ParseResult<'a, Alphabet, Val<Alphabet>> {
+        // the memo holds parses of suffixes of `s`, so it can't outlive the call
+        let mut state = ParseState { stack: BTreeMap::new(), memo: BTreeMap::new(), furthest: s.len() };
+        match self._parse_k(s, env, firsts, (bounds, bounds.default), &mut state) {
+            Err(ParseErr::Invalid(msg, _)) => Err(ParseErr::Invalid(msg, state.furthest)),
+            res => res,
+        }
+    }
+
+    /// `k` holds the bounds of every function, and those of the function being parsed
+    fn _parse_k<'a>(
+        &self,
+        s: &'a [Alphabet],
+        env: &BTreeMap<Name, RegExp<Alphabet, Name>>,
+        firsts: &BTreeMap<Alphabet, Name>,
+        k: (&FunctionBounds<Name>, Bounds),
+        state: &mut ParseState<'a, Alphabet, Name>,
+    ) -> ParseResult<'a, Alphabet, Val<Alphabet>> {
+        match self {
             RegExp::Epsilon => Ok((Val::Star(Vec::new()), s)),
             RegExp::Var(x) => {
-                let re = env
-                    .get(x)
-                    .expect(&format!("name {:?} doesn't exist in env", x));
-                let nested_level = *stack.entry(x.clone()).or_default();
-                if nested_level == k {
-                    let res = if let Some(res) = memo.get(&(x.clone(), s.len())) {
+                let re = env.get(x).ok_or_else(|| {
+                    ParseErr::Fatal(Error::UnknownFunction(format!("{:?}", x)))
+                })?;
+                let callee_k = (k.0, k.0.of(x));
+                let nested_level = *state.stack.entry(x.clone()).or_default();
+                if nested_level == callee_k.1.recursion {
+                    let res = if let Some(res) = state.memo.get(&(x.clone(), s.len())) {
                         res.clone()
                     } else {
-                        let res = re._parse_k(s, env, firsts, k, stack, memo);
-                        memo.insert((x.clone(), s.len()), res.clone());
+                        let res = re._parse_k(s, env, firsts, callee_k, state);
+                        state.memo.insert((x.clone(), s.len()), res.clone());
                         res
                     };
-                    let res = match res {
-                        Ok((_, s)) => Ok((Val::Epsilon, s)),
-                        Err(ParseErr::Abort(_)) => Err(ParseErr::Abort(Val::Epsilon)),
-                        res @ Err(ParseErr::Invalid(_)) => res,
-                    };
-                    res
+                    let cut = Val::Dropped(Dropped { iterations: 0, calls: 1 });
+                    match res {
+                        Ok((_, s)) => Ok((cut, s)),
+                        Err(ParseErr::Abort(_)) => Err(ParseErr::Abort(cut)),
+                        res @ Err(_) => res,
+                    }
                 } else {
-                    *stack.get_mut(x).unwrap() += 1;
-                    let res = if let Some(res) = memo.get(&(x.clone(), s.len())) {
+                    *state.stack.get_mut(x).unwrap() += 1;
+                    let res = if let Some(res) = state.memo.get(&(x.clone(), s.len())) {
                         res.clone()
                     } else {
-                        let res = re._parse_k(s, env, firsts, k, stack, memo);
-                        memo.insert((x.clone(), s.len()), res.clone());
+                        let res = re._parse_k(s, env, firsts, callee_k, state);
+                        state.memo.insert((x.clone(), s.len()), res.clone());
                         res
                     };
-                    memo.remove(&(x.clone(), s.len()));
-                    *stack.get_mut(x).unwrap() -= 1;
+                    state.memo.remove(&(x.clone(), s.len()));
+                    *state.stack.get_mut(x).unwrap() -= 1;
                     res
                 }
             }
@@ -349,106 +422,82 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
                 // println!("literal {:?}", c);
                 if s.is_empty() {
                     Err(ParseErr::Abort(Val::Epsilon))
-                } else {
-                    if c == &s[0] {
-                        // println!("matched {:?}", c);
-                        // println!("matched so far: {:?}", matched);
-                        Ok((Val::Literal(c.clone()), &s[1..]))
-                    } else {
-                        if let Some(x) = firsts.get(&s[0]) {
-                            // println!("implicit call!");
-                            let re = RegExp::Var(x.clone());
-                            let (val, s1) = re._parse_k(s, env, firsts, k, stack, memo)?;
-                            let res = match RegExp::Literal(c.clone())
-                                ._parse_k(s1, env, firsts, k, stack, memo)
-                            {
-                                Ok((val2, s2)) => {
-                                    Ok((Val::Concat(Box::new(val), Box::new(val2)), s2))
-                                }
-                                Err(ParseErr::Abort(x)) => {
-                                    Err(ParseErr::Abort(Val::Concat(Box::new(val), Box::new(x))))
-                                }
-                                res @ Err(ParseErr::Invalid(_)) => res,
-                            };
-                            res
-                            // Err(ParseErr::Abort(Val::Epsilon))
-                            // res
-                            // match res {
-                            //     Ok((val, s)) => todo!(),
-                            //     Err(_) => todo!(),
-                            // }
-                        } else {
-                            // println!("expected {:?} found {:?} stack: {:?}", c, &s, &stack);
-                            // println!("no function starts with {:?}", &s[0]);
-                            // println!("expected {:?} found {:?}", c, &s[0]);
-                            // println!("firsts: {:?}", firsts);
-                            // Err(ParseErr::Invalid(format!("no function starts with {:?}", &s[0])))
-                            Err(ParseErr::Invalid(format!(
-                                "expected {:?} found {:?}",
-                                c, &s[0]
-                            )))
+                } else if c == &s[0] {
+                    // println!("matched {:?}", c);
+                    Ok((Val::Literal(c.clone()), &s[1..]))
+                } else if let Some(x) = firsts.get(&s[0]) {
+                    // println!("implicit call!");
+                    let re = RegExp::Var(x.clone());
+                    let (val, s1) = re._parse_k(s, env, firsts, k, state)?;
+                    match RegExp::Literal(c.clone())._parse_k(s1, env, firsts, k, state) {
+                        Ok((val2, s2)) => Ok((Val::Concat(Box::new(val), Box::new(val2)), s2)),
+                        Err(ParseErr::Abort(x)) => {
+                            Err(ParseErr::Abort(Val::Concat(Box::new(val), Box::new(x))))
                         }
+                        res @ Err(_) => res,
                     }
+                } else {
+                    // println!("expected {:?} found {:?} stack: {:?}", c, &s, &stack);
+                    // println!("firsts: {:?}", firsts);
+                    Err(state.invalid(s, c))
                 }
             }
             RegExp::Literals(lits) => {
-                let mut lit_vals = Vec::new();
+                // the values of the implicit calls, between runs of literals
+                let mut vals = Vec::new();
+                let mut run = Vec::new();
                 let mut rest = s;
-                for lit in lits {
+                let mut lits = lits.iter().peekable();
+                while let Some(&lit) = lits.peek() {
                     if rest.is_empty() {
-                        return Err(ParseErr::Abort(Val::Seq(lit_vals)));
-                    } else {
-                        if lit == &rest[0] {
-                            lit_vals.push(Val::Literal(lit.clone()));
-                            rest = &rest[1..];
-                        } else {
-                            if let Some(x) = firsts.get(&rest[0]) {
-                                let re = RegExp::Var(x.clone());
-                                let res = re._parse_k(s, env, firsts, k, stack, memo);
-                                match res {
-                                    Ok((val, rest_path)) => {
-                                        lit_vals.push(val);
-                                        rest = rest_path;
-                                    }
-                                    Err(ParseErr::Abort(v)) => {
-                                        lit_vals.push(v);
-                                        return Err(ParseErr::Abort(Val::Seq(lit_vals)));
-                                    }
-                                    res @ Err(ParseErr::Invalid(_)) => return res,
-                                }
-                            } else {
-                                // println!("expected {:?} found {:?} stack: {:?}", c, &s, &stack);
-                                // println!("expected {:?} found {:?}", lit, &rest[0]);
-                                // println!("firsts: {:?}", firsts);
-                                return Err(ParseErr::Invalid(format!(
-                                    "expected {:?} found {:?}",
-                                    lit, &rest[0]
-                                )));
+                        push_run(&mut vals, &mut run);
+                        return Err(ParseErr::Abort(Val::Seq(vals)));
+                    } else if lit == &rest[0] {
+                        run.push(lit.clone());
+                        rest = &rest[1..];
+                        lits.next();
+                    } else if let Some(x) = firsts.get(&rest[0]) {
+                        // the same literal is expected after the call
+                        push_run(&mut vals, &mut run);
+                        let re = RegExp::Var(x.clone());
+                        match re._parse_k(rest, env, firsts, k, state) {
+                            Ok((val, rest_path)) => {
+                                vals.push(val);
+                                rest = rest_path;
+                            }
+                            Err(ParseErr::Abort(v)) => {
+                                vals.push(v);
+                                return Err(ParseErr::Abort(Val::Seq(vals)));
                             }
+                            res @ Err(_) => return res,
                         }
+                    } else {
+                        // println!("expected {:?} found {:?}", lit, &rest[0]);
+                        return Err(state.invalid(rest, lit));
                     }
                 }
-                Ok((Val::Seq(lit_vals), rest))
+                if vals.is_empty() {
+                    return Ok((Val::Literals(run), rest));
+                }
+                push_run(&mut vals, &mut run);
+                Ok((Val::Seq(vals), rest))
             }
             RegExp::Concat(r1, r2) => {
                 // println!("concat");
-                // println!("concat1 {:?}", r1);
-                let (v1, s1) = r1._parse_k(s, env, firsts, k, stack, memo)?;
-                // println!("concat2 {:?}", r1);
-                let res = match r2._parse_k(s1, env, firsts, k, stack, memo) {
+                let (v1, s1) = r1._parse_k(s, env, firsts, k, state)?;
+                match r2._parse_k(s1, env, firsts, k, state) {
                     Ok((v2, s2)) => Ok((Val::Concat(Box::new(v1), Box::new(v2)), s2)),
                     Err(ParseErr::Abort(v2)) => {
                         Err(ParseErr::Abort(Val::Concat(Box::new(v1), Box::new(v2))))
                     }
-                    res @ Err(ParseErr::Invalid(_)) => res,
-                };
-                res
+                    res @ Err(_) => res,
+                }
             }
             RegExp::Seq(rs) => {
                 let mut vals = Vec::new();
                 let mut rest = s;
                 for r in rs {
-                    match r._parse_k(rest, env, firsts, k, stack, memo) {
+                    match r._parse_k(rest, env, firsts, k, state) {
                         Ok((v, s)) => {
                             vals.push(v);
                             rest = s;
@@ -457,7 +506,7 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
                             vals.push(v);
                             return Err(ParseErr::Abort(Val::Seq(vals)));
                         }
-                        res @ Err(ParseErr::Invalid(_)) => {
+                        res @ Err(_) => {
                             return res;
                         }
                     }
@@ -466,31 +515,22 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
             }
             RegExp::Alter(r1, r2) => {
                 // println!("alter");
-                // println!("alter1");
-                let res = match r1._parse_k(s, env, firsts, k, stack, memo) {
-                    res @ Ok(..) | res @ Err(ParseErr::Abort(..)) => res,
-                    Err(ParseErr::Invalid(msg)) => {
-                        // println!("r1 invalid: {:?}", msg);
-                        // println!("alter2");
-                        r2._parse_k(s, env, firsts, k, stack, memo)
+                match r1._parse_k(s, env, firsts, k, state) {
+                    Err(ParseErr::Invalid(_msg, _)) => {
+                        // println!("r1 invalid: {:?}", _msg);
+                        r2._parse_k(s, env, firsts, k, state)
                     }
-                };
-                // let res = r2._parse_k(s, env, firsts, k, stack, matched, memo);
-                res
+                    res => res,
+                }
             }
             RegExp::Star(r) => {
                 // println!("star");
-                match r.parse_star_k(s, env, firsts, k, stack, memo) {
-                    Ok((vals, s)) => Ok((Val::Star(vals), s)),
-                    Err(ParseErr::Abort(val)) => Err(ParseErr::Abort(val)),
-                    Err(ParseErr::Invalid(s)) => Err(ParseErr::Invalid(s)),
-                }
+                let (vals, s) = r.parse_star_k(s, env, firsts, k, state)?;
+                Ok((Val::Star(vals), s))
             }
-        };
-        res
+        }
     }
 
-    #[allow(dead_code)]
     fn parse_star_inf<'a>(
         &self,
         mut s: &'a [Alphabet],
@@ -509,43 +549,103 @@ impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<
         mut s: &'a [Alphabet],
         env: &BTreeMap<Name, Self>,
         firsts: &BTreeMap<Alphabet, Name>,
-        k: usize,
-        stack: &mut BTreeMap<Name, usize>,
-        memo: &mut BTreeMap<
-            (Name, usize),
-            Result<(Val<Alphabet>, &'a [Alphabet]), ParseErr<Alphabet>>,
-        >,
-    ) -> Result<(Vec<Val<Alphabet>>, &'a [Alphabet]), ParseErr<Alphabet>> {
+        k: (&FunctionBounds<Name>, Bounds),
+        state: &mut ParseState<'a, Alphabet, Name>,
+    ) -> ParseResult<'a, Alphabet, Vec<Val<Alphabet>>> {
         let mut acc = Vec::new();
+        let mut dropped = 0;
         loop {
-            match self._parse_k(s, env, firsts, k, stack, memo) {
+            match self._parse_k(s, env, firsts, k, state) {
+                // an iteration matching the empty path would repeat forever
+                Ok((_, new_s)) if new_s.len() == s.len() => break,
                 Ok((val, new_s)) => {
                     s = new_s;
-                    if acc.len() == k {
+                    if acc.len() == k.1.loops {
                         // consumes more `self`, but don't push to `acc`
+                        dropped += 1;
                         continue;
                     } else {
                         acc.push(val);
                     }
                 }
                 Err(ParseErr::Abort(val)) => {
-                    if acc.len() == k {
-                        // consumes more `self`, but don't push to `acc`
-                        return Err(ParseErr::Abort(Val::Star(acc)));
-                    } else {
+                    // the path ends in this iteration, which is kept as the last one of the loop
+                    if acc.len() < k.1.loops || !s.is_empty() {
                         acc.push(val);
-                        return Err(ParseErr::Abort(Val::Star(acc)));
                     }
+                    push_dropped(&mut acc, Dropped { iterations: dropped, calls: 0 });
+                    return Err(ParseErr::Abort(Val::Star(acc)));
                 }
-                Err(ParseErr::Invalid(_)) => {
+                Err(ParseErr::Invalid(..)) => {
                     break;
                 }
+                Err(err @ ParseErr::Fatal(_)) => return Err(err),
             }
         }
+        push_dropped(&mut acc, Dropped { iterations: dropped, calls: 0 });
         Ok((acc, s))
     }
 }
 
+/// Records the iterations and calls dropped in `vals`, if any
+fn push_dropped<Alphabet>(vals: &mut Vec<Val<Alphabet>>, dropped: Dropped) {
+    if dropped != Dropped::default() {
+        vals.push(Val::Dropped(dropped));
+    }
+}
+
+/// Moves the literals matched in a row to `vals`
+fn push_run<Alphabet>(vals: &mut Vec<Val<Alphabet>>, run: &mut Vec<Alphabet>) {
+    if !run.is_empty() {
+        vals.push(Val::Literals(std::mem::take(run)));
+    }
+}
+
+/// Bounds of `RegExp::parse_k` in the calls of a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    /// Iterations of each `Star` kept
+    pub loops: usize,
+    /// Calls of the function nested in themselves kept
+    pub recursion: usize,
+}
+
+/// `Bounds` of each function, see `RegExp::parse_bounded`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionBounds<Name> {
+    /// Bounds of the functions without their own
+    pub default: Bounds,
+    pub functions: BTreeMap<Name, Bounds>,
+}
+
+impl<Name: Ord> FunctionBounds<Name> {
+    /// Bounds of `k` for loops and recursion in every function
+    pub fn new(k: usize) -> Self {
+        Self { default: Bounds { loops: k, recursion: k }, functions: BTreeMap::new() }
+    }
+
+    /// Returns the bounds of `fun`
+    pub fn of(&self, fun: &Name) -> Bounds {
+        self.functions.get(fun).copied().unwrap_or(self.default)
+    }
+}
+
+/// Loop iterations and recursive calls parsed without being kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Dropped {
+    /// Iterations of a loop after its first `k` ones
+    pub iterations: usize,
+    /// Calls nested `k` times in themselves
+    pub calls: usize,
+}
+
+impl AddAssign for Dropped {
+    fn add_assign(&mut self, other: Self) {
+        self.iterations += other.iterations;
+        self.calls += other.calls;
+    }
+}
+
 /// Result of parsing
 #[derive(Debug, Clone)]
 pub enum Val<Alphabet> {
@@ -555,27 +655,39 @@ pub enum Val<Alphabet> {
     Concat(Box<Val<Alphabet>>, Box<Val<Alphabet>>),
     Seq(Vec<Val<Alphabet>>),
     Star(Vec<Val<Alphabet>>),
+    /// Parts of the path that aren't kept
+    Dropped(Dropped),
 }
 
 impl<Alphabet> Val<Alphabet> {
+    /// Returns the path kept by the parse
     pub fn into_vec(self) -> Vec<Alphabet> {
+        let mut path = Vec::new();
+        self.append_to(&mut path);
+        path
+    }
+
+    /// Appends the path kept by the parse to `path`
+    pub fn append_to(self, path: &mut Vec<Alphabet>) {
+        self.append_counting(path, &mut Dropped::default());
+    }
+
+    /// Appends the path kept by the parse to `path`, and adds what it drops to `dropped`
+    pub fn append_counting(self, path: &mut Vec<Alphabet>, dropped: &mut Dropped) {
         match self {
-            Val::Epsilon => Vec::new(),
-            Val::Literal(c) => vec![c],
-            Val::Literals(cs) => cs,
+            Val::Epsilon => {}
+            Val::Literal(c) => path.push(c),
+            Val::Literals(mut cs) => path.append(&mut cs),
             Val::Concat(v1, v2) => {
-                let mut r1 = v1.into_vec();
-                let mut r2 = v2.into_vec();
-                r1.append(&mut r2);
-                r1
+                v1.append_counting(path, dropped);
+                v2.append_counting(path, dropped);
             }
             Val::Seq(vs) | Val::Star(vs) => {
-                let mut res = Vec::new();
                 for v in vs {
-                    res.append(&mut v.into_vec())
+                    v.append_counting(path, dropped);
                 }
-                res
             }
+            Val::Dropped(d) => *dropped += d,
         }
     }
 }
@@ -584,25 +696,32 @@ impl<Alphabet> Val<Alphabet> {
 mod tests {
     use crate::re::*;
 
+    fn lit(c: i32) -> Arc<RegExp<i32, i32>> {
+        Arc::new(RegExp::Literal(c))
+    }
+
+    fn cat(r1: Arc<RegExp<i32, i32>>, r2: Arc<RegExp<i32, i32>>) -> Arc<RegExp<i32, i32>> {
+        RegExp::concat(r1, r2)
+    }
+
     #[test]
     fn test1() {
         use RegExp::*;
         // 1(21)*3
         let re: RegExp<_, ()> = Concat(
-            Box::new(Literal(1)),
-            Box::new(Concat(
-                Box::new(Star(Box::new(Concat(
-                    Box::new(Literal(2)),
-                    Box::new(Literal(1)),
+            Arc::new(Literal(1)),
+            Arc::new(Concat(
+                Arc::new(Star(Arc::new(Concat(
+                    Arc::new(Literal(2)),
+                    Arc::new(Literal(1)),
                 )))),
-                Box::new(Literal(3)),
+                Arc::new(Literal(3)),
             )),
         );
         let s = vec![1, 2, 1, 2, 1, 2, 1, 3];
-        let (v, _) = re.parse_inf(&s, &BTreeMap::new()).unwrap();
-        let k = 2;
-        let reduced = v.reduce(k);
-        assert!(reduced == vec![1, 2, 1, 2, 1, 3]);
+        let (v, rest) = re.parse_inf(&s, &BTreeMap::new()).unwrap();
+        assert!(rest.is_empty());
+        assert!(v.into_vec() == s);
     }
 
     #[test]
@@ -610,13 +729,13 @@ mod tests {
         use RegExp::*;
         // 1(21)*3
         let re: RegExp<_, ()> = Concat(
-            Box::new(Literal(1)),
-            Box::new(Concat(
-                Box::new(Star(Box::new(Concat(
-                    Box::new(Literal(2)),
-                    Box::new(Literal(1)),
+            Arc::new(Literal(1)),
+            Arc::new(Concat(
+                Arc::new(Star(Arc::new(Concat(
+                    Arc::new(Literal(2)),
+                    Arc::new(Literal(1)),
                 )))),
-                Box::new(Literal(3)),
+                Arc::new(Literal(3)),
             )),
         );
         let s = vec![1, 2, 1, 2, 1, 2, 1, 3];
@@ -631,59 +750,63 @@ mod tests {
     #[test]
     fn test2() {
         // (12)*(13)
-        let re: RegExp<_, ()> = RegExp::concat(
-            RegExp::star(RegExp::concat(RegExp::literal(1), RegExp::literal(2))),
-            RegExp::concat(RegExp::literal(1), RegExp::literal(3)),
+        let re: Arc<RegExp<_, ()>> = RegExp::concat(
+            Arc::new(RegExp::star(RegExp::concat(
+                Arc::new(RegExp::literal(1)),
+                Arc::new(RegExp::literal(2)),
+            ))),
+            RegExp::concat(Arc::new(RegExp::literal(1)), Arc::new(RegExp::literal(3))),
         );
         let s = vec![1, 2, 1, 2, 1, 2, 1, 3];
-        let (v, _) = re.parse_inf(&s, &BTreeMap::new()).unwrap();
-        let k = 2;
-        let reduced = v.reduce(k);
-        assert!(reduced == vec![1, 2, 1, 2, 1, 3]);
+        let (v, rest) = re.parse_inf(&s, &BTreeMap::new()).unwrap();
+        assert!(rest.is_empty());
+        assert!(v.into_vec() == s);
     }
 
     #[test]
     fn test2_() {
         // (12)*(13)
-        let re: RegExp<_, ()> = RegExp::concat(
-            RegExp::star(RegExp::concat(RegExp::literal(1), RegExp::literal(2))),
-            RegExp::concat(RegExp::literal(1), RegExp::literal(3)),
+        let re: Arc<RegExp<_, ()>> = RegExp::concat(
+            Arc::new(RegExp::star(RegExp::concat(
+                Arc::new(RegExp::literal(1)),
+                Arc::new(RegExp::literal(2)),
+            ))),
+            RegExp::concat(Arc::new(RegExp::literal(1)), Arc::new(RegExp::literal(3))),
         );
         let s = vec![1, 2, 1, 2, 1, 2, 1, 3];
         let k = 2;
-        let (v, _) = re.parse_k(&s, &BTreeMap::new(), k).unwrap();
+        let (v, _) = re
+            .parse_k(&s, &BTreeMap::new(), &BTreeMap::new(), k)
+            .unwrap();
         let reduced = v.into_vec();
         assert!(reduced == vec![1, 2, 1, 2, 1, 3]);
     }
 
     #[test]
     fn test3() {
-        use RegExp::*;
+        let eps = Arc::new(RegExp::Epsilon);
         let re = RegExp::alter(
-            RegExp::concat(
-                RegExp::concat(RegExp::concat(Literal(9), Epsilon), Literal(11)),
-                RegExp::concat(
-                    Literal(13),
-                    RegExp::concat(
-                        Epsilon,
-                        RegExp::concat(
-                            Literal(15),
+            cat(
+                cat(cat(lit(9), eps.clone()), lit(11)),
+                cat(
+                    lit(13),
+                    cat(
+                        eps.clone(),
+                        cat(
+                            lit(15),
                             RegExp::alter(
-                                RegExp::concat(Literal(16), Literal(27)),
-                                RegExp::concat(
-                                    Literal(17),
-                                    RegExp::concat(
-                                        Literal(18),
-                                        RegExp::concat(
-                                            Literal(22),
-                                            RegExp::concat(
-                                                Var(0),
-                                                RegExp::concat(
-                                                    Literal(24),
-                                                    RegExp::concat(
-                                                        Epsilon,
-                                                        RegExp::concat(Literal(26), Literal(27)),
-                                                    ),
+                                cat(lit(16), lit(27)),
+                                cat(
+                                    lit(17),
+                                    cat(
+                                        lit(18),
+                                        cat(
+                                            lit(22),
+                                            cat(
+                                                Arc::new(RegExp::Var(0)),
+                                                cat(
+                                                    lit(24),
+                                                    cat(eps.clone(), cat(lit(26), lit(27))),
                                                 ),
                                             ),
                                         ),
@@ -694,60 +817,132 @@ mod tests {
                     ),
                 ),
             ),
-            RegExp::concat(
-                RegExp::concat(
-                    RegExp::concat(RegExp::concat(Literal(9), Epsilon), Literal(11)),
-                    Literal(12),
-                ),
-                Literal(27),
-            ),
+            cat(cat(cat(cat(lit(9), eps), lit(11)), lit(12)), lit(27)),
         );
         let re0 = RegExp::alter(
             RegExp::alter(
-                RegExp::concat(
-                    RegExp::concat(RegExp::Literal(0), RegExp::Literal(2)),
-                    RegExp::Literal(8),
-                ),
-                RegExp::concat(
-                    RegExp::concat(RegExp::Literal(0), RegExp::Literal(1)),
-                    RegExp::concat(RegExp::Literal(2), RegExp::Literal(8)),
-                ),
+                cat(cat(lit(0), lit(2)), lit(8)),
+                cat(cat(lit(0), lit(1)), cat(lit(2), lit(8))),
             ),
-            RegExp::concat(
+            cat(
                 RegExp::alter(
-                    RegExp::concat(
-                        RegExp::concat(RegExp::Literal(0), RegExp::Literal(2)),
-                        RegExp::Literal(3),
-                    ),
-                    RegExp::concat(
-                        RegExp::concat(RegExp::Literal(0), RegExp::Literal(1)),
-                        RegExp::concat(RegExp::Literal(2), RegExp::Literal(3)),
-                    ),
+                    cat(cat(lit(0), lit(2)), lit(3)),
+                    cat(cat(lit(0), lit(1)), cat(lit(2), lit(3))),
                 ),
-                RegExp::concat(RegExp::Literal(7), RegExp::Literal(8)),
+                cat(lit(7), lit(8)),
             ),
         );
         let path = vec![9, 11, 13, 15, 17, 18, 22, 0, 2, 3];
         let mut env = BTreeMap::new();
-        env.insert(0, re0);
-        let res = re.parse_k(&path, &env, 3);
+        env.insert(0, re0.as_ref().clone());
+        let res = re.parse_k(&path, &env, &BTreeMap::new(), 3);
         println!("{:?}", res);
+        assert!(matches!(res, Err(ParseErr::Abort(_))));
     }
 
     #[test]
     fn test4() {
         // f = 1(2|f)3
         let re = RegExp::Seq(vec![
-            RegExp::literal(1),
-            RegExp::alter(RegExp::Literal(2), RegExp::Var(0)),
-            RegExp::literal(3),
+            lit(1),
+            RegExp::alter(lit(2), Arc::new(RegExp::Var(0))),
+            lit(3),
         ]);
         let s = vec![1, 1, 1, 2, 3, 3, 3];
         let mut env = BTreeMap::new();
         env.insert(0, re.clone());
         let k = 1;
-        let (v, _) = re.parse_k(&s, &env, k).unwrap();
+        let (v, _) = re.parse_k(&s, &env, &BTreeMap::new(), k).unwrap();
         let reduced = v.into_vec();
         assert!(reduced == vec![1, 1, 3, 3]);
     }
+
+    #[test]
+    fn unknown_var_is_fatal() {
+        let re = RegExp::concat(lit(1), Arc::new(RegExp::Var(7)));
+        let res = re.parse_k(&[1, 2], &BTreeMap::new(), &BTreeMap::new(), 1);
+        assert!(matches!(
+            res,
+            Err(ParseErr::Fatal(Error::UnknownFunction(_)))
+        ));
+    }
+
+    #[test]
+    fn star_of_epsilon_alternative() {
+        // (1|ε)*2, as built for an exit block with successors
+        let eps = Arc::new(RegExp::Epsilon);
+        let re = cat(Arc::new(RegExp::star(RegExp::alter(eps, lit(1)))), lit(2));
+        let (v, rest) = re.parse_k(&[1, 1, 1, 2], &BTreeMap::new(), &BTreeMap::new(), 1).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(v.into_vec(), vec![1, 2]);
+        let (v, _) = re.parse_k(&[2], &BTreeMap::new(), &BTreeMap::new(), 1).unwrap();
+        assert_eq!(v.into_vec(), vec![2]);
+    }
+
+    #[test]
+    fn epsilon_alternatives_go_last() {
+        // (ε|1)2, as built for a branch around an external call: the parser commits to the first
+        // alternative that matches, so trying ε first rejects the path 1 2
+        let eps = Arc::new(RegExp::Epsilon);
+        let wrong = cat(Arc::new(RegExp::Alter(eps.clone(), lit(1))), lit(2));
+        assert!(wrong.parse_k(&[1, 2], &BTreeMap::new(), &BTreeMap::new(), 1).is_err());
+        let alternative = RegExp::alter(eps, lit(1));
+        assert!(matches!(alternative.as_ref(), RegExp::Alter(_, r2) if **r2 == RegExp::Epsilon));
+        let re = cat(alternative, lit(2));
+        let (v, rest) = re.parse_k(&[1, 2], &BTreeMap::new(), &BTreeMap::new(), 1).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(v.into_vec(), vec![1, 2]);
+    }
+
+    #[test]
+    fn star_of_empty_iterations() {
+        // ε* 2, as built for a loop of external calls: an iteration matching the empty path
+        // ends the star instead of repeating forever
+        let re = cat(Arc::new(RegExp::star(Arc::new(RegExp::Epsilon))), lit(2));
+        let (v, rest) = re.parse_k(&[2], &BTreeMap::new(), &BTreeMap::new(), 1).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(v.into_vec(), vec![2]);
+    }
+
+    #[test]
+    fn literals_and_seq() {
+        // f = 1 2 3 (4 | ε), g = 10 11
+        let re = RegExp::Seq(vec![
+            Arc::new(RegExp::Literals(vec![1, 2, 3])),
+            RegExp::alter(lit(4), Arc::new(RegExp::Epsilon)),
+        ]);
+        let env = BTreeMap::from([(0, re.clone()), (1, RegExp::Literals(vec![10, 11]))]);
+        let firsts = BTreeMap::from([(1, 0), (10, 1)]);
+        assert_eq!(re.first_opt(), Ok(Some(1)));
+        assert_eq!(re.size(), 7);
+        re.debug();
+        let (v, rest) = re.parse_inf(&[1, 2, 3, 4], &env).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(v.into_vec(), vec![1, 2, 3, 4]);
+        let (v, rest) = re.parse_k(&[1, 2, 3, 4], &env, &firsts, 1).unwrap();
+        assert!(rest.is_empty());
+        assert!(matches!(&v, Val::Seq(vals) if matches!(vals[0], Val::Literals(_))));
+        // implicit calls to `g` between the literals
+        let path = [1, 10, 11, 2, 10, 11, 10, 11, 3, 4];
+        let (v, rest) = re.parse_k(&path, &env, &firsts, 1).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(v.into_vec(), path);
+        assert!(matches!(re.parse_k(&[1, 2, 5], &env, &firsts, 1), Err(ParseErr::Invalid(..))));
+        assert!(matches!(re.parse_k(&[1, 10], &env, &firsts, 1), Err(ParseErr::Abort(_))));
+    }
+
+    #[test]
+    fn invalid_paths_fail_at_the_furthest_block() {
+        // (1 2)* 3, whose star stops at the iteration failing at 5 before 3 fails at 1
+        let re = RegExp::concat(Arc::new(RegExp::Star(Arc::new(RegExp::Literals(vec![1, 2])))), lit(3));
+        let res = re.parse_k(&[1, 2, 1, 5, 3], &BTreeMap::new(), &BTreeMap::new(), 1);
+        assert!(matches!(res, Err(ParseErr::Invalid(_, 2))));
+        assert!(matches!(re.parse_k(&[1, 2, 1], &BTreeMap::new(), &BTreeMap::new(), 1), Err(ParseErr::Abort(_))));
+    }
+
+    #[test]
+    fn first_of_call_is_error() {
+        let re = RegExp::concat(Arc::new(RegExp::Var(0)), lit(1));
+        assert!(matches!(re.first(), Err(Error::NoFirstBlock(_))));
+    }
 }
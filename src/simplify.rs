@@ -0,0 +1,386 @@
+//! Simplification of the regular expressions built by the conversion of CFGs
+//!
+//! State elimination builds large trees of binary `Concat` and `Alter`. `RegExp::simplify` rewrites them,
+//! without changing the language:
+//!
+//! - chains of `Concat` are flattened to `Seq`, with runs of literals merged to `Literals`, and `Epsilon` removed,
+//! - nested `Alter` are flattened, duplicated alternatives removed,
+//!   and the common prefixes and suffixes of alternatives factored out,
+//! - `Epsilon` alternatives are dropped when another alternative matches the empty path, and kept last otherwise,
+//! - `(r*)*`, `(ε|r)*` and `ε*` are collapsed.
+
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use crate::re::RegExp;
+
+type Re<Alphabet, Name> = Arc<RegExp<Alphabet, Name>>;
+
+impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> RegExp<Alphabet, Name> {
+    /// Returns a simpler regular expression with the same language
+    pub fn simplify(&self) -> Self {
+        let mut simplifier = Simplifier { memo: HashMap::new() };
+        Arc::unwrap_or_clone(simplifier.simplify(self))
+    }
+
+    /// Returns whether the expression matches the empty path
+    pub fn is_nullable(&self) -> bool {
+        match self {
+            RegExp::Epsilon | RegExp::Star(_) => true,
+            RegExp::Var(_) | RegExp::Literal(_) => false,
+            RegExp::Literals(lits) => lits.is_empty(),
+            RegExp::Concat(r1, r2) => r1.is_nullable() && r2.is_nullable(),
+            RegExp::Seq(rs) => rs.iter().all(|r| r.is_nullable()),
+            RegExp::Alter(r1, r2) => r1.is_nullable() || r2.is_nullable(),
+        }
+    }
+}
+
+struct Simplifier<Alphabet, Name> {
+    /// Simplified shared sub-expressions, by address
+    memo: HashMap<*const RegExp<Alphabet, Name>, Re<Alphabet, Name>>,
+}
+
+impl<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug> Simplifier<Alphabet, Name> {
+    fn simplify(&mut self, re: &RegExp<Alphabet, Name>) -> Re<Alphabet, Name> {
+        let key = re as *const _;
+        if let Some(simplified) = self.memo.get(&key) {
+            return simplified.clone();
+        }
+        let simplified = match re {
+            RegExp::Epsilon | RegExp::Var(_) | RegExp::Literal(_) => Arc::new(re.clone()),
+            RegExp::Literals(_) | RegExp::Concat(..) | RegExp::Seq(_) => {
+                let mut items = Vec::new();
+                self.push_items(re, &mut items);
+                seq(items)
+            }
+            RegExp::Alter(..) => {
+                let mut alternatives = Vec::new();
+                self.push_alternatives(re, &mut alternatives);
+                alter(alternatives)
+            }
+            RegExp::Star(r) => star(self.simplify(r)),
+        };
+        self.memo.insert(key, simplified.clone());
+        simplified
+    }
+
+    /// Pushes the simplified factors of a sequence to `items`
+    fn push_items(&mut self, re: &RegExp<Alphabet, Name>, items: &mut Vec<Re<Alphabet, Name>>) {
+        match re {
+            RegExp::Concat(r1, r2) => {
+                self.push_items(r1, items);
+                self.push_items(r2, items);
+            }
+            RegExp::Seq(rs) => {
+                for r in rs {
+                    self.push_items(r, items);
+                }
+            }
+            RegExp::Literals(lits) => items.extend(lits.iter().map(|lit| Arc::new(RegExp::Literal(lit.clone())))),
+            _ => items.extend(factors(&self.simplify(re))),
+        }
+    }
+
+    /// Pushes the simplified alternatives of nested `Alter` to `alternatives`
+    fn push_alternatives(&mut self, re: &RegExp<Alphabet, Name>, alternatives: &mut Vec<Re<Alphabet, Name>>) {
+        match re {
+            RegExp::Alter(r1, r2) => {
+                self.push_alternatives(r1, alternatives);
+                self.push_alternatives(r2, alternatives);
+            }
+            _ => {
+                let simplified = self.simplify(re);
+                match simplified.as_ref() {
+                    // a simplified `Alter` is already flat
+                    RegExp::Alter(..) => alternatives.extend(flat_alternatives(&simplified)),
+                    _ => alternatives.push(simplified),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the factors of a simplified sequence
+fn factors<Alphabet: Clone, Name>(re: &Re<Alphabet, Name>) -> Vec<Re<Alphabet, Name>> {
+    match re.as_ref() {
+        RegExp::Epsilon => Vec::new(),
+        RegExp::Seq(rs) => rs.iter().flat_map(factors).collect(),
+        RegExp::Literals(lits) => lits.iter().map(|lit| Arc::new(RegExp::Literal(lit.clone()))).collect(),
+        _ => vec![re.clone()],
+    }
+}
+
+/// Returns the alternatives of a simplified `Alter`
+fn flat_alternatives<Alphabet, Name>(re: &Re<Alphabet, Name>) -> Vec<Re<Alphabet, Name>> {
+    match re.as_ref() {
+        RegExp::Alter(r1, r2) => {
+            let mut alternatives = flat_alternatives(r1);
+            alternatives.extend(flat_alternatives(r2));
+            alternatives
+        }
+        _ => vec![re.clone()],
+    }
+}
+
+/// Returns the sequence of simplified `items`, none of them a sequence or `Epsilon`
+fn seq<Alphabet: Clone, Name>(items: Vec<Re<Alphabet, Name>>) -> Re<Alphabet, Name> {
+    let mut merged = Vec::new();
+    let mut lits = Vec::new();
+    for item in items {
+        match item.as_ref() {
+            RegExp::Literal(lit) => lits.push(lit.clone()),
+            _ => {
+                push_literals(&mut merged, &mut lits);
+                merged.push(item);
+            }
+        }
+    }
+    push_literals(&mut merged, &mut lits);
+    match merged.len() {
+        0 => Arc::new(RegExp::Epsilon),
+        1 => merged.pop().unwrap(),
+        _ => Arc::new(RegExp::Seq(merged)),
+    }
+}
+
+fn push_literals<Alphabet: Clone, Name>(items: &mut Vec<Re<Alphabet, Name>>, lits: &mut Vec<Alphabet>) {
+    match lits.len() {
+        0 => {}
+        1 => items.push(Arc::new(RegExp::Literal(lits.pop().unwrap()))),
+        _ => items.push(Arc::new(RegExp::Literals(std::mem::take(lits)))),
+    }
+}
+
+/// Returns the alternation of simplified `alternatives`, none of them an `Alter`
+fn alter<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug>(
+    alternatives: Vec<Re<Alphabet, Name>>,
+) -> Re<Alphabet, Name> {
+    let mut unique: Vec<Re<Alphabet, Name>> = Vec::new();
+    for alternative in alternatives {
+        if !unique.contains(&alternative) {
+            unique.push(alternative);
+        }
+    }
+    let has_epsilon = unique.iter().any(|r| matches!(r.as_ref(), RegExp::Epsilon));
+    unique.retain(|r| !matches!(r.as_ref(), RegExp::Epsilon));
+    let sequences: Vec<_> = unique.iter().map(factors).collect();
+    let mut alternatives = factor_suffixes(factor_prefixes(sequences));
+    // `Epsilon` last, so that the other alternatives are tried first when parsing
+    if has_epsilon && !alternatives.iter().any(|r| r.is_nullable()) {
+        alternatives.push(Arc::new(RegExp::Epsilon));
+    }
+    alternatives
+        .into_iter()
+        .rev()
+        .reduce(|rest, alternative| Arc::new(RegExp::Alter(alternative, rest)))
+        .unwrap_or_else(|| Arc::new(RegExp::Epsilon))
+}
+
+/// Factors the common prefix of the alternatives starting with the same factor,
+/// in the order of their first alternative
+fn factor_prefixes<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug>(
+    sequences: Vec<Vec<Re<Alphabet, Name>>>,
+) -> Vec<Vec<Re<Alphabet, Name>>> {
+    let mut groups: Vec<Vec<Vec<Re<Alphabet, Name>>>> = Vec::new();
+    for sequence in sequences {
+        match groups.iter_mut().find(|group| !sequence.is_empty() && group[0].first() == sequence.first()) {
+            Some(group) => group.push(sequence),
+            None => groups.push(vec![sequence]),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|mut group| {
+            if group.len() == 1 {
+                return group.pop().unwrap();
+            }
+            let common = (0..)
+                .take_while(|&i| group.iter().all(|sequence| i < sequence.len() && sequence[i] == group[0][i]))
+                .count();
+            let mut factored = group[0][..common].to_vec();
+            let rests = group.into_iter().map(|sequence| seq(sequence[common..].to_vec())).collect();
+            factored.extend(factors(&alter(rests)));
+            factored
+        })
+        .collect()
+}
+
+/// Factors the common suffix of the alternatives ending with the same factor, and returns the alternatives
+fn factor_suffixes<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug>(
+    sequences: Vec<Vec<Re<Alphabet, Name>>>,
+) -> Vec<Re<Alphabet, Name>> {
+    let mut groups: Vec<Vec<Vec<Re<Alphabet, Name>>>> = Vec::new();
+    for sequence in sequences {
+        match groups.iter_mut().find(|group| !sequence.is_empty() && group[0].last() == sequence.last()) {
+            Some(group) => group.push(sequence),
+            None => groups.push(vec![sequence]),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|mut group| {
+            if group.len() == 1 {
+                return seq(group.pop().unwrap());
+            }
+            let common = (1..)
+                .take_while(|&i| {
+                    group.iter().all(|sequence| {
+                        i <= sequence.len() && sequence[sequence.len() - i] == group[0][group[0].len() - i]
+                    })
+                })
+                .count();
+            let suffix = group[0][group[0].len() - common..].to_vec();
+            let rests = group
+                .into_iter()
+                .map(|sequence| seq(sequence[..sequence.len() - common].to_vec()))
+                .collect();
+            let mut factored = factors(&alter(rests));
+            factored.extend(suffix);
+            seq(factored)
+        })
+        .collect()
+}
+
+/// Returns the `Star` of a simplified expression
+fn star<Alphabet: Eq + Clone + Ord + Debug, Name: Eq + Clone + Ord + Debug>(
+    re: Re<Alphabet, Name>,
+) -> Re<Alphabet, Name> {
+    match re.as_ref() {
+        RegExp::Epsilon => re,
+        RegExp::Star(_) => re,
+        RegExp::Alter(..) => {
+            // (ε|r)* = r* and (r*|s)* = (r|s)*
+            let alternatives: Vec<_> = flat_alternatives(&re)
+                .into_iter()
+                .filter(|r| !matches!(r.as_ref(), RegExp::Epsilon))
+                .map(|r| match r.as_ref() {
+                    RegExp::Star(inner) => inner.clone(),
+                    _ => r,
+                })
+                .collect();
+            let inner = alter(alternatives);
+            match inner.as_ref() {
+                RegExp::Epsilon => inner,
+                _ => Arc::new(RegExp::Star(inner)),
+            }
+        }
+        _ => Arc::new(RegExp::Star(re)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    type R = RegExp<i32, i32>;
+
+    /// Returns the ends of the matches of `re` in `s` from `start`, as a reference matcher
+    fn ends(re: &R, s: &[i32], start: usize) -> BTreeSet<usize> {
+        let from_all = |re: &R, starts: BTreeSet<usize>| starts.into_iter().flat_map(|i| ends(re, s, i)).collect();
+        match re {
+            RegExp::Epsilon => BTreeSet::from([start]),
+            RegExp::Var(_) => BTreeSet::new(),
+            RegExp::Literal(c) => (s.get(start) == Some(c)).then_some(start + 1).into_iter().collect(),
+            RegExp::Literals(cs) => {
+                (s[start..].starts_with(cs)).then_some(start + cs.len()).into_iter().collect()
+            }
+            RegExp::Concat(r1, r2) => from_all(r2, ends(r1, s, start)),
+            RegExp::Seq(rs) => rs.iter().fold(BTreeSet::from([start]), |starts, r| from_all(r, starts)),
+            RegExp::Alter(r1, r2) => &ends(r1, s, start) | &ends(r2, s, start),
+            RegExp::Star(r) => {
+                let mut reached = BTreeSet::from([start]);
+                let mut frontier = reached.clone();
+                while !frontier.is_empty() {
+                    let next: BTreeSet<_> = from_all(r, frontier);
+                    frontier = &next - &reached;
+                    reached.extend(next);
+                }
+                reached
+            }
+        }
+    }
+
+    fn matches(re: &R, s: &[i32]) -> bool {
+        ends(re, s, 0).contains(&s.len())
+    }
+
+    /// xorshift, to generate expressions without extra dependencies
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    fn random_re(rng: &mut Rng, depth: usize) -> Arc<R> {
+        let choice = if depth == 0 { rng.below(3) } else { rng.below(8) };
+        Arc::new(match choice {
+            0 => RegExp::Epsilon,
+            1 | 2 => RegExp::Literal(rng.below(3) as i32),
+            3 => RegExp::Literals((0..rng.below(3) + 1).map(|_| rng.below(3) as i32).collect()),
+            4 => RegExp::Concat(random_re(rng, depth - 1), random_re(rng, depth - 1)),
+            5 => RegExp::Seq((0..rng.below(4)).map(|_| random_re(rng, depth - 1)).collect()),
+            6 => RegExp::Alter(random_re(rng, depth - 1), random_re(rng, depth - 1)),
+            _ => RegExp::Star(random_re(rng, depth - 1)),
+        })
+    }
+
+    /// All paths over blocks 0, 1, 2 up to length 5
+    fn paths() -> Vec<Vec<i32>> {
+        let mut paths = vec![vec![]];
+        let mut last = vec![vec![]];
+        for _ in 0..5 {
+            last = last
+                .iter()
+                .flat_map(|path: &Vec<i32>| (0..3).map(move |c| [&path[..], &[c]].concat()))
+                .collect();
+            paths.extend(last.iter().cloned());
+        }
+        paths
+    }
+
+    #[test]
+    fn simplify_preserves_the_language() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        let paths = paths();
+        for _ in 0..300 {
+            let re = random_re(&mut rng, 4);
+            let simplified = re.simplify();
+            for path in &paths {
+                assert_eq!(matches(&re, path), matches(&simplified, path), "{:?} {:?} {:?}", re, simplified, path);
+            }
+            assert_eq!(simplified.simplify(), simplified, "{:?}", re);
+        }
+    }
+
+    #[test]
+    fn simplify_factors_and_flattens() {
+        let lit = |c| Arc::new(R::Literal(c));
+        let cat = |r1, r2| Arc::new(R::Concat(r1, r2));
+        let eps = Arc::new(R::Epsilon);
+        // 1 2 (3 4 | 3 5) | 1 2 ε
+        let re = R::Alter(
+            cat(cat(lit(1), lit(2)), Arc::new(R::Alter(cat(lit(3), lit(4)), cat(lit(3), lit(5))))),
+            cat(cat(lit(1), lit(2)), eps.clone()),
+        );
+        // 1 2 (3 (4 | 5) | ε)
+        let expected = R::Seq(vec![
+            Arc::new(R::Literals(vec![1, 2])),
+            Arc::new(R::Alter(
+                Arc::new(R::Seq(vec![lit(3), Arc::new(R::Alter(lit(4), lit(5)))])),
+                eps.clone(),
+            )),
+        ]);
+        assert_eq!(re.simplify(), expected);
+        assert!(re.simplify().size() < re.size());
+        // ((ε | 1)*)* = 1*
+        let re = R::Star(Arc::new(R::Star(Arc::new(R::Alter(eps, lit(1))))));
+        assert_eq!(re.simplify(), R::Star(lit(1)));
+    }
+}
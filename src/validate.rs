@@ -0,0 +1,196 @@
+//! Validation of the CFGs of a program before building a reducer
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::{
+    error::Error,
+    extern_cfg::{BlockID, FunID},
+    program::{Program, EXTERN_CALL, INDIRECT_CALL, NOT_A_CALL},
+};
+
+/// A problem found in the CFGs of a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// The entry, exit or an extra exit block of a function is not in the block table
+    UnknownBlock { function: FunID, block: BlockID },
+    /// A successor of a block is not in the block table
+    DanglingSuccessor { block: BlockID, successor: BlockID },
+    /// A block calls a function that is not defined
+    UnknownCallee { block: BlockID, callee: FunID },
+    /// Two functions start with the same block
+    SharedEntry { block: BlockID, first: FunID, second: FunID },
+    /// Neither the exit block nor an extra exit is reachable from the entry of a function.
+    /// The function is taken not to return.
+    NoExit { function: FunID },
+    /// A block is not reachable from the entry of any function
+    UnreachableBlock { block: BlockID },
+}
+
+impl Diagnostic {
+    /// Returns whether a reducer can't be built from the CFGs.
+    /// Otherwise the diagnostic is a warning.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Diagnostic::UnreachableBlock { .. } | Diagnostic::NoExit { .. })
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::UnknownBlock { function, block } => {
+                write!(f, "function {} refers to unknown block {}", function, block)
+            }
+            Diagnostic::DanglingSuccessor { block, successor } => {
+                write!(f, "block {} has unknown successor {}", block, successor)
+            }
+            Diagnostic::UnknownCallee { block, callee } => {
+                write!(f, "block {} calls unknown function {}", block, callee)
+            }
+            Diagnostic::SharedEntry { block, first, second } => {
+                write!(f, "functions {} {} both start with block {}", first, second, block)
+            }
+            Diagnostic::NoExit { function } => write!(f, "function {} has no exit block", function),
+            Diagnostic::UnreachableBlock { block } => write!(f, "block {} is unreachable", block),
+        }
+    }
+}
+
+impl From<&Diagnostic> for Error {
+    fn from(diagnostic: &Diagnostic) -> Self {
+        match diagnostic {
+            Diagnostic::UnknownBlock { block, .. } => Error::UnknownBlock(block.to_string()),
+            Diagnostic::DanglingSuccessor { successor, .. } => Error::UnknownBlock(successor.to_string()),
+            Diagnostic::UnknownCallee { callee, .. } => Error::UnknownFunction(callee.to_string()),
+            Diagnostic::SharedEntry { block, first, second } => Error::DuplicateEntry {
+                block: block.to_string(),
+                first: first.to_string(),
+                second: second.to_string(),
+            },
+            Diagnostic::NoExit { function } => Error::NoExit(function.to_string()),
+            Diagnostic::UnreachableBlock { block } => {
+                Error::InvalidArgument(format!("block {} is unreachable", block))
+            }
+        }
+    }
+}
+
+/// Returns all the problems found in the CFGs of `program`
+pub fn validate(program: &Program) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let fun_count = program.functions.len() as FunID;
+    let mut entries = BTreeMap::new();
+    for (fun_id, function) in program.functions.iter().enumerate() {
+        let fun_id = fun_id as FunID;
+        for &block in [function.entry, function.exit].iter().chain(&function.extra_exits) {
+            if !program.blocks.contains_key(&block) {
+                diagnostics.push(Diagnostic::UnknownBlock { function: fun_id, block });
+            }
+        }
+        if let Some(&first) = entries.get(&function.entry) {
+            diagnostics.push(Diagnostic::SharedEntry { block: function.entry, first, second: fun_id });
+        } else {
+            entries.insert(function.entry, fun_id);
+        }
+    }
+    for (&block_id, block) in &program.blocks {
+        for &successor in &block.successors {
+            if !program.blocks.contains_key(&successor) {
+                diagnostics.push(Diagnostic::DanglingSuccessor { block: block_id, successor });
+            }
+        }
+        let callees = match block.calls {
+            NOT_A_CALL | EXTERN_CALL => &[][..],
+            INDIRECT_CALL => &block.callees[..],
+            _ => std::slice::from_ref(&block.calls),
+        };
+        for &callee in callees {
+            if !(0..fun_count).contains(&callee) {
+                diagnostics.push(Diagnostic::UnknownCallee { block: block_id, callee });
+            }
+        }
+        if block.calls < INDIRECT_CALL {
+            diagnostics.push(Diagnostic::UnknownCallee { block: block_id, callee: block.calls });
+        }
+    }
+    let mut reachable = BTreeSet::new();
+    for (fun_id, function) in program.functions.iter().enumerate() {
+        let blocks = reachable_blocks(program, function.entry);
+        let has_exit = std::iter::once(&function.exit)
+            .chain(&function.extra_exits)
+            .any(|exit| blocks.contains(exit));
+        if !has_exit && program.blocks.contains_key(&function.entry) {
+            diagnostics.push(Diagnostic::NoExit { function: fun_id as FunID });
+        }
+        reachable.extend(blocks);
+    }
+    for &block in program.blocks.keys() {
+        if !reachable.contains(&block) {
+            diagnostics.push(Diagnostic::UnreachableBlock { block });
+        }
+    }
+    diagnostics
+}
+
+/// Returns the blocks in the block table reachable from `entry`
+fn reachable_blocks(program: &Program, entry: BlockID) -> BTreeSet<BlockID> {
+    let mut visited = BTreeSet::new();
+    let mut to_visit = vec![entry];
+    while let Some(block_id) = to_visit.pop() {
+        let Some(block) = program.blocks.get(&block_id) else {
+            continue;
+        };
+        if visited.insert(block_id) {
+            to_visit.extend_from_slice(&block.successors);
+        }
+    }
+    visited
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::{Block, Function};
+
+    #[test]
+    fn reports_all_problems() {
+        let block = |calls, successors: &[BlockID]| Block {
+            calls,
+            callees: Vec::new(),
+            successors: successors.to_vec(),
+        };
+        let function = |entry, exit| Function { name: String::new(), entry, exit, extra_exits: Vec::new() };
+        let mut program = Program {
+            functions: vec![function(0, 2), function(3, 3), function(3, 4), function(5, 8)],
+            blocks: BTreeMap::from([
+                (0, block(1, &[1])),
+                (1, block(7, &[2, 9])),
+                (2, block(NOT_A_CALL, &[])),
+                (3, block(NOT_A_CALL, &[])),
+                (5, block(NOT_A_CALL, &[6])),
+                (6, block(EXTERN_CALL, &[5])),
+                (8, block(NOT_A_CALL, &[])),
+            ]),
+        };
+        program.functions[0].extra_exits.push(10);
+        let diagnostics = validate(&program);
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic::UnknownBlock { function: 0, block: 10 },
+                Diagnostic::UnknownBlock { function: 2, block: 4 },
+                Diagnostic::SharedEntry { block: 3, first: 1, second: 2 },
+                Diagnostic::DanglingSuccessor { block: 1, successor: 9 },
+                Diagnostic::UnknownCallee { block: 1, callee: 7 },
+                // block 3 has no successors, but doesn't return
+                Diagnostic::NoExit { function: 2 },
+                Diagnostic::NoExit { function: 3 },
+                Diagnostic::UnreachableBlock { block: 8 },
+            ]
+        );
+        assert!(diagnostics[..5].iter().all(Diagnostic::is_error));
+        assert!(!diagnostics[5..].iter().any(Diagnostic::is_error));
+    }
+}